@@ -0,0 +1,25 @@
+#!/bin/sh
+# Measures the CPU time an idle `net.http.serve` process uses.
+#
+#   ./example/bench/idle_cpu.sh [lulu binary] [seconds]
+set -e
+
+LULU=${1:-lulu}
+SECONDS_IDLE=${2:-10}
+ADDR=127.0.0.1:8741
+DIR=$(cd "$(dirname "$0")" && pwd)
+
+"$LULU" run "$DIR/idle_serve.lua" "$ADDR" >/dev/null &
+PID=$!
+trap 'kill $PID 2>/dev/null' EXIT
+
+sleep 1
+curl -s "http://$ADDR/" >/dev/null || { echo "server did not start"; exit 1; }
+
+TICKS=$(getconf CLK_TCK)
+before=$(awk '{print $14 + $15}' /proc/$PID/stat)
+sleep "$SECONDS_IDLE"
+after=$(awk '{print $14 + $15}' /proc/$PID/stat)
+
+awk -v t=$((after - before)) -v hz="$TICKS" -v s="$SECONDS_IDLE" \
+  'BEGIN { printf "%.2fs of CPU in %ss idle (%.1f%% of a core)\n", t / hz, s, 100 * t / hz / s }'
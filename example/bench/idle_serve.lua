@@ -0,0 +1,12 @@
+-- An HTTP server that sits idle while a future waits on a timer, used by
+-- idle_cpu.sh to measure how much CPU the runtime burns doing nothing.
+using { lulib.net }
+
+local addr = argv[#argv] or "127.0.0.1:8741"
+
+async(function()
+  net.http.serve(addr, function(req)
+    return { status = 200, body = "ok" }
+  end)
+  sleep(3600)
+end)
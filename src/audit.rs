@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::lockfile::{LockedPackage, Lockfile};
+use crate::registry::compare_versions;
+
+pub const ADVISORY_DB_ENV: &str = "LULU_ADVISORY_DB";
+
+/// One entry of the advisory database. `versions` holds exact versions or
+/// commits, `*`, or comma separated comparators such as `>=1.0.0, <1.4.2`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Advisory {
+  #[serde(default)]
+  pub id: Option<String>,
+  #[serde(alias = "package")]
+  pub name: String,
+  pub versions: Vec<String>,
+  pub description: String,
+  #[serde(default)]
+  pub url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AdvisoryFile {
+  List(Vec<Advisory>),
+  Database { advisories: Vec<Advisory> },
+}
+
+/// `LULU_ADVISORY_DB`, or `<config dir>/lulu/advisories.json`.
+pub fn default_db_path() -> Option<PathBuf> {
+  std::env::var(ADVISORY_DB_ENV)
+    .ok()
+    .map(PathBuf::from)
+    .or_else(|| dirs::config_dir().map(|dir| dir.join("lulu").join("advisories.json")))
+}
+
+pub fn load_advisories(path: &Path) -> Result<Vec<Advisory>> {
+  let content = fs::read_to_string(path)
+    .with_context(|| format!("Could not read advisory database {}", path.display()))?;
+  let file: AdvisoryFile = serde_json::from_str(&content)
+    .with_context(|| format!("Malformed advisory database {}", path.display()))?;
+
+  Ok(match file {
+    AdvisoryFile::List(advisories) => advisories,
+    AdvisoryFile::Database { advisories } => advisories,
+  })
+}
+
+fn comparator_matches(comparator: &str, version: &str) -> bool {
+  let (op, wanted) = ["!=", ">=", "<=", ">", "<", "="]
+    .iter()
+    .find_map(|op| comparator.strip_prefix(op).map(|rest| (*op, rest.trim())))
+    .unwrap_or(("=", comparator));
+  let ordering = compare_versions(version.trim_start_matches('v'), wanted.trim_start_matches('v'));
+
+  match op {
+    "!=" => ordering != Ordering::Equal,
+    ">=" => ordering != Ordering::Less,
+    "<=" => ordering != Ordering::Greater,
+    ">" => ordering == Ordering::Greater,
+    "<" => ordering == Ordering::Less,
+    _ => ordering == Ordering::Equal,
+  }
+}
+
+pub fn version_matches(spec: &str, version: Option<&str>, commit: Option<&str>) -> bool {
+  let spec = spec.trim();
+  if spec == "*" {
+    return true;
+  }
+
+  let is_commit = spec.len() >= 7 && spec.chars().all(|c| c.is_ascii_hexdigit());
+  if is_commit && commit.is_some_and(|commit| commit.starts_with(spec)) {
+    return true;
+  }
+
+  let Some(version) = version else {
+    return false;
+  };
+  spec
+    .split(',')
+    .map(str::trim)
+    .filter(|c| !c.is_empty())
+    .all(|comparator| comparator_matches(comparator, version))
+}
+
+pub fn affects(advisory: &Advisory, package: &LockedPackage) -> bool {
+  (advisory.name == package.name || advisory.name == package.url)
+    && advisory.versions.iter().any(|spec| {
+      version_matches(spec, package.version.as_deref(), package.commit.as_deref())
+    })
+}
+
+/// Every locked package of the project paired with the advisories that affect it.
+pub fn audit<'a>(
+  lockfile: &'a Lockfile,
+  advisories: &'a [Advisory],
+) -> Vec<(&'a LockedPackage, &'a Advisory)> {
+  lockfile
+    .packages
+    .iter()
+    .flat_map(|package| {
+      advisories
+        .iter()
+        .filter(move |advisory| affects(advisory, package))
+        .map(move |advisory| (package, advisory))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_version_matches() {
+    assert!(version_matches("*", None, None));
+    assert!(version_matches("1.2.0", Some("1.2.0"), None));
+    assert!(!version_matches("1.2.0", Some("1.2.1"), None));
+    assert!(version_matches(">=1.0.0, <1.4.2", Some("1.3.9"), None));
+    assert!(!version_matches(">=1.0.0, <1.4.2", Some("1.4.2"), None));
+    assert!(version_matches("<2", Some("v1.10.0"), None));
+    assert!(version_matches("a1b2c3d", None, Some("a1b2c3d4e5f6")));
+    assert!(!version_matches("<1.0.0", None, Some("a1b2c3d4e5f6")));
+  }
+}
@@ -0,0 +1,342 @@
+//! Restrictions applied to the `build` function of dependencies. Builds of
+//! packages that are not trusted with `--allow-build-scripts` may only write
+//! inside the package directory, may only reach the network when their
+//! `lulu.conf.lua` declares `build_permissions = { net = true }`, and cannot
+//! run commands or load native code.
+
+use crate::core::Lulu;
+use mlua::Lua;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the package manager on `lulu build` processes of untrusted
+/// dependencies, to the directory they may write in.
+pub const BUILD_SANDBOX_ENV: &str = "LULU_BUILD_SANDBOX";
+/// Set next to [`BUILD_SANDBOX_ENV`] once the build was allowed the network.
+pub const BUILD_SANDBOX_NET_ENV: &str = "LULU_BUILD_SANDBOX_NET";
+/// Comma separated package names whose builds run unrestricted, or `*` for all.
+pub const ALLOW_BUILD_SCRIPTS_ENV: &str = "LULU_ALLOW_BUILD_SCRIPTS";
+/// Exit code of a sandboxed build that stopped at a denied capability.
+pub const BUILD_DENIED_EXIT: i32 = 77;
+
+/// The error of a capability refused to a sandboxed build.
+pub fn denied(what: &str) -> mlua::Error {
+  mlua::Error::external(format!(
+    "{} is not allowed in a sandboxed dependency build",
+    what
+  ))
+}
+
+pub fn allowed_build_scripts() -> Vec<String> {
+  std::env::var(ALLOW_BUILD_SCRIPTS_ENV)
+    .map(|value| {
+      value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+pub fn is_build_trusted(allowed: &[String], name: &str) -> bool {
+  allowed.iter().any(|allowed| allowed == "*" || allowed == name)
+}
+
+/// Resolves `path` the way the OS would: the longest part of it that exists
+/// is canonicalized, following symlinks, and the rest, which cannot hold
+/// symlinks yet, is resolved lexically. A dangling symlink resolves to where
+/// it points, as writing through it creates that file.
+pub fn resolve(path: &Path) -> PathBuf {
+  resolve_links(path, 0)
+}
+
+fn resolve_links(path: &Path, depth: usize) -> PathBuf {
+  let components: Vec<Component> = path.components().collect();
+  let mut split = components.len();
+  let mut resolved = PathBuf::new();
+  while split > 0 {
+    let prefix: PathBuf = components[..split].iter().collect();
+    if let Ok(real) = prefix.canonicalize() {
+      resolved = real;
+      break;
+    }
+    if depth < 40
+      && let Ok(link) = std::fs::read_link(&prefix)
+    {
+      let target = prefix.parent().unwrap_or(Path::new("/")).join(link);
+      let rest: PathBuf = components[split..].iter().collect();
+      return resolve_links(&target.join(rest), depth + 1);
+    }
+    split -= 1;
+  }
+
+  for component in &components[split..] {
+    match component {
+      Component::ParentDir => {
+        resolved.pop();
+      }
+      Component::CurDir => {}
+      other => resolved.push(other),
+    }
+  }
+  resolved
+}
+
+pub struct BuildSandbox {
+  root: PathBuf,
+  net: AtomicBool,
+  denied: AtomicBool,
+}
+
+impl BuildSandbox {
+  pub fn new(root: &Path) -> Arc<Self> {
+    let root = std::env::current_dir()
+      .map(|cwd| cwd.join(root))
+      .unwrap_or(root.to_path_buf());
+
+    Arc::new(BuildSandbox {
+      root: resolve(&root),
+      net: AtomicBool::new(false),
+      denied: AtomicBool::new(false),
+    })
+  }
+
+  /// The sandbox of this process, when it was started for an untrusted
+  /// dependency or by the build of one.
+  pub fn from_env() -> Option<Arc<Self>> {
+    let root = std::env::var_os(BUILD_SANDBOX_ENV)?;
+    let sandbox = Self::new(Path::new(&root));
+    if std::env::var_os(BUILD_SANDBOX_NET_ENV).is_some() {
+      sandbox.allow_net();
+    }
+    Some(sandbox)
+  }
+
+  /// The environment that puts `lulu` processes started by the build in
+  /// this same sandbox.
+  pub fn child_env(&self) -> Vec<(String, String)> {
+    let mut env = vec![(
+      BUILD_SANDBOX_ENV.to_string(),
+      self.root.to_string_lossy().to_string(),
+    )];
+    if self.net.load(Ordering::SeqCst) {
+      env.push((BUILD_SANDBOX_NET_ENV.to_string(), "1".to_string()));
+    }
+    env
+  }
+
+  /// Called once the package's `build_permissions` asked for network access.
+  pub fn allow_net(&self) {
+    self.net.store(true, Ordering::SeqCst);
+  }
+
+  /// Whether a capability was refused, so the build can exit with
+  /// [`BUILD_DENIED_EXIT`] and let the package manager ask for trust.
+  pub fn was_denied(&self) -> bool {
+    self.denied.load(Ordering::SeqCst)
+  }
+
+  pub fn deny(&self, what: &str) -> mlua::Error {
+    self.denied.store(true, Ordering::SeqCst);
+    denied(what)
+  }
+
+  /// Fails when a `lulu` process started by the build exited with
+  /// [`BUILD_DENIED_EXIT`], so that this build does too.
+  pub fn check_child(&self, what: &str, code: Option<i32>) -> mlua::Result<()> {
+    if code == Some(BUILD_DENIED_EXIT) {
+      self.denied.store(true, Ordering::SeqCst);
+      Err(mlua::Error::external(format!(
+        "{} stopped at a capability the sandbox denied",
+        what
+      )))
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn check_write(&self, path: impl AsRef<Path>) -> mlua::Result<()> {
+    let path = std::env::current_dir()?.join(path);
+    if resolve(&path).starts_with(&self.root) {
+      Ok(())
+    } else {
+      Err(self.deny(&format!("Writing to {}", path.display())))
+    }
+  }
+
+  pub fn check_net(&self, what: &str) -> mlua::Result<()> {
+    if self.net.load(Ordering::SeqCst) {
+      Ok(())
+    } else {
+      Err(self.deny(&format!(
+        "{} (network access, declare `build_permissions = {{ net = true }}`)",
+        what
+      )))
+    }
+  }
+
+  pub fn check_env(&self, key: &str) -> mlua::Result<()> {
+    let sandbox_keys = [
+      BUILD_SANDBOX_ENV,
+      BUILD_SANDBOX_NET_ENV,
+      ALLOW_BUILD_SCRIPTS_ENV,
+    ];
+    if sandbox_keys.contains(&key) {
+      Err(self.deny(&format!("Setting {}", key)))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Replaces the parts of the Lua standard library that can write files,
+  /// run processes or load native code or bytecode with checked versions.
+  pub fn apply(self: &Arc<Self>, lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let io: mlua::Table = globals.get("io")?;
+    let os: mlua::Table = globals.get("os")?;
+    let package: mlua::Table = globals.get("package")?;
+
+    let open: mlua::Function = io.get("open")?;
+    let sandbox = self.clone();
+    io.set(
+      "open",
+      lua.create_function(move |_, (path, mode): (String, Option<String>)| {
+        if mode
+          .as_deref()
+          .is_some_and(|mode| mode.contains(['w', 'a', '+']))
+        {
+          sandbox.check_write(&path)?;
+        }
+        open.call::<mlua::MultiValue>((path, mode))
+      })?,
+    )?;
+
+    let output: mlua::Function = io.get("output")?;
+    let sandbox = self.clone();
+    io.set(
+      "output",
+      lua.create_function(move |_, args: mlua::MultiValue| {
+        if let Some(mlua::Value::String(path)) = args.front() {
+          sandbox.check_write(path.to_str()?.as_ref())?;
+        }
+        output.call::<mlua::MultiValue>(args)
+      })?,
+    )?;
+
+    let remove: mlua::Function = os.get("remove")?;
+    let sandbox = self.clone();
+    os.set(
+      "remove",
+      lua.create_function(move |_, path: String| {
+        sandbox.check_write(&path)?;
+        remove.call::<mlua::MultiValue>(path)
+      })?,
+    )?;
+
+    let rename: mlua::Function = os.get("rename")?;
+    let sandbox = self.clone();
+    os.set(
+      "rename",
+      lua.create_function(move |_, (from, to): (String, String)| {
+        sandbox.check_write(&from)?;
+        sandbox.check_write(&to)?;
+        rename.call::<mlua::MultiValue>((from, to))
+      })?,
+    )?;
+
+    for (table, name) in [(&os, "execute"), (&io, "popen"), (&package, "loadlib")] {
+      let sandbox = self.clone();
+      let what = format!("{}()", name);
+      table.set(
+        name,
+        lua.create_function(move |_, _: mlua::MultiValue| Err::<(), _>(sandbox.deny(&what)))?,
+      )?;
+    }
+    package.set("cpath", "")?;
+
+    // `io.lines`, `io.input`, `loadfile` and `dofile` only read, which the
+    // sandbox allows, but code they load has to be source.
+    crate::permissions::source_only(lua)
+  }
+
+  /// Sandboxes the states `lulu` loads confs, compiles and bundles in, and
+  /// makes it refuse plugins.
+  pub fn apply_lulu(self: &Arc<Self>, lulu: &Lulu) -> mlua::Result<()> {
+    self.apply(&lulu.lua)?;
+    self.apply(lulu.compiler.lua())?;
+    lulu.lua.set_app_data(self.clone());
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_write() {
+    let root = std::env::temp_dir().join(format!("lulu-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let sandbox = BuildSandbox::new(&root);
+
+    assert!(sandbox.check_write(root.join(".lib/dylib/a.so")).is_ok());
+    assert!(!sandbox.was_denied());
+    assert!(sandbox.check_write(root.join("../escape")).is_err());
+    assert!(sandbox.check_write("/etc/passwd").is_err());
+    assert!(sandbox.check_net("download_file").is_err());
+    assert!(sandbox.was_denied());
+
+    assert!(sandbox.check_env(BUILD_SANDBOX_NET_ENV).is_err());
+    assert!(sandbox.check_child("Bundling a", Some(0)).is_ok());
+    assert!(
+      sandbox
+        .check_child("Bundling a", Some(BUILD_DENIED_EXIT))
+        .is_err()
+    );
+    assert_eq!(sandbox.child_env().len(), 1);
+    sandbox.allow_net();
+    let env = sandbox.child_env();
+    assert_eq!(env[0].0, BUILD_SANDBOX_ENV);
+    assert_eq!(Path::new(&env[0].1), resolve(&root));
+    assert_eq!(env[1].0, BUILD_SANDBOX_NET_ENV);
+
+    std::fs::remove_dir_all(root).ok();
+  }
+
+  #[test]
+  fn test_apply() {
+    let root = std::env::temp_dir().join(format!("lulu-sandbox-apply-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let lua = Lua::new();
+    BuildSandbox::new(&root).apply(&lua).unwrap();
+
+    lua
+      .load(format!("io.output({:?}):close()", root.join("out.txt")))
+      .exec()
+      .unwrap();
+    for code in [
+      "io.output('/tmp/../escape.txt')",
+      "os.execute('true')",
+      "io.popen('true')",
+    ] {
+      assert!(lua.load(code).exec().is_err(), "{}", code);
+    }
+    let escapes: bool = lua
+      .load(
+        "return debug ~= nil or jit ~= nil \
+         or load(string.dump(function() end)) ~= nil",
+      )
+      .eval()
+      .unwrap();
+    assert!(!escapes);
+    assert_eq!(
+      lua.load("return load('return 1')()").eval::<i64>().unwrap(),
+      1
+    );
+
+    std::fs::remove_dir_all(root).ok();
+  }
+}
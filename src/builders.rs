@@ -5,6 +5,12 @@ use std::sync::{Arc, RwLock};
 
 pub trait BuilderTrait: Send + Sync {
   fn build(&self, path: &PathBuf, args: &[String]) -> mlua::Result<()>;
+
+  /// Dynamic libraries a successful `build` produced, which `build_with`
+  /// collects into `.lib/dylib`.
+  fn dylibs(&self, _path: &PathBuf, _args: &[String]) -> Vec<PathBuf> {
+    Vec::new()
+  }
 }
 
 pub struct CargoBuilder;
@@ -19,6 +25,52 @@ impl BuilderTrait for CargoBuilder {
       Err(mlua::Error::external(format!("Cargo failed: {:?}", status)))
     }
   }
+
+  /// The `cdylib`s of a `cargo build`, such as lulu plugins.
+  fn dylibs(&self, path: &PathBuf, args: &[String]) -> Vec<PathBuf> {
+    if !args.iter().any(|arg| arg == "build") {
+      return Vec::new();
+    }
+
+    let value_of = |flag: &str| {
+      args.iter().enumerate().find_map(|(i, arg)| {
+        arg
+          .strip_prefix(&format!("{}=", flag))
+          .map(String::from)
+          .or_else(|| (arg == flag).then(|| args.get(i + 1).cloned()).flatten())
+      })
+    };
+    // Cargo puts the `dev` profile in `debug`.
+    let release = args.iter().any(|arg| arg == "--release" || arg == "-r");
+    let profile = match value_of("--profile") {
+      Some(profile) if profile != "dev" => profile,
+      None if release => "release".to_string(),
+      _ => "debug".to_string(),
+    };
+
+    let mut dir = match std::env::var("CARGO_TARGET_DIR") {
+      Ok(dir) => path.join(dir),
+      Err(_) => path.join("target"),
+    };
+    if let Some(target) = value_of("--target") {
+      dir = dir.join(target);
+    }
+
+    let mut dylibs: Vec<PathBuf> = std::fs::read_dir(dir.join(profile))
+      .into_iter()
+      .flatten()
+      .flatten()
+      .map(|entry| entry.path())
+      .filter(|file| {
+        file.is_file()
+          && file
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(std::env::consts::DLL_SUFFIX))
+      })
+      .collect();
+    dylibs.sort();
+    dylibs
+  }
 }
 
 pub struct MakeBuilder;
@@ -92,11 +144,12 @@ pub fn register_default_builders() {
   map.insert("gcc".into(), Arc::new(GCCBuilder));
 }
 
+/// Builds `path` and returns the dynamic libraries the build produced.
 pub fn build_path(
   builder: impl Into<String>,
   path: impl Into<PathBuf>,
   args: impl Into<Vec<String>>,
-) -> mlua::Result<()> {
+) -> mlua::Result<Vec<PathBuf>> {
   let builder_name = builder.into();
 
   let builder = BUILDERS
@@ -106,7 +159,9 @@ pub fn build_path(
     .cloned()
     .ok_or_else(|| mlua::Error::external(format!("Builder '{}' not found", builder_name)))?;
 
-  builder.build(&path.into(), &args.into())
+  let (path, args) = (path.into(), args.into());
+  builder.build(&path, &args)?;
+  Ok(builder.dylibs(&path, &args))
 }
 
 #[cfg(test)]
@@ -376,9 +376,12 @@ function Future.new(fn)
   return self
 end
 
-function Future:poll(...)
+-- Resumes the future once without yielding, `pending` is set when it is
+-- waiting on an async Rust function.
+function Future:step(...)
   if self.done then return self.result end
   local ok, res = coroutine.resume(self.co, ...)
+  self.pending = ok and res == __poll_pending
   if not ok then
     self.error = res
     self.done = true
@@ -387,9 +390,15 @@ function Future:poll(...)
   if coroutine.status(self.co) == "dead" then
     self.done = true
     self.result = res
-  else
+  end
+  return res
+end
+
+function Future:poll(...)
+  local res = self:step(...)
+  if not self.done then
     -- Yield control back to the scheduler after every poll
-    coroutine.yield()
+    coroutine.yield(self.pending and __poll_pending or nil)
   end
   return res
 end
@@ -423,25 +432,36 @@ function async(fn)
   return Future.new(fn)
 end
 
-Future.scheduler = coroutine.create(function()
-  local i = 1
+-- Polls every future once per round. When none of them made progress
+-- because they all wait on async Rust functions, the scheduler yields
+-- `__poll_pending` so `Lulu::exec_final` sleeps until one of those is woken.
+function Future.schedule()
   while #__future_stack > 0 do
-    local fut = __future_stack[i]
-    if not fut.done then
-      fut:poll()
-    end
-    if fut.done then
-      fut:last()
-      table.remove(__future_stack, i)
-    else
-      i = i + 1
+    local progressed = false
+    local i = 1
+    while i <= #__future_stack do
+      local fut = __future_stack[i]
+      if not fut.done then
+        fut:step()
+      end
+      if fut.done then
+        if not fut.cancelled then
+          fut:last()
+        end
+        table.remove(__future_stack, i)
+        progressed = true
+      else
+        progressed = progressed or not fut.pending
+        i = i + 1
+      end
     end
-    if i > #__future_stack then i = 1 end
-    coroutine.yield()
+    coroutine.yield((not progressed) and __poll_pending or nil)
   end
 
   return false
-end)
+end
+
+Future.scheduler = coroutine.create(Future.schedule)
 
 
 
@@ -1376,15 +1396,21 @@ end
 
 
 class! @into_collectible("collect") Sandbox, {
-  init(){
-    self.env = {}
+  init(options){
+    self.vm = create_sandbox(options)
   }
   set(key, val) {
-    self.env[key] = val
+    self.vm:set(key, val)
     return self
   }
+  get(key) {
+    return self.vm:get(key)
+  }
   eval(code, name){
-    return exec_sandboxed(code, name or "lulu::sandbox", self.env)
+    return self.vm:eval(code, name or "lulu::sandbox")
+  }
+  memory(){
+    return self.vm:memory()
   }
 }
 
@@ -1439,7 +1465,30 @@ function dylib_cdef(def)
   end
 end
 
+-- The name of the strict module that called into std, if it is one. Walks
+-- the stack with `getfenv`, as `debug` is gone without the ffi permission.
+local function strict_caller()
+  -- 1 is pcall, 2 this function and 3 the one from std that called it.
+  local level = 4
+  local ok, env = pcall(getfenv, level)
+  while ok do
+    if env ~= _G then
+      local m = rawget(env, "mod")
+      if type(m) == "table" and rawget(m, "strict") then
+        return m.name
+      end
+      return nil
+    end
+    level = level + 1
+    ok, env = pcall(getfenv, level)
+  end
+end
+
 function into_global(key, value)
+  local strict = strict_caller()
+  if strict then
+    error("module '" .. strict .. "' can't assign the global '" .. tostring(key) .. "' in strict mode", 2)
+  end
   _G[key] = value
   return value
 end
@@ -1448,6 +1497,19 @@ function globalize(thing, name)
   return into_global(name, thing)
 end
 
+function import_names(exports, name, ...)
+  local keys = { ... }
+  local values = {}
+  for i, key in ipairs(keys) do
+    local value = type(exports) == "table" and exports[key] or nil
+    if value == nil then
+      error("module '" .. name .. "' has no export '" .. key .. "'", 2)
+    end
+    values[i] = value
+  end
+  return unpack(values, 1, #keys)
+end
+
 local _keystore = {}
 
 function static(key, val)
@@ -0,0 +1,51 @@
+function timers.after(ms, fn)
+  local timer = timers.timer()
+  async(function()
+    if timer:wait(ms) then
+      fn()
+    end
+  end)
+  return timer
+end
+
+function timers.every(ms, fn)
+  local timer = timers.timer()
+  async(function()
+    while timer:wait(ms) do
+      fn()
+    end
+  end)
+  return timer
+end
+
+function timers.cancel(timer)
+  timer:cancel()
+end
+
+-- A future that resolves like `future`, or fails once `timers.now()` reaches
+-- `at`. The scheduler keeps running `future`, this only waits for it to be
+-- done, and drops it once it timed out.
+function timers.deadline(future, at)
+  local timer = timers.timer()
+  local expired = false
+  async(function()
+    expired = timer:wait(math.max(at - timers.now(), 0))
+  end)
+
+  return async(function()
+    while not future.done do
+      if expired then
+        future.done = true
+        future.cancelled = true
+        error("Future timed out", 0)
+      end
+      coroutine.yield(__poll_pending)
+    end
+    timer:cancel()
+    return future:last()
+  end)
+end
+
+function timers.timeout(future, ms)
+  return timers.deadline(future, timers.now() + ms)
+end
@@ -1,89 +1,246 @@
 use crate::conf::{conf_to_string, load_lulu_conf_from_bytecode};
 use crate::core::{LuLib, Lulu, LuluModSource};
-use crate::util::lua_to_bytecode;
+use crate::signing::{BundleSignature, SIGNATURE_TRAILER_LEN, TrustPolicy, sign_bundle};
+use crate::target::Target;
+use crate::sourcemap::generate_line_map;
+use crate::util::{dump_function, lua_to_bytecode, module_bytecode};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
+#[derive(Debug, Clone, Default)]
+pub struct BundleOptions {
+  /// Private key used to append an ed25519 signature trailer.
+  pub sign: Option<PathBuf>,
+  /// Platform to bundle for, the host when `None`.
+  pub target: Option<Target>,
+  /// zstd compress modules and assets.
+  pub compress: bool,
+  /// Globs of modules to bundle even when nothing requires them statically.
+  pub keep: Vec<String>,
+  /// Strip debug info from the bytecode of every module.
+  pub release: bool,
+  /// Minify code modules before they are compiled.
+  pub minify: bool,
+  /// Embed line maps so errors point at the original source lines.
+  pub debug: bool,
+}
+
+/// Footer magic of the original layout: `[total size][module count]LUL!`.
+const MAGIC_V1: &[u8; 4] = b"LUL!";
+/// Footer magic of versioned layouts: `[total size][version]LULV`.
+const MAGIC_VERSIONED: &[u8; 4] = b"LULV";
+const FOOTER_V1_LEN: u64 = 20;
+const FOOTER_VERSIONED_LEN: u64 = 16;
+pub const BUNDLE_VERSION: u32 = 2;
+
+const ENTRY_MODULE: u8 = 0;
+const ENTRY_CONF: u8 = 1;
+const ENTRY_ASSET: u8 = 2;
+const ENTRY_SOURCEMAP: u8 = 3;
+
+const LUAJIT_SIGNATURE: &[u8] = b"\x1bLJ";
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+const ZSTD_LEVEL: i32 = 19;
+/// The most an entry may decompress to, whatever its header claims.
+const MAX_ENTRY_LEN: usize = 1 << 30;
+
+/// The modules and asset files stored in a `.lulib` or appended to an executable.
+#[derive(Debug, Clone, Default)]
+pub struct Bundle {
+  pub mods: HashMap<String, LuLib>,
+  pub assets: HashMap<String, Vec<u8>>,
+  /// Original line of every Lua line of a module, for `--debug` bundles.
+  pub sourcemaps: HashMap<String, Vec<u32>>,
+}
+
+impl From<HashMap<String, LuLib>> for Bundle {
+  fn from(mods: HashMap<String, LuLib>) -> Self {
+    Bundle {
+      mods,
+      ..Default::default()
+    }
+  }
+}
+
+fn invalid_data(message: impl Into<String>) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+struct HeaderEntry<'a> {
+  kind: u8,
+  name: &'a str,
+  conf_idx: u32,
+  data: &'a [u8],
+}
+
+/// Writes a version 2 bundle: a header table describing every module, conf
+/// and asset, followed by their data and the `LULV` footer. Identical blobs
+/// are stored once, and with `compress` each blob is zstd compressed when
+/// that makes it smaller.
 pub fn write_bundle<W: Write>(
   writer: &mut W,
-  bytes: HashMap<String, LuLib>,
+  bundle: &Bundle,
+  compress: bool,
 ) -> std::io::Result<()> {
-  let mut total_size: u64 = 0;
-
-  let mut unique_confs: Vec<Vec<u8>> = Vec::new();
-  let mut conf_map: HashMap<Vec<u8>, u32> = HashMap::new();
-
-  for (_name, lib) in &bytes {
-    if let Some(conf) = &lib.conf {
-      if !conf_map.contains_key(conf) {
-        let idx = unique_confs.len() as u32;
-        unique_confs.push(conf.clone());
-        conf_map.insert(conf.clone(), idx);
-      }
+  let mut confs: Vec<&[u8]> = Vec::new();
+  let mut conf_map: HashMap<&[u8], u32> = HashMap::new();
+  for lib in bundle.mods.values() {
+    if let Some(conf) = &lib.conf
+      && !conf_map.contains_key(conf.as_slice())
+    {
+      conf_map.insert(conf, confs.len() as u32);
+      confs.push(conf);
     }
   }
 
-  for (name, lib) in &bytes {
-    let name_bytes = name.as_bytes();
-    let name_len = name_bytes.len() as u32;
-    let data_len = lib.bytes.len() as u64;
-    let conf_idx = lib
-      .conf
-      .as_ref()
-      .and_then(|c| conf_map.get(c).copied())
-      .unwrap_or(u32::MAX);
+  let mut entries: Vec<HeaderEntry> = confs
+    .iter()
+    .map(|conf| HeaderEntry {
+      kind: ENTRY_CONF,
+      name: "",
+      conf_idx: u32::MAX,
+      data: conf,
+    })
+    .collect();
+
+  let mut mods: Vec<_> = bundle.mods.iter().collect();
+  mods.sort_by(|a, b| a.0.cmp(b.0));
+  for (name, lib) in mods {
+    entries.push(HeaderEntry {
+      kind: ENTRY_MODULE,
+      name,
+      conf_idx: lib
+        .conf
+        .as_ref()
+        .and_then(|c| conf_map.get(c.as_slice()).copied())
+        .unwrap_or(u32::MAX),
+      data: &lib.bytes,
+    });
+  }
 
-    writer.write_all(&name_len.to_le_bytes())?;
-    writer.write_all(name_bytes)?;
-    writer.write_all(&data_len.to_le_bytes())?;
-    writer.write_all(&lib.bytes)?;
-    writer.write_all(&conf_idx.to_le_bytes())?;
+  let mut assets: Vec<_> = bundle.assets.iter().collect();
+  assets.sort_by(|a, b| a.0.cmp(b.0));
+  for (name, data) in assets {
+    entries.push(HeaderEntry {
+      kind: ENTRY_ASSET,
+      name,
+      conf_idx: u32::MAX,
+      data,
+    });
+  }
 
-    total_size += 4 + name_bytes.len() as u64 + 8 + data_len + 4;
+  let mut sourcemaps: Vec<(&String, Vec<u8>)> = bundle
+    .sourcemaps
+    .iter()
+    .map(|(name, map)| (name, map.iter().flat_map(|line| line.to_le_bytes()).collect()))
+    .collect();
+  sourcemaps.sort_by(|a, b| a.0.cmp(b.0));
+  for (name, data) in &sourcemaps {
+    entries.push(HeaderEntry {
+      kind: ENTRY_SOURCEMAP,
+      name,
+      conf_idx: u32::MAX,
+      data,
+    });
   }
 
-  let conf_count = unique_confs.len() as u64;
-  writer.write_all(&conf_count.to_le_bytes())?;
-  total_size += 8;
+  let mut data_section: Vec<u8> = Vec::new();
+  let mut blobs: HashMap<&[u8], (u8, u64, u64)> = HashMap::new();
+  let mut header: Vec<u8> = Vec::new();
+  header.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+
+  for entry in &entries {
+    let (compression, offset, stored_len) = match blobs.get(entry.data) {
+      Some(blob) => *blob,
+      None => {
+        let compressed = if compress {
+          zstd::bulk::compress(entry.data, ZSTD_LEVEL)
+            .ok()
+            .filter(|c| c.len() < entry.data.len())
+        } else {
+          None
+        };
+        let (compression, stored) = match &compressed {
+          Some(compressed) => (COMPRESSION_ZSTD, compressed.as_slice()),
+          None => (COMPRESSION_NONE, entry.data),
+        };
+
+        let blob = (compression, data_section.len() as u64, stored.len() as u64);
+        data_section.extend_from_slice(stored);
+        blobs.insert(entry.data, blob);
+        blob
+      }
+    };
 
-  for conf in &unique_confs {
-    let len = conf.len() as u64;
-    writer.write_all(&len.to_le_bytes())?;
-    writer.write_all(conf)?;
-    total_size += 8 + len;
+    header.push(entry.kind);
+    header.push(compression);
+    header.extend_from_slice(&(entry.name.len() as u32).to_le_bytes());
+    header.extend_from_slice(entry.name.as_bytes());
+    header.extend_from_slice(&entry.conf_idx.to_le_bytes());
+    header.extend_from_slice(&offset.to_le_bytes());
+    header.extend_from_slice(&stored_len.to_le_bytes());
+    header.extend_from_slice(&(entry.data.len() as u64).to_le_bytes());
   }
 
-  let module_count = bytes.len() as u64;
+  let total_size = (8 + header.len() + data_section.len()) as u64;
 
+  writer.write_all(&(header.len() as u64).to_le_bytes())?;
+  writer.write_all(&header)?;
+  writer.write_all(&data_section)?;
   writer.write_all(&total_size.to_le_bytes())?;
-  writer.write_all(&module_count.to_le_bytes())?;
-  writer.write_all(b"LUL!")?;
+  writer.write_all(&BUNDLE_VERSION.to_le_bytes())?;
+  writer.write_all(MAGIC_VERSIONED)?;
 
   Ok(())
 }
 
-pub fn write_bin(output: &PathBuf, bytes: HashMap<String, LuLib>) -> std::io::Result<()> {
-  let mut bin = OpenOptions::new().append(true).open(output)?;
-  write_bundle(&mut bin, bytes)
+pub fn encode_bundle(bundle: &Bundle, options: &BundleOptions) -> std::io::Result<Vec<u8>> {
+  let mut body = Vec::new();
+  write_bundle(&mut body, bundle, options.compress)?;
+
+  if let Some(key) = &options.sign {
+    let signature = sign_bundle(&body, key)?;
+    body.extend_from_slice(&signature.to_bytes());
+  }
+
+  Ok(body)
 }
 
 static EXEC_PATH: std::sync::Mutex<Option<PathBuf>> = std::sync::Mutex::new(None);
 
-fn get_exec_path() -> PathBuf {
-  let mut guard = EXEC_PATH.lock().unwrap();
+/// The stub set with `set_stub`/`stubs` or `LULU_EXEC_PATH`, otherwise the
+/// running executable for the host or a stub from the stubs directory.
+fn get_exec_path(target: &Target) -> std::io::Result<PathBuf> {
+  let guard = EXEC_PATH.lock().unwrap();
 
   if let Some(path) = &*guard {
-    return path.clone();
+    return Ok(path.clone());
   }
 
-  let path = std::env::var("LULU_EXEC_PATH")
-    .map(PathBuf::from)
-    .unwrap_or_else(|_| std::env::current_exe().unwrap());
+  if let Ok(path) = std::env::var("LULU_EXEC_PATH") {
+    return Ok(PathBuf::from(path));
+  }
+
+  if target.is_host() {
+    return std::env::current_exe();
+  }
 
-  *guard = Some(path.clone());
-  path
+  target.find_stub().ok_or_else(|| {
+    std::io::Error::new(
+      std::io::ErrorKind::NotFound,
+      format!(
+        "No stub runtime for {}, put one at {} or set one with set_stub/stubs",
+        target,
+        Target::stubs_dir()
+          .map(|dir| dir.join(format!("lulu-{}", target)).display().to_string())
+          .unwrap_or(format!("${}/lulu-{}", crate::target::STUBS_DIR_ENV, target))
+      ),
+    )
+  })
 }
 
 pub fn set_exec_path<P: Into<PathBuf>>(path: P) {
@@ -91,14 +248,14 @@ pub fn set_exec_path<P: Into<PathBuf>>(path: P) {
   *guard = Some(path.into());
 }
 
-pub fn make_bin(output: &PathBuf, bytes: HashMap<String, LuLib>) -> std::io::Result<()> {
-  let exe_path = get_exec_path();
-
-  let output = if std::env::consts::OS == "windows" {
-    &output.with_extension("exe")
-  } else {
-    output
-  };
+pub fn make_bin(
+  output: &Path,
+  bundle: &Bundle,
+  options: &BundleOptions,
+) -> std::io::Result<()> {
+  let target = options.target.clone().unwrap_or_else(Target::host);
+  let exe_path = get_exec_path(&target)?;
+  let output = target.exe_path(output);
 
   let mut exe_file = File::open(&exe_path)?;
   let mut exe_contents = Vec::new();
@@ -107,8 +264,7 @@ pub fn make_bin(output: &PathBuf, bytes: HashMap<String, LuLib>) -> std::io::Res
   let mut file = File::create(&output)?;
 
   file.write_all(&exe_contents)?;
-
-  write_bin(output, bytes)?;
+  file.write_all(&encode_bundle(bundle, options)?)?;
 
   #[cfg(unix)]
   {
@@ -123,24 +279,202 @@ pub fn make_bin(output: &PathBuf, bytes: HashMap<String, LuLib>) -> std::io::Res
   Ok(())
 }
 
-fn load_bundle_from_reader<R: Read + Seek>(
+/// Reads the bundle at the end of `reader`, returning its body (everything
+/// the signature covers, `LUL!` or `LULV` footer included) and its signature,
+/// if any.
+pub fn read_bundle_body<R: Read + Seek>(
+  reader: &mut R,
+) -> std::io::Result<(Vec<u8>, Option<BundleSignature>)> {
+  let len = reader.seek(SeekFrom::End(0))?;
+
+  let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid bundle magic");
+
+  let mut signature = None;
+  let mut end = len;
+  if len >= SIGNATURE_TRAILER_LEN as u64 {
+    reader.seek(SeekFrom::Start(len - SIGNATURE_TRAILER_LEN as u64))?;
+    let mut trailer = vec![0u8; SIGNATURE_TRAILER_LEN];
+    reader.read_exact(&mut trailer)?;
+    if let Some(sig) = BundleSignature::from_trailer(&trailer) {
+      signature = Some(sig);
+      end = len - SIGNATURE_TRAILER_LEN as u64;
+    }
+  }
+
+  if end < FOOTER_VERSIONED_LEN {
+    return Err(invalid());
+  }
+
+  reader.seek(SeekFrom::Start(end - 4))?;
+  let mut magic = [0u8; 4];
+  reader.read_exact(&mut magic)?;
+
+  let footer_len = match &magic {
+    MAGIC_V1 => FOOTER_V1_LEN,
+    MAGIC_VERSIONED => FOOTER_VERSIONED_LEN,
+    _ => return Err(invalid()),
+  };
+  if end < footer_len {
+    return Err(invalid());
+  }
+
+  reader.seek(SeekFrom::Start(end - footer_len))?;
+  let mut total_size_buf = [0u8; 8];
+  reader.read_exact(&mut total_size_buf)?;
+  let total_size = u64::from_le_bytes(total_size_buf);
+  if total_size.checked_add(footer_len).is_none_or(|size| size > end) {
+    return Err(invalid());
+  }
+
+  let start = end - footer_len - total_size;
+  reader.seek(SeekFrom::Start(start))?;
+  let mut body = vec![0u8; (end - start) as usize];
+  reader.read_exact(&mut body)?;
+
+  Ok((body, signature))
+}
+
+pub fn load_bundle_from_reader<R: Read + Seek>(
   reader: &mut R,
-) -> std::io::Result<HashMap<String, LuLib>> {
-  reader.seek(SeekFrom::End(-20))?;
-  let mut footer = [0u8; 20];
-  reader.read_exact(&mut footer)?;
+  policy: &TrustPolicy,
+) -> std::io::Result<Bundle> {
+  let (body, signature) = read_bundle_body(reader)?;
+  policy.check(&body, signature.as_ref())?;
+  parse_bundle_body(&body)
+}
+
+/// The layout version of a body returned by [`read_bundle_body`].
+pub fn bundle_version(body: &[u8]) -> u32 {
+  if body.ends_with(MAGIC_V1) {
+    return 1;
+  }
+
+  let footer = &body[body.len() - FOOTER_VERSIONED_LEN as usize..];
+  u32::from_le_bytes(footer[8..12].try_into().unwrap())
+}
+
+/// Parses a body returned by [`read_bundle_body`], in either layout.
+pub fn parse_bundle_body(body: &[u8]) -> std::io::Result<Bundle> {
+  match bundle_version(body) {
+    1 => parse_bundle_v1(body).map(Bundle::from),
+    2 => parse_bundle_v2(&body[..body.len() - FOOTER_VERSIONED_LEN as usize]),
+    version => Err(invalid_data(format!(
+      "Unsupported bundle version {}, this lulu reads up to {}",
+      version, BUNDLE_VERSION
+    ))),
+  }
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+  let mut buf = [0u8; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+  let mut buf = [0u8; 8];
+  reader.read_exact(&mut buf)?;
+  Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads `len` bytes, failing before anything is allocated when the body
+/// doesn't have that many left.
+fn read_bytes(reader: &mut Cursor<&[u8]>, len: usize) -> std::io::Result<Vec<u8>> {
+  let left = (reader.get_ref().len() as u64).saturating_sub(reader.position());
+  if len as u64 > left {
+    return Err(invalid_data("Bundle is truncated"));
+  }
+  let mut bytes = vec![0u8; len];
+  reader.read_exact(&mut bytes)?;
+  Ok(bytes)
+}
+
+/// Decompresses an entry, which has to come out as `raw_len` bytes.
+fn decompress_entry(stored: &[u8], raw_len: usize, name: &str) -> std::io::Result<Vec<u8>> {
+  if raw_len > MAX_ENTRY_LEN {
+    return Err(invalid_data(format!("Bundle entry {} is too large", name)));
+  }
+  let mut bytes = Vec::new();
+  zstd::stream::read::Decoder::new(stored)?
+    .take(raw_len as u64 + 1)
+    .read_to_end(&mut bytes)?;
+  if bytes.len() != raw_len {
+    return Err(invalid_data(format!(
+      "Bundle entry {} doesn't have the size it claims",
+      name
+    )));
+  }
+  Ok(bytes)
+}
+
+fn parse_bundle_v2(body: &[u8]) -> std::io::Result<Bundle> {
+  let reader = &mut Cursor::new(body);
+  let header_len = read_u64(reader)? as usize;
+  let data_start = 8 + header_len;
+  if data_start > body.len() {
+    return Err(invalid_data("Bundle header is truncated"));
+  }
+  let data = &body[data_start..];
+
+  let entry_count = read_u32(reader)?;
+  let mut confs: Vec<Vec<u8>> = Vec::new();
+  let mut mods: Vec<(String, Vec<u8>, u32)> = Vec::new();
+  let mut bundle = Bundle::default();
+
+  for _ in 0..entry_count {
+    let mut kind_buf = [0u8; 2];
+    reader.read_exact(&mut kind_buf)?;
+    let [kind, compression] = kind_buf;
+
+    let name_len = read_u32(reader)? as usize;
+    let name = String::from_utf8(read_bytes(reader, name_len)?)
+      .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let conf_idx = read_u32(reader)?;
+    let offset = read_u64(reader)? as usize;
+    let stored_len = read_u64(reader)? as usize;
+    let raw_len = read_u64(reader)? as usize;
+
+    let stored = offset
+      .checked_add(stored_len)
+      .and_then(|end| data.get(offset..end))
+      .ok_or_else(|| invalid_data(format!("Bundle entry {} is out of bounds", name)))?;
+    let bytes = match compression {
+      COMPRESSION_NONE => stored.to_vec(),
+      COMPRESSION_ZSTD => decompress_entry(stored, raw_len, &name)?,
+      other => return Err(invalid_data(format!("Unknown compression {} for {}", other, name))),
+    };
+
+    match kind {
+      ENTRY_CONF => confs.push(bytes),
+      ENTRY_MODULE => mods.push((name, bytes, conf_idx)),
+      ENTRY_ASSET => {
+        bundle.assets.insert(name, bytes);
+      }
+      ENTRY_SOURCEMAP => {
+        let map = bytes
+          .chunks_exact(4)
+          .map(|line| u32::from_le_bytes(line.try_into().unwrap()))
+          .collect();
+        bundle.sourcemaps.insert(name, map);
+      }
+      _ => {}
+    }
+  }
 
-  if &footer[16..20] != b"LUL!" {
-    return Err(std::io::Error::new(
-      std::io::ErrorKind::InvalidData,
-      "Invalid bundle magic",
-    ));
+  for (name, bytes, conf_idx) in mods {
+    let conf = confs.get(conf_idx as usize).cloned();
+    bundle.mods.insert(name, LuLib { bytes, conf });
   }
 
-  let total_size = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+  Ok(bundle)
+}
+
+fn parse_bundle_v1(body: &[u8]) -> std::io::Result<HashMap<String, LuLib>> {
+  let footer = &body[body.len() - FOOTER_V1_LEN as usize..];
   let module_count = u64::from_le_bytes(footer[8..16].try_into().unwrap());
 
-  reader.seek(SeekFrom::End(-(20 + total_size as i64)))?;
+  let reader = &mut Cursor::new(body);
 
   let mut modules = HashMap::new();
 
@@ -150,17 +484,14 @@ fn load_bundle_from_reader<R: Read + Seek>(
     reader.read_exact(&mut name_len_buf)?;
     let name_len = u32::from_le_bytes(name_len_buf) as usize;
 
-    let mut name_bytes = vec![0u8; name_len];
-    reader.read_exact(&mut name_bytes)?;
-    let name = String::from_utf8(name_bytes)
+    let name = String::from_utf8(read_bytes(reader, name_len)?)
       .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
     let mut data_len_buf = [0u8; 8];
     reader.read_exact(&mut data_len_buf)?;
     let data_len = u64::from_le_bytes(data_len_buf) as usize;
 
-    let mut data = vec![0u8; data_len];
-    reader.read_exact(&mut data)?;
+    let data = read_bytes(reader, data_len)?;
 
     let mut conf_idx_buf = [0u8; 4];
     reader.read_exact(&mut conf_idx_buf)?;
@@ -179,14 +510,17 @@ fn load_bundle_from_reader<R: Read + Seek>(
     reader.read_exact(&mut len_buf)?;
     let len = u64::from_le_bytes(len_buf) as usize;
 
-    let mut conf = vec![0u8; len];
-    reader.read_exact(&mut conf)?;
-    confs.push(conf);
+    confs.push(read_bytes(reader, len)?);
   }
 
   for (name, data, conf_idx) in module_meta {
     let conf = if conf_idx != u32::MAX {
-      Some(confs[conf_idx as usize].clone())
+      Some(
+        confs
+          .get(conf_idx as usize)
+          .ok_or_else(|| invalid_data(format!("Bundle module {} has no conf", name)))?
+          .clone(),
+      )
     } else {
       None
     };
@@ -196,19 +530,39 @@ fn load_bundle_from_reader<R: Read + Seek>(
   Ok(modules)
 }
 
-pub fn load_lulib(path: &Path) -> std::io::Result<HashMap<String, LuLib>> {
+pub fn load_lulib(path: &Path) -> std::io::Result<Bundle> {
+  load_lulib_trusted(path, &TrustPolicy::from_keyring())
+}
+
+pub fn load_lulib_trusted(
+  path: &Path,
+  policy: &TrustPolicy,
+) -> std::io::Result<Bundle> {
   let mut f = File::open(path)?;
-  load_bundle_from_reader(&mut f)
+  load_bundle_from_reader(&mut f, policy)
+    .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
 }
 
-pub fn load_embedded_scripts() -> Option<HashMap<String, LuLib>> {
+pub fn load_embedded_scripts() -> Option<Bundle> {
   let exe = std::env::current_exe().ok()?;
   let mut f = File::open(&exe).ok()?;
-  load_bundle_from_reader(&mut f).ok()
+  match load_bundle_from_reader(&mut f, &TrustPolicy::from_keyring()) {
+    Ok(mods) => Some(mods),
+    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    }
+    Err(_) => None,
+  }
 }
 
-pub fn reg_bundle_nods(lulu: &mut Lulu, mods: HashMap<String, LuLib>) -> mlua::Result<()> {
-  for (name, data) in mods.iter() {
+pub fn reg_bundle_nods(lulu: &mut Lulu, bundle: Bundle) -> mlua::Result<()> {
+  for (name, data) in bundle.assets {
+    lulu.assets.entry(name).or_insert(data);
+  }
+  lulu.sourcemaps.extend(bundle.sourcemaps);
+
+  for (name, data) in bundle.mods.iter() {
     let conf = if let Some(confbytes) = data.conf.clone() {
       let conf = load_lulu_conf_from_bytecode(&lulu.lua, confbytes)?;
 
@@ -229,20 +583,78 @@ pub fn reg_bundle_nods(lulu: &mut Lulu, mods: HashMap<String, LuLib>) -> mlua::R
   Ok(())
 }
 
-pub async fn run_bundle(mods: HashMap<String, LuLib>, lulu: &mut Lulu) -> mlua::Result<()> {
-  reg_bundle_nods(lulu, mods)?;
+pub async fn run_bundle(bundle: Bundle, lulu: &mut Lulu) -> mlua::Result<()> {
+  reg_bundle_nods(lulu, bundle)?;
+
+  let main_name = lulu.find_mod("main")?;
+  let main_conf = lulu
+    .mods
+    .iter()
+    .find(|lmod| lmod.name == main_name)
+    .and_then(|lmod| lmod.conf.clone());
+  if let Some(conf) = main_conf {
+    if lulu.permissions.is_none() {
+      lulu.permissions = conf.permissions.clone();
+    }
+    lulu.load_plugins(&conf)?;
+  }
 
   lulu.preload_mods()?;
 
-  let main_name = lulu.find_mod("main")?;
   lulu.exec_final(main_name.as_str()).await?;
   Ok(())
 }
 
-pub fn bundle_lulu_or_exec(lulu: &mut Lulu, file: PathBuf, output: PathBuf) -> mlua::Result<()> {
-  lulu.entry_mod_path(file.clone())?;
+/// Prints the size of every stripped module next to its size with debug info.
+fn report_release_sizes(mods: &HashMap<String, LuLib>, debug_sizes: &HashMap<String, usize>) {
+  let mut rows: Vec<(&String, usize, usize)> = mods
+    .iter()
+    .filter_map(|(name, lib)| Some((name, *debug_sizes.get(name)?, lib.bytes.len())))
+    .collect();
+  if rows.is_empty() {
+    return;
+  }
+  rows.sort();
+
+  let format_size = |size: usize| crate::cache::format_size(size as u64);
+  let name_w = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0).max(6);
+  println!("{:name_w$}  {:>10}  {:>10}  {:>7}", "MODULE", "DEBUG", "RELEASE", "DELTA");
+  for (name, before, after) in &rows {
+    println!(
+      "{:name_w$}  {:>10}  {:>10}  {:>6.1}%",
+      name,
+      format_size(*before),
+      format_size(*after),
+      (*after as f64 - *before as f64) / *before as f64 * 100.0
+    );
+  }
+
+  let before: usize = rows.iter().map(|(_, before, _)| before).sum();
+  let after: usize = rows.iter().map(|(_, _, after)| after).sum();
+  println!(
+    "{:name_w$}  {:>10}  {:>10}  {:>6.1}%",
+    "total",
+    format_size(before),
+    format_size(after),
+    (after as f64 - before as f64) / before as f64 * 100.0
+  );
+}
+
+pub fn bundle_lulu_or_exec(
+  lulu: &mut Lulu,
+  file: PathBuf,
+  output: PathBuf,
+  options: &BundleOptions,
+) -> mlua::Result<()> {
+  if let Some(target) = &options.target {
+    target.apply_defs(&mut lulu.compiler.defs);
+  }
+  let entry = lulu.entry_mod_path(file.clone())?;
 
   let mut combined_bytes = HashMap::<String, LuLib>::new();
+  let mut sourcemaps = HashMap::<String, Vec<u32>>::new();
+  // Size of each module with debug info, to report what `--release` saved.
+  let mut debug_sizes = HashMap::<String, usize>::new();
 
   for lmod in &lulu.mods {
     let conf = if let Some(conf) = lmod.conf.clone() {
@@ -251,34 +663,188 @@ pub fn bundle_lulu_or_exec(lulu: &mut Lulu, file: PathBuf, output: PathBuf) -> m
     } else {
       None
     };
-    match &lmod.source {
+    let bytes = match &lmod.source {
       LuluModSource::Code(code) => {
-        combined_bytes.insert(
-          lmod.name.clone(),
-          LuLib {
-            bytes: lua_to_bytecode(&lulu.lua, code.as_str())?,
-            conf,
-          },
-        );
+        if options.debug
+          && let Some(original) = lmod.path.as_ref().and_then(|p| std::fs::read_to_string(p).ok())
+        {
+          sourcemaps.insert(lmod.name.clone(), generate_line_map(&original, code));
+        }
+
+        if options.release {
+          let debug = module_bytecode(&lulu.lua, &lmod.name, code.as_bytes(), false)?;
+          debug_sizes.insert(lmod.name.clone(), debug.len());
+          let code = if options.minify {
+            crate::minify::minify(code)
+          } else {
+            code.clone()
+          };
+          module_bytecode(&lulu.lua, &lmod.name, code.as_bytes(), true)?
+        } else {
+          module_bytecode(&lulu.lua, &lmod.name, code.as_bytes(), false)?
+        }
       }
-      LuluModSource::Bytecode(bytes) => {
-        combined_bytes.insert(
-          lmod.name.clone(),
-          LuLib {
-            bytes: bytes.clone(),
-            conf,
-          },
-        );
+      // Lua modules of included libraries are dumped again without debug
+      // info, other bytes are left alone.
+      LuluModSource::Bytecode(bytes) if options.release && bytes.starts_with(LUAJIT_SIGNATURE) => {
+        debug_sizes.insert(lmod.name.clone(), bytes.len());
+        let func = lulu.lua.load(&bytes[..]).into_function()?;
+        dump_function(&lulu.lua, &func, true)?
       }
+      LuluModSource::Bytecode(bytes) => bytes.clone(),
+    };
+
+    combined_bytes.insert(lmod.name.clone(), LuLib { bytes, conf });
+  }
+
+  let packages: HashMap<String, String> = lulu
+    .mods
+    .iter()
+    .filter_map(|lmod| {
+      let manifest = lmod.conf.as_ref()?.manifest.as_ref()?;
+      let package = manifest.get::<Option<String>>("name").ok().flatten()?;
+      Some((lmod.name.clone(), package))
+    })
+    .collect();
+
+  // A library is entered through any module of its own package.
+  let is_lulib = output.extension().and_then(|s| s.to_str()) == Some("lulib");
+  let roots: Vec<String> = match packages.get(&entry) {
+    Some(package) if is_lulib => packages
+      .iter()
+      .filter(|(_, p)| *p == package)
+      .map(|(name, _)| name.clone())
+      .collect(),
+    _ => vec![entry],
+  };
+
+  let reached = crate::treeshake::reachable(&combined_bytes, &packages, &roots, &options.keep);
+  let mut dropped: Vec<(String, usize)> = combined_bytes
+    .iter()
+    .filter(|(name, _)| !reached.contains(*name))
+    .map(|(name, lib)| (name.clone(), lib.bytes.len()))
+    .collect();
+  if !dropped.is_empty() {
+    dropped.sort();
+    println!(
+      "Left out {} of {} modules that are never required ({}), use --keep to bundle them:",
+      dropped.len(),
+      combined_bytes.len(),
+      crate::cache::format_size(dropped.iter().map(|(_, size)| *size as u64).sum())
+    );
+    for (name, size) in &dropped {
+      println!("  {} ({})", name, crate::cache::format_size(*size as u64));
     }
+    combined_bytes.retain(|name, _| reached.contains(name));
+    sourcemaps.retain(|name, _| reached.contains(name));
+  }
+
+  if options.release {
+    report_release_sizes(&combined_bytes, &debug_sizes);
   }
 
-  if output.extension().and_then(|s| s.to_str()) == Some("lulib") {
-    let mut f = File::create(output)?;
-    write_bundle(&mut f, combined_bytes)?;
+  let bundle = Bundle {
+    mods: combined_bytes,
+    assets: lulu.assets.clone(),
+    sourcemaps,
+  };
+
+  if is_lulib {
+    std::fs::write(output, encode_bundle(&bundle, options)?)?;
   } else {
-    make_bin(&output, combined_bytes)?;
+    make_bin(&output, &bundle, options)?;
   }
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lib(bytes: &[u8], conf: Option<&[u8]>) -> LuLib {
+    LuLib {
+      bytes: bytes.to_vec(),
+      conf: conf.map(|c| c.to_vec()),
+    }
+  }
+
+  #[test]
+  fn test_roundtrip_v2() {
+    let code = b"return 'hello hello hello hello hello hello hello'".repeat(8);
+    let mut bundle = Bundle::default();
+    bundle.mods.insert("main".into(), lib(&code, Some(b"conf")));
+    bundle.mods.insert("copy".into(), lib(&code, Some(b"conf")));
+    bundle.mods.insert("bare".into(), lib(b"x", None));
+    bundle.assets.insert("assets/a.txt".into(), b"asset".to_vec());
+
+    let mut plain = Vec::new();
+    write_bundle(&mut plain, &bundle, false).unwrap();
+    let mut compressed = Vec::new();
+    write_bundle(&mut compressed, &bundle, true).unwrap();
+    assert!(compressed.len() < plain.len());
+    assert!(plain.len() < code.len() * 2);
+
+    let (body, _) = read_bundle_body(&mut Cursor::new(compressed)).unwrap();
+    let parsed = parse_bundle_body(&body).unwrap();
+    assert_eq!(parsed.mods["copy"].bytes, code);
+    assert_eq!(parsed.mods["main"].conf.as_deref(), Some(&b"conf"[..]));
+    assert_eq!(parsed.mods["bare"].conf, None);
+    assert_eq!(parsed.assets["assets/a.txt"], b"asset");
+  }
+
+  #[test]
+  fn test_corrupt_lengths_v2() {
+    let mut bundle = Bundle::default();
+    let code = b"return 'hello hello hello hello hello hello hello'".repeat(8);
+    bundle.mods.insert("main".into(), lib(&code, None));
+    let mut file = Vec::new();
+    write_bundle(&mut file, &bundle, true).unwrap();
+    let (body, _) = read_bundle_body(&mut Cursor::new(file)).unwrap();
+
+    // [header_len u64][count u32][kind][compression][name_len u32][name]
+    // [conf_idx u32][offset u64][stored_len u64][raw_len u64]
+    let name_len = u32::from_le_bytes(body[14..18].try_into().unwrap()) as usize;
+    let raw_len = 18 + name_len + 4 + 8 + 8;
+
+    let mut huge_name = body.clone();
+    huge_name[14..18].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(parse_bundle_body(&huge_name).is_err());
+
+    let mut huge_raw = body.clone();
+    huge_raw[raw_len..raw_len + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(parse_bundle_body(&huge_raw).is_err());
+
+    let mut short_raw = body;
+    short_raw[raw_len..raw_len + 8].copy_from_slice(&1u64.to_le_bytes());
+    assert!(parse_bundle_body(&short_raw).is_err());
+  }
+
+  #[test]
+  fn test_read_v1() {
+    // [name_len][name][data_len][data][conf_idx] per module, then the confs
+    // and the `LUL!` footer.
+    let mut body = Vec::new();
+    for (name, data, conf_idx) in [("main", &b"code"[..], 0u32), ("util", b"more", u32::MAX)] {
+      body.extend_from_slice(&(name.len() as u32).to_le_bytes());
+      body.extend_from_slice(name.as_bytes());
+      body.extend_from_slice(&(data.len() as u64).to_le_bytes());
+      body.extend_from_slice(data);
+      body.extend_from_slice(&conf_idx.to_le_bytes());
+    }
+    body.extend_from_slice(&1u64.to_le_bytes());
+    body.extend_from_slice(&4u64.to_le_bytes());
+    body.extend_from_slice(b"conf");
+    let total_size = body.len() as u64;
+    body.extend_from_slice(&total_size.to_le_bytes());
+    body.extend_from_slice(&2u64.to_le_bytes());
+    body.extend_from_slice(MAGIC_V1);
+
+    let mut file = b"#!stub".to_vec();
+    file.extend_from_slice(&body);
+    let bundle = load_bundle_from_reader(&mut Cursor::new(file), &TrustPolicy::default()).unwrap();
+    assert_eq!(bundle.mods["main"].conf.as_deref(), Some(&b"conf"[..]));
+    assert_eq!(bundle.mods["util"].bytes, b"more");
+    assert!(bundle.assets.is_empty());
+  }
+}
@@ -0,0 +1,329 @@
+//! Reads the confs of bundles out of their LuaJIT bytecode without running
+//! it, for `lulu inspect` to look at artifacts that may not be trusted.
+//!
+//! Bundled confs are dumped from the single table constructor that
+//! [`conf_to_string`](crate::conf::conf_to_string) writes, so only the
+//! instructions of LuaJIT 2.1 that build tables out of constants are
+//! understood. Anything else is refused.
+
+use mlua::{Lua, Table, Value};
+
+const SIGNATURE: &[u8] = b"\x1bLJ";
+const VERSION: u8 = 2;
+
+const FLAG_BE: u32 = 0x01;
+const FLAG_STRIP: u32 = 0x02;
+
+const KGC_CHILD: u32 = 0;
+const KGC_TAB: u32 = 1;
+const KGC_STR: u32 = 5;
+
+const KTAB_NIL: u32 = 0;
+const KTAB_FALSE: u32 = 1;
+const KTAB_TRUE: u32 = 2;
+const KTAB_INT: u32 = 3;
+const KTAB_NUM: u32 = 4;
+const KTAB_STR: u32 = 5;
+
+const OP_MOV: u8 = 18;
+const OP_KSTR: u8 = 39;
+const OP_KSHORT: u8 = 41;
+const OP_KNUM: u8 = 42;
+const OP_KPRI: u8 = 43;
+const OP_KNIL: u8 = 44;
+const OP_TNEW: u8 = 52;
+const OP_TDUP: u8 = 53;
+const OP_TSETV: u8 = 60;
+const OP_TSETS: u8 = 61;
+const OP_TSETB: u8 = 62;
+const OP_RET: u8 = 74;
+const OP_RET1: u8 = 76;
+
+fn malformed() -> mlua::Error {
+  mlua::Error::RuntimeError("Malformed conf bytecode".into())
+}
+
+fn not_plain() -> mlua::Error {
+  mlua::Error::RuntimeError("Conf bytecode does more than build a table".into())
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    Reader { bytes, pos: 0 }
+  }
+
+  fn take(&mut self, len: usize) -> mlua::Result<&'a [u8]> {
+    let end = self.pos.checked_add(len).ok_or_else(malformed)?;
+    let bytes = self.bytes.get(self.pos..end).ok_or_else(malformed)?;
+    self.pos = end;
+    Ok(bytes)
+  }
+
+  fn byte(&mut self) -> mlua::Result<u8> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn uleb(&mut self) -> mlua::Result<u32> {
+    let mut value = 0u32;
+    let mut shift = 0;
+    loop {
+      let byte = self.byte()?;
+      if shift >= 32 {
+        return Err(malformed());
+      }
+      value |= ((byte & 0x7f) as u32) << shift;
+      shift += 7;
+      if byte < 0x80 {
+        return Ok(value);
+      }
+    }
+  }
+
+  /// A number constant, whose first byte also tells an integer from a double.
+  fn knum(&mut self) -> mlua::Result<Value> {
+    let first = self.byte()?;
+    let mut low = ((first >> 1) & 0x3f) as u32;
+    if first >= 0x80 {
+      let mut shift = 6;
+      loop {
+        let byte = self.byte()?;
+        if shift >= 32 {
+          return Err(malformed());
+        }
+        low |= ((byte & 0x7f) as u32) << shift;
+        shift += 7;
+        if byte < 0x80 {
+          break;
+        }
+      }
+    }
+    if first & 1 == 1 {
+      let high = self.uleb()?;
+      Ok(Value::Number(f64::from_bits(
+        ((high as u64) << 32) | low as u64,
+      )))
+    } else {
+      Ok(Value::Integer(low as i32 as i64))
+    }
+  }
+
+  fn ktab(&mut self, lua: &Lua) -> mlua::Result<Value> {
+    Ok(match self.uleb()? {
+      KTAB_NIL => Value::Nil,
+      KTAB_FALSE => Value::Boolean(false),
+      KTAB_TRUE => Value::Boolean(true),
+      KTAB_INT => Value::Integer(self.uleb()? as i32 as i64),
+      KTAB_NUM => {
+        let low = self.uleb()? as u64;
+        let high = self.uleb()? as u64;
+        Value::Number(f64::from_bits((high << 32) | low))
+      }
+      tp => {
+        let len = (tp - KTAB_STR) as usize;
+        Value::String(lua.create_string(self.take(len)?)?)
+      }
+    })
+  }
+
+  fn kgc(&mut self, lua: &Lua) -> mlua::Result<Value> {
+    match self.uleb()? {
+      KGC_CHILD => Err(not_plain()),
+      KGC_TAB => {
+        let table = lua.create_table()?;
+        let narray = self.uleb()?;
+        let nhash = self.uleb()?;
+        for i in 0..narray {
+          table.raw_set(i, self.ktab(lua)?)?;
+        }
+        for _ in 0..nhash {
+          let key = self.ktab(lua)?;
+          let value = self.ktab(lua)?;
+          table.raw_set(key, value)?;
+        }
+        Ok(Value::Table(table))
+      }
+      tp if tp >= KGC_STR => {
+        let len = (tp - KGC_STR) as usize;
+        Ok(Value::String(lua.create_string(self.take(len)?)?))
+      }
+      // 64 bit and complex cdata.
+      _ => Err(not_plain()),
+    }
+  }
+}
+
+fn copy_table(lua: &Lua, template: &Table) -> mlua::Result<Table> {
+  let table = lua.create_table()?;
+  for pair in template.pairs::<Value, Value>() {
+    let (key, value) = pair?;
+    table.raw_set(key, value)?;
+  }
+  Ok(table)
+}
+
+/// The table a dumped conf chunk returns, built in `lua` without running
+/// the chunk.
+pub fn read_conf_table(lua: &Lua, bytes: &[u8]) -> mlua::Result<Table> {
+  let mut reader = Reader::new(bytes);
+  if reader.take(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+    return Err(malformed());
+  }
+  if reader.byte()? != VERSION {
+    return Err(mlua::Error::RuntimeError(
+      "Conf bytecode is from an unsupported LuaJIT version".into(),
+    ));
+  }
+  let flags = reader.uleb()?;
+  let big_endian = flags & FLAG_BE != 0;
+  let stripped = flags & FLAG_STRIP != 0;
+  if !stripped {
+    let len = reader.uleb()? as usize;
+    reader.take(len)?;
+  }
+
+  let len = reader.uleb()? as usize;
+  let mut proto = Reader::new(reader.take(len)?);
+  // Functions come as more prototypes before the main one.
+  if len == 0 || reader.uleb()? != 0 {
+    return Err(not_plain());
+  }
+
+  proto.take(2)?;
+  let frame_size = proto.byte()? as usize;
+  let upvalues = proto.byte()? as usize;
+  let gc_count = proto.uleb()?;
+  let num_count = proto.uleb()?;
+  let code_len = proto.uleb()? as usize;
+  if !stripped && proto.uleb()? != 0 {
+    proto.uleb()?;
+    proto.uleb()?;
+  }
+  let code = proto.take(code_len.checked_mul(4).ok_or_else(malformed)?)?;
+  proto.take(upvalues * 2)?;
+
+  let mut gc = Vec::new();
+  for _ in 0..gc_count {
+    gc.push(proto.kgc(lua)?);
+  }
+  let mut nums = Vec::new();
+  for _ in 0..num_count {
+    nums.push(proto.knum()?);
+  }
+
+  // Operands count GC constants from the end of the dumped list.
+  let constant = |d: usize| -> mlua::Result<&Value> {
+    gc.len()
+      .checked_sub(d + 1)
+      .and_then(|i| gc.get(i))
+      .ok_or_else(malformed)
+  };
+  let mut slots = vec![Value::Nil; frame_size];
+  let slot = |slots: &[Value], i: usize| slots.get(i).cloned().ok_or_else(malformed);
+  let table = |slots: &[Value], i: usize| match slots.get(i) {
+    Some(Value::Table(table)) => Ok(table.clone()),
+    _ => Err(not_plain()),
+  };
+
+  for word in code.chunks_exact(4) {
+    let word: [u8; 4] = word.try_into().unwrap();
+    let ins = if big_endian {
+      u32::from_be_bytes(word)
+    } else {
+      u32::from_le_bytes(word)
+    };
+    let op = (ins & 0xff) as u8;
+    let a = ((ins >> 8) & 0xff) as usize;
+    let c = ((ins >> 16) & 0xff) as usize;
+    let b = (ins >> 24) as usize;
+    let d = (ins >> 16) as usize;
+
+    let value = match op {
+      OP_MOV => slot(&slots, d)?,
+      OP_KSTR => match constant(d)? {
+        Value::String(s) => Value::String(s.clone()),
+        _ => return Err(malformed()),
+      },
+      OP_KSHORT => Value::Integer(d as u16 as i16 as i64),
+      OP_KNUM => nums.get(d).cloned().ok_or_else(malformed)?,
+      OP_KPRI => match d {
+        0 => Value::Nil,
+        1 => Value::Boolean(false),
+        2 => Value::Boolean(true),
+        _ => return Err(malformed()),
+      },
+      OP_KNIL => {
+        for i in a..=d {
+          *slots.get_mut(i).ok_or_else(malformed)? = Value::Nil;
+        }
+        continue;
+      }
+      OP_TNEW => Value::Table(lua.create_table()?),
+      OP_TDUP => match constant(d)? {
+        Value::Table(template) => Value::Table(copy_table(lua, template)?),
+        _ => return Err(malformed()),
+      },
+      OP_TSETS | OP_TSETB | OP_TSETV => {
+        let key = match op {
+          OP_TSETS => constant(c)?.clone(),
+          OP_TSETB => Value::Integer(c as i64),
+          _ => slot(&slots, c)?,
+        };
+        table(&slots, b)?.raw_set(key, slot(&slots, a)?)?;
+        continue;
+      }
+      OP_RET | OP_RET1 => return table(&slots, a),
+      _ => return Err(not_plain()),
+    };
+    *slots.get_mut(a).ok_or_else(malformed)? = value;
+  }
+
+  Err(malformed())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::lua_to_bytecode;
+
+  #[test]
+  fn test_read_conf_table() {
+    let lua = Lua::new();
+    let dump = |code: &str| lua_to_bytecode(&lua, code).unwrap();
+
+    let conf = read_conf_table(
+      &lua,
+      &dump(
+        "return { manifest = { name = 'x', tags = { 'a', 'b' } }, strict = true, \
+         n = 70000, f = 1.5, neg = -3, list = { 1, 2, { deep = false } } }",
+      ),
+    )
+    .unwrap();
+    let manifest: Table = conf.get("manifest").unwrap();
+    assert_eq!(manifest.get::<String>("name").unwrap(), "x");
+    assert_eq!(
+      manifest.get::<Vec<String>>("tags").unwrap(),
+      vec!["a".to_string(), "b".to_string()]
+    );
+    assert!(conf.get::<bool>("strict").unwrap());
+    assert_eq!(conf.get::<i64>("n").unwrap(), 70000);
+    assert_eq!(conf.get::<f64>("f").unwrap(), 1.5);
+    assert_eq!(conf.get::<i64>("neg").unwrap(), -3);
+    let list: Table = conf.get("list").unwrap();
+    assert_eq!(list.len().unwrap(), 3);
+    assert!(!list.get::<Table>(3).unwrap().get::<bool>("deep").unwrap());
+
+    for code in [
+      "return { x = os.exit() }",
+      "return { f = function() end }",
+      "print('hi') return {}",
+    ] {
+      assert!(read_conf_table(&lua, &dump(code)).is_err(), "{}", code);
+    }
+    assert!(read_conf_table(&lua, b"\x1bLJ\x02").is_err());
+  }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const CACHE_META_FILE: &str = ".lulu-cache.json";
+
+/// What a cache directory holds, stored as `.lulu-cache.json` inside it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheMeta {
+  pub url: String,
+  #[serde(default)]
+  pub name: Option<String>,
+  #[serde(default)]
+  pub version: Option<String>,
+  /// Seconds since the unix epoch.
+  pub fetched_at: u64,
+  pub size: u64,
+  /// The `sha256` pin the fetched artifact was verified against.
+  #[serde(default)]
+  pub sha256: Option<String>,
+}
+
+impl CacheMeta {
+  pub fn load(cache_path: &Path) -> Option<Self> {
+    let content = fs::read_to_string(cache_path.join(CACHE_META_FILE)).ok()?;
+    serde_json::from_str(&content).ok()
+  }
+
+  pub fn save(&self, cache_path: &Path) -> Result<()> {
+    fs::write(
+      cache_path.join(CACHE_META_FILE),
+      serde_json::to_string_pretty(self)?,
+    )?;
+    Ok(())
+  }
+
+  /// Whether the entry can be used for a dependency pinned to `sha256`,
+  /// which it must have been verified against when it was fetched.
+  pub fn verified_for(&self, sha256: Option<&str>) -> bool {
+    match (sha256, &self.sha256) {
+      (None, _) => true,
+      (Some(pin), Some(verified)) => verified.eq_ignore_ascii_case(pin.trim()),
+      (Some(_), None) => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+  pub key: String,
+  pub path: PathBuf,
+  pub meta: CacheMeta,
+}
+
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+  let Ok(entries) = fs::read_dir(path) else {
+    return 0;
+  };
+
+  entries
+    .flatten()
+    .map(|entry| match entry.file_type() {
+      Ok(t) if t.is_dir() => dir_size(&entry.path()),
+      Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+      Err(_) => 0,
+    })
+    .sum()
+}
+
+/// Reads the metadata of a cache directory. Entries fetched before metadata
+/// was recorded fall back to the directory's modification time and size.
+pub fn read_entry(path: &Path) -> CacheEntry {
+  let key = path
+    .file_name()
+    .map(|n| n.to_string_lossy().to_string())
+    .unwrap_or_default();
+
+  let meta = CacheMeta::load(path).unwrap_or_else(|| CacheMeta {
+    url: String::new(),
+    name: None,
+    version: None,
+    fetched_at: fs::metadata(path)
+      .and_then(|m| m.modified())
+      .ok()
+      .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+      .map(|d| d.as_secs())
+      .unwrap_or(0),
+    size: dir_size(path),
+    sha256: None,
+  });
+
+  CacheEntry {
+    key,
+    path: path.to_path_buf(),
+    meta,
+  }
+}
+
+/// Parses durations such as `30d`, `12h`, `2w` or `90` (seconds).
+pub fn parse_duration(s: &str) -> Result<Duration> {
+  let s = s.trim();
+  let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  let (number, unit) = s.split_at(split);
+  let number: u64 = number
+    .parse()
+    .map_err(|_| anyhow!("Invalid duration \"{}\"", s))?;
+
+  let seconds = match unit.trim() {
+    "" | "s" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 60 * 60 * 24,
+    "w" => 60 * 60 * 24 * 7,
+    _ => return Err(anyhow!("Invalid duration unit in \"{}\", use s, m, h, d or w", s)),
+  };
+
+  Ok(Duration::from_secs(number * seconds))
+}
+
+/// Parses sizes such as `2G`, `500M`, `512KB` or `1024` (bytes), in powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+  let s = s.trim();
+  let split = s
+    .find(|c: char| !c.is_ascii_digit() && c != '.')
+    .unwrap_or(s.len());
+  let (number, unit) = s.split_at(split);
+  let number: f64 = number.parse().map_err(|_| anyhow!("Invalid size \"{}\"", s))?;
+
+  let multiplier = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+    "" => 1u64,
+    "K" => 1 << 10,
+    "M" => 1 << 20,
+    "G" => 1 << 30,
+    "T" => 1 << 40,
+    _ => return Err(anyhow!("Invalid size unit in \"{}\", use K, M, G or T", s)),
+  };
+
+  Ok((number * multiplier as f64) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < UNITS.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+
+  if unit == 0 {
+    format!("{} B", bytes)
+  } else {
+    format!("{:.1} {}", size, UNITS[unit])
+  }
+}
+
+pub fn format_age(fetched_at: u64) -> String {
+  let age = now().saturating_sub(fetched_at);
+  match age {
+    0..60 => "just now".to_string(),
+    60..3600 => format!("{}m ago", age / 60),
+    3600..86400 => format!("{}h ago", age / 3600),
+    _ => format!("{}d ago", age / 86400),
+  }
+}
+
+/// Picks the entries to prune: everything fetched before `older_than`, then
+/// the oldest remaining entries until the cache fits in `max_size`.
+pub fn select_prunable(
+  mut entries: Vec<CacheEntry>,
+  older_than: Option<Duration>,
+  max_size: Option<u64>,
+) -> Vec<CacheEntry> {
+  entries.sort_by_key(|e| e.meta.fetched_at);
+
+  let cutoff = older_than.map(|age| now().saturating_sub(age.as_secs()));
+  let (mut pruned, kept): (Vec<_>, Vec<_>) = entries
+    .into_iter()
+    .partition(|e| cutoff.is_some_and(|cutoff| e.meta.fetched_at < cutoff));
+
+  if let Some(max_size) = max_size {
+    let mut total: u64 = kept.iter().map(|e| e.meta.size).sum();
+    for entry in kept {
+      if total <= max_size {
+        break;
+      }
+      total -= entry.meta.size;
+      pruned.push(entry);
+    }
+  }
+
+  pruned
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(key: &str, age_days: u64, size: u64) -> CacheEntry {
+    CacheEntry {
+      key: key.to_string(),
+      path: PathBuf::from(key),
+      meta: CacheMeta {
+        fetched_at: now() - age_days * 86400,
+        size,
+        ..Default::default()
+      },
+    }
+  }
+
+  #[test]
+  fn test_parse_units() {
+    assert_eq!(parse_duration("30d").unwrap(), Duration::from_secs(30 * 86400));
+    assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+    assert_eq!(parse_size("512kb").unwrap(), 512 << 10);
+    assert!(parse_duration("3y").is_err());
+  }
+
+  #[test]
+  fn test_select_prunable() {
+    let entries = vec![entry("old", 40, 10), entry("mid", 10, 30), entry("new", 1, 30)];
+
+    let keys = |pruned: Vec<CacheEntry>| pruned.into_iter().map(|e| e.key).collect::<Vec<_>>();
+    assert_eq!(
+      keys(select_prunable(entries.clone(), Some(Duration::from_secs(30 * 86400)), None)),
+      vec!["old"]
+    );
+    assert_eq!(keys(select_prunable(entries, None, Some(40))), vec!["old", "mid"]);
+  }
+
+  #[test]
+  fn test_verified_for() {
+    let meta = CacheMeta {
+      sha256: Some("abc".to_string()),
+      ..Default::default()
+    };
+    assert!(meta.verified_for(None));
+    assert!(meta.verified_for(Some("ABC ")));
+    assert!(!meta.verified_for(Some("def")));
+    assert!(!CacheMeta::default().verified_for(Some("abc")));
+  }
+}
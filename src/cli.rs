@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use crate::permissions::Permissions;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -8,6 +9,17 @@ use std::path::PathBuf;
 pub struct Cli {
   #[command(subcommand)]
   pub command: Commands,
+
+  #[arg(
+    long,
+    global = true,
+    value_name = "PACKAGES",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_build_scripts: Option<Vec<String>>,
 }
 
 #[derive(Subcommand)]
@@ -19,6 +31,12 @@ pub enum Commands {
     #[arg(short = 'b', long)]
     build: bool,
 
+    #[arg(long, conflicts_with = "build")]
+    hot: bool,
+
+    #[command(flatten)]
+    permissions: PermissionArgs,
+
     #[arg(trailing_var_arg = true)]
     args: Vec<String>,
   },
@@ -38,6 +56,38 @@ pub enum Commands {
 
     #[arg(name = "OUTPUT_FILE")]
     output: PathBuf,
+
+    #[arg(short = 's', long, value_name = "KEY")]
+    sign: Option<PathBuf>,
+
+    #[arg(short = 't', long, value_name = "OS-ARCH")]
+    target: Option<String>,
+
+    #[arg(short = 'z', long)]
+    compress: bool,
+
+    #[arg(short = 'k', long, value_name = "PATTERN")]
+    keep: Vec<String>,
+
+    #[arg(long, conflicts_with = "debug")]
+    release: bool,
+
+    #[arg(long, requires = "release")]
+    minify: bool,
+
+    #[arg(long)]
+    debug: bool,
+  },
+  Keygen {
+    #[arg(name = "KEY_FILE")]
+    path: PathBuf,
+  },
+  Inspect {
+    #[arg(name = "FILE")]
+    file: PathBuf,
+
+    #[arg(short = 'x', long, value_name = "DIR")]
+    extract: Option<PathBuf>,
   },
   Compile {
     #[arg(name = "FILE")]
@@ -59,10 +109,26 @@ pub enum Commands {
   Build {
     #[arg(name = "PATH", default_value = ".")]
     path: PathBuf,
+
+    #[arg(short = 't', long, value_name = "OS-ARCH")]
+    target: Option<String>,
   },
   Resolve {
     #[arg(name = "URL", default_value = ".")]
     item: String,
+
+    #[arg(long)]
+    offline: bool,
+
+    #[arg(short = 'j', long)]
+    jobs: Option<usize>,
+  },
+  Vendor {
+    #[arg(name = "PATH", default_value = ".")]
+    path: PathBuf,
+
+    #[arg(long)]
+    offline: bool,
   },
   Update {
     #[arg(name = "PACKAGES")]
@@ -71,6 +137,52 @@ pub enum Commands {
     #[arg(short, long, default_value = ".")]
     project: PathBuf,
   },
+  Add {
+    #[arg(name = "PACKAGES", required = true)]
+    packages: Vec<String>,
+
+    #[arg(long)]
+    sha256: Option<String>,
+
+    #[arg(short, long, default_value = ".")]
+    project: PathBuf,
+  },
+  Remove {
+    #[arg(name = "PACKAGES", required = true)]
+    packages: Vec<String>,
+
+    #[arg(short, long, default_value = ".")]
+    project: PathBuf,
+  },
+  Verify {
+    #[arg(name = "PATH", default_value = ".")]
+    path: PathBuf,
+  },
+  Outdated {
+    #[arg(name = "PATH", default_value = ".")]
+    path: PathBuf,
+  },
+  Audit {
+    #[arg(name = "PATH", default_value = ".")]
+    path: PathBuf,
+
+    #[arg(long)]
+    db: Option<PathBuf>,
+  },
+  Publish {
+    #[arg(name = "PATH", default_value = ".")]
+    path: PathBuf,
+
+    #[arg(short = 'r', long)]
+    registry: Option<String>,
+
+    #[arg(long)]
+    token: Option<String>,
+  },
+  Registry {
+    #[command(subcommand)]
+    registry_command: RegistryCommand,
+  },
   Cache {
     #[command(subcommand)]
     cache_command: CacheCommand,
@@ -85,4 +197,126 @@ pub enum CacheCommand {
     #[arg(name = "PACKAGE_URL")]
     package_url: String,
   },
+  Prune {
+    #[arg(long)]
+    older_than: Option<String>,
+
+    #[arg(long)]
+    max_size: Option<String>,
+
+    #[arg(long)]
+    dry_run: bool,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum RegistryCommand {
+  Serve {
+    #[arg(name = "DIR")]
+    dir: PathBuf,
+
+    #[arg(short = 'a', long, default_value = "127.0.0.1:4873")]
+    addr: String,
+
+    #[arg(long)]
+    token: Option<String>,
+
+    #[arg(long)]
+    allow_anonymous_publish: bool,
+
+    #[arg(long, default_value = crate::registry::DEFAULT_MAX_UPLOAD_SIZE)]
+    max_upload_size: String,
+  },
+}
+
+#[derive(Args)]
+pub struct PermissionArgs {
+  #[arg(short = 'A', long)]
+  pub allow_all: bool,
+
+  #[arg(
+    long,
+    value_name = "PATHS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_read: Option<Vec<String>>,
+
+  #[arg(
+    long,
+    value_name = "PATHS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_write: Option<Vec<String>>,
+
+  #[arg(
+    long,
+    value_name = "HOSTS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_net: Option<Vec<String>>,
+
+  #[arg(
+    long,
+    value_name = "PROGRAMS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_run: Option<Vec<String>>,
+
+  #[arg(
+    long,
+    value_name = "VARS",
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "*",
+    value_delimiter = ','
+  )]
+  pub allow_env: Option<Vec<String>>,
+
+  #[arg(long)]
+  pub allow_ffi: bool,
+}
+
+impl PermissionArgs {
+  /// `None` when no flag was given, leaving the conf to decide.
+  pub fn permissions(&self) -> Option<Permissions> {
+    if self.allow_all {
+      return Some(Permissions::all());
+    }
+
+    let given = [
+      &self.allow_read,
+      &self.allow_write,
+      &self.allow_net,
+      &self.allow_run,
+      &self.allow_env,
+    ];
+    if !self.allow_ffi && given.iter().all(|grants| grants.is_none()) {
+      return None;
+    }
+
+    Some(Permissions {
+      read: self.allow_read.clone().unwrap_or_default(),
+      write: self.allow_write.clone().unwrap_or_default(),
+      net: self.allow_net.clone().unwrap_or_default(),
+      run: self.allow_run.clone().unwrap_or_default(),
+      env: self.allow_env.clone().unwrap_or_default(),
+      ffi: if self.allow_ffi {
+        vec![crate::permissions::ALLOW_ALL.to_string()]
+      } else {
+        Vec::new()
+      },
+    })
+  }
 }
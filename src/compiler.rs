@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 
-use crate::{conf::LuluConf, ops::std::STD_MODULES};
+use crate::{
+  conf::LuluConf,
+  ops::std::{STD_MODULES, STDModule},
+};
 
 #[derive(Debug, Clone)]
 pub struct MacroDefinition {
@@ -211,6 +214,14 @@ impl MacroRegistry {
         body: tokenize("local $name = require($expr)"),
       },
     );
+    macros.insert(
+      "export".to_string(),
+      MacroDefinition {
+        name: "export".to_string(),
+        params: vec!["names".to_string()],
+        body: Vec::new(),
+      },
+    );
     macros.insert(
       "test".to_string(),
       MacroDefinition {
@@ -538,6 +549,19 @@ fn get_token_string(tok: &Token) -> Option<String> {
   }
 }
 
+/// The identifiers listed in the arguments of `export!`, or in the braces
+/// of a destructuring `import!`.
+fn export_names(args: &[Vec<Token>]) -> Vec<String> {
+  args
+    .iter()
+    .flatten()
+    .filter_map(|t| match t {
+      Token::Identifier(name, _) => Some(name.clone()),
+      _ => None,
+    })
+    .collect()
+}
+
 fn get_token_string_all(tok: &Token) -> String {
   match tok {
     Token::String(s, _) => s.clone(),
@@ -739,6 +763,21 @@ impl Compiler {
     }
   }
 
+  /// The state the code of `eval!` runs in and `const!` values are kept in.
+  pub fn lua(&self) -> &mlua::Lua {
+    &self.lua
+  }
+
+  /// Adds the macros of a std module registered after this compiler was
+  /// created, such as one from a plugin.
+  pub fn add_module_macros(&mut self, module: &STDModule) {
+    for (name, params, body) in &module.macros {
+      self
+        .macros
+        .define_macro(name.clone(), params.clone(), tokenize(body));
+    }
+  }
+
   pub fn compile(&mut self, code: &str, path: Option<String>, conf: Option<LuluConf>) -> String {
     let tokens = tokenize(code);
     let processed_tokens = self.process_macros(tokens, path, conf);
@@ -1231,6 +1270,15 @@ impl Compiler {
       _ => panic!("Undefined macro: {}", macro_name),
     };
 
+    // `import! { a, b }, "./file.lua"` takes names out of the module.
+    let destructure = macro_name == "import"
+      && matches!(
+        tokens[start + 1..]
+          .iter()
+          .find(|t| !matches!(t, Token::Whitespace(_, _))),
+        Some(Token::LeftBrace(_))
+      );
+
     let mut i = start + 1;
     let mut args = Vec::new();
     let mut current_arg = Vec::new();
@@ -1348,6 +1396,14 @@ impl Compiler {
       self.compile_get(args)
     } else if macro_name == "all" {
       self.compile_multi_op(args)
+    } else if macro_name == "export" {
+      let names = export_names(&args);
+      let code = names
+        .iter()
+        .map(|name| format!("mod.exports.{} = {}", name, name))
+        .collect::<Vec<_>>()
+        .join("; ");
+      tokenize(&code)
     } else if macro_name == "import" {
       let mut cargs = args.clone();
       let cpath = get_token_string(&args[1][0]).unwrap();
@@ -1362,21 +1418,42 @@ impl Compiler {
       self
         .importmap
         .insert(name.clone(), (cpath.clone(), path.clone(), conf.clone()));
-      cargs[1] = vec![Token::String(format!("{}", name), 0)];
-      self.substitute_macro_params(
-        &macro_def.body,
-        &macro_def.params,
-        &cargs,
-        path.clone(),
-        conf.clone(),
-      )
+      if destructure {
+        let names = export_names(&args[..1]);
+        let keys = names
+          .iter()
+          .map(|n| format!("\"{}\"", n))
+          .collect::<Vec<_>>()
+          .join(", ");
+        tokenize(&format!(
+          "local {} = import_names(require(\"{}\"), \"{}\", {})",
+          names.join(", "),
+          name,
+          name,
+          keys
+        ))
+      } else {
+        cargs[1] = vec![Token::String(format!("{}", name), 0)];
+        self.substitute_macro_params(
+          &macro_def.body,
+          &macro_def.params,
+          &cargs,
+          path.clone(),
+          conf.clone(),
+        )
+      }
     } else if macro_name == "include_bytes" {
       let cpath = get_token_string(&args[0][0]).unwrap();
-      let name = format!("bytes://{}", crate::util::normalize_name(&cpath));
-
-      self
-        .importmap
-        .insert(name.clone(), (cpath.clone(), path.clone(), conf.clone()));
+      // Resources are already in the bundle, so they are looked up at runtime.
+      let name = if cpath.starts_with(crate::resources::RES_SCHEME) {
+        cpath.clone()
+      } else {
+        let name = format!("bytes://{}", crate::util::normalize_name(&cpath));
+        self
+          .importmap
+          .insert(name.clone(), (cpath.clone(), path.clone(), conf.clone()));
+        name
+      };
       self.substitute_macro_params(
         &macro_def.body,
         &macro_def.params,
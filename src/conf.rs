@@ -1,3 +1,4 @@
+use crate::permissions::Permissions;
 use mlua::{Lua, UserData};
 // use regex::Regex;
 use std::collections::HashMap;
@@ -8,7 +9,16 @@ pub struct LuluConf {
   pub manifest: Option<mlua::Table>,
   pub mods: Option<HashMap<String, String>>,
   pub include: Option<Vec<String>>,
+  pub dependencies: Option<Vec<Dependency>>,
   pub macros: Option<String>,
+  pub trusted_keys: Option<Vec<String>>,
+  pub require_signed: Option<bool>,
+  pub registry: Option<String>,
+  pub build_permissions: Option<HashMap<String, bool>>,
+  pub resources: Option<Vec<String>>,
+  pub permissions: Option<Permissions>,
+  pub plugins: Option<Vec<String>>,
+  pub strict: Option<bool>,
 }
 
 impl UserData for LuluConf {
@@ -23,10 +33,57 @@ pub enum FetchField {
   Code,
   Lulib {
     url: String,
-    include: Option<HashMap<String, Vec<String>>>,
+    sha256: Option<String>,
+    include: Option<HashMap<String, Vec<Dependency>>>,
   },
 }
 
+/// A URL optionally pinned to a sha256 digest, written either as a plain
+/// string or as `{ "url", sha256 = "..." }` in `dependencies` and `fetch.include`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+  pub url: String,
+  pub sha256: Option<String>,
+}
+
+impl From<&str> for Dependency {
+  fn from(url: &str) -> Self {
+    Dependency {
+      url: url.to_string(),
+      sha256: None,
+    }
+  }
+}
+
+impl From<String> for Dependency {
+  fn from(url: String) -> Self {
+    Dependency { url, sha256: None }
+  }
+}
+
+impl mlua::FromLua for Dependency {
+  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+    match value {
+      mlua::Value::String(s) => Ok(Dependency::from(s.to_str()?.to_string())),
+      mlua::Value::Table(t) => {
+        let url = match t.get::<Option<String>>(1)? {
+          Some(url) => url,
+          None => t.get::<String>("url")?,
+        };
+        let sha256 = t
+          .get::<Option<String>>("sha256")?
+          .map(|s| s.trim().to_lowercase());
+        Ok(Dependency { url, sha256 })
+      }
+      other => Err(mlua::Error::FromLuaConversionError {
+        from: other.type_name(),
+        to: "Dependency".to_string(),
+        message: Some("expected a url string or a { url, sha256 = ... } table".to_string()),
+      }),
+    }
+  }
+}
+
 fn table_to_lua_string(table: &mlua::Table) -> mlua::Result<String> {
   let mut parts = Vec::new();
   for pair in table.pairs::<mlua::Value, mlua::Value>() {
@@ -77,7 +134,48 @@ pub fn conf_to_string(conf: &LuluConf) -> mlua::Result<String> {
 ",
     );
   }
-  
+
+  if let Some(include) = &conf.include {
+    out.push_str("  include = { ");
+    for lib in include {
+      out.push_str(&format!(r#""{}","#, lib));
+    }
+    out.push_str(
+      " },
+",
+    );
+  }
+
+  if let Some(dependencies) = &conf.dependencies {
+    out.push_str("  dependencies = { ");
+    for dep in dependencies {
+      match &dep.sha256 {
+        Some(sha256) => out.push_str(&format!(r#"{{ "{}", sha256 = "{}" }},"#, dep.url, sha256)),
+        None => out.push_str(&format!(r#""{}","#, dep.url)),
+      }
+    }
+    out.push_str(
+      " },
+",
+    );
+  }
+
+  if let Some(permissions) = &conf.permissions {
+    out.push_str(&format!("  permissions = {},\n", permissions.to_lua_string()));
+  }
+
+  if let Some(plugins) = &conf.plugins {
+    out.push_str("  plugins = { ");
+    for plugin in plugins {
+      out.push_str(&format!(r#""{}","#, plugin));
+    }
+    out.push_str(" },\n");
+  }
+
+  if let Some(strict) = conf.strict {
+    out.push_str(&format!("  strict = {},\n", strict));
+  }
+
   if let Some(macros) = &conf.macros {
     out.push_str(format!("  macros = [[{}]]\n", macros).as_str());
   }
@@ -96,14 +194,17 @@ pub fn load_lulu_fetch_field(lua: &Lua, code: String) -> mlua::Result<Option<Fet
     match fetch {
       mlua::Value::Table(table) => {
         let lulib: Option<String> = table.get("lulib").ok();
-        let include: Option<HashMap<String, Vec<String>>> =
+        let sha256: Option<String> = table
+          .get::<Option<String>>("sha256")?
+          .map(|s| s.trim().to_lowercase());
+        let include: Option<HashMap<String, Vec<Dependency>>> =
           if let Ok(include_table) = table.get::<mlua::Table>("include") {
             let mut map = HashMap::new();
             for pair in include_table.pairs::<String, mlua::Value>() {
               let (key, val) = pair?;
               if let mlua::Value::Table(inner) = val {
                 let mut vec = Vec::new();
-                for v in inner.sequence_values::<String>() {
+                for v in inner.sequence_values::<Dependency>() {
                   vec.push(v?);
                 }
                 map.insert(key, vec);
@@ -116,6 +217,7 @@ pub fn load_lulu_fetch_field(lua: &Lua, code: String) -> mlua::Result<Option<Fet
 
         return Ok(Some(FetchField::Lulib {
           url: lulib.unwrap(),
+          sha256,
           include: include,
         }));
       }
@@ -129,11 +231,14 @@ pub fn load_lulu_fetch_field(lua: &Lua, code: String) -> mlua::Result<Option<Fet
   Ok(None)
 }
 
-pub fn load_lulu_conf_dependiencies(lua: &Lua, code: String) -> mlua::Result<Option<Vec<String>>> {
+pub fn load_lulu_conf_dependiencies(
+  lua: &Lua,
+  code: String,
+) -> mlua::Result<Option<Vec<Dependency>>> {
   lua.load(&code).set_name("lulu.conf.lua").exec()?;
 
   let globals = lua.globals();
-  let dependencies: Option<Vec<String>> = globals.get("dependencies")?;
+  let dependencies: Option<Vec<Dependency>> = globals.get("dependencies")?;
 
   Ok(dependencies)
 }
@@ -163,6 +268,16 @@ pub fn load_lulu_conf_code(lua: &Lua, code: CodeType) -> mlua::Result<LuluConf>
       lua.globals()
     }
   };
+  conf_from_table(globals)
+}
+
+/// Reads a bundled conf without running its bytecode, for artifacts that
+/// may not be trusted.
+pub fn read_lulu_conf_from_bytecode(lua: &Lua, bytecode: &[u8]) -> mlua::Result<LuluConf> {
+  conf_from_table(crate::bytecode::read_conf_table(lua, bytecode)?)
+}
+
+fn conf_from_table(globals: mlua::Table) -> mlua::Result<LuluConf> {
   let manifest: Option<mlua::Table> = globals.get("manifest").ok();
   let mods = globals
     .get::<HashMap<String, String>>("mods")
@@ -172,21 +287,71 @@ pub fn load_lulu_conf_code(lua: &Lua, code: CodeType) -> mlua::Result<LuluConf>
     .get::<Vec<String>>("include")
     .map(Some)
     .unwrap_or(None);
+  let dependencies = globals
+    .get::<Vec<Dependency>>("dependencies")
+    .map(Some)
+    .unwrap_or(None);
   let macros = globals
     .get::<String>("macros")
     .map(Some)
     .unwrap_or(None);
+  let trusted_keys = globals
+    .get::<Vec<String>>("trusted_keys")
+    .map(Some)
+    .unwrap_or(None);
+  let require_signed = globals
+    .get::<bool>("require_signed")
+    .map(Some)
+    .unwrap_or(None);
+  let registry = globals
+    .get::<String>("registry")
+    .map(Some)
+    .unwrap_or(None);
+  let build_permissions = globals
+    .get::<HashMap<String, bool>>("build_permissions")
+    .map(Some)
+    .unwrap_or(None);
+  let resources = globals
+    .get::<Vec<String>>("resources")
+    .map(Some)
+    .unwrap_or(None);
+  let permissions = globals.get::<Option<Permissions>>("permissions")?;
+  let plugins = globals
+    .get::<Vec<String>>("plugins")
+    .map(Some)
+    .unwrap_or(None);
+  let strict = globals
+    .get::<bool>("strict")
+    .map(Some)
+    .unwrap_or(None);
   
   globals.set("manifest", mlua::Value::Nil)?;
   globals.set("mods", mlua::Value::Nil)?;
   globals.set("macros", mlua::Value::Nil)?;
   globals.set("include", mlua::Value::Nil)?;
+  globals.set("trusted_keys", mlua::Value::Nil)?;
+  globals.set("require_signed", mlua::Value::Nil)?;
+  globals.set("registry", mlua::Value::Nil)?;
+  globals.set("build_permissions", mlua::Value::Nil)?;
+  globals.set("resources", mlua::Value::Nil)?;
+  globals.set("permissions", mlua::Value::Nil)?;
+  globals.set("plugins", mlua::Value::Nil)?;
+  globals.set("strict", mlua::Value::Nil)?;
   
   Ok(LuluConf {
     manifest,
     mods,
     include,
-    macros
+    dependencies,
+    macros,
+    trusted_keys,
+    require_signed,
+    registry,
+    build_permissions,
+    resources,
+    permissions,
+    plugins,
+    strict,
   })
 }
 
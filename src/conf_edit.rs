@@ -0,0 +1,399 @@
+//! Text-level edits of `lulu.conf.lua` used by `lulu add` and `lulu remove`.
+//! Only the touched entries change, so the rest of the file keeps its
+//! formatting and comments.
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+  Str(String),
+  Open,
+  Close,
+  Sep,
+  Comment,
+  Other,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+  kind: TokenKind,
+  start: usize,
+  end: usize,
+}
+
+/// Returns the number of `=` in a long bracket opening at `i`, e.g. `[==[`.
+fn long_bracket_level(bytes: &[u8], i: usize) -> Option<usize> {
+  if bytes.get(i) != Some(&b'[') {
+    return None;
+  }
+  let mut j = i + 1;
+  while bytes.get(j) == Some(&b'=') {
+    j += 1;
+  }
+  (bytes.get(j) == Some(&b'[')).then_some(j - i - 1)
+}
+
+fn long_bracket_end(src: &str, from: usize, level: usize) -> usize {
+  let close = format!("]{}]", "=".repeat(level));
+  src[from..]
+    .find(&close)
+    .map(|i| from + i + close.len())
+    .unwrap_or(src.len())
+}
+
+fn tokenize(src: &str) -> Vec<Token> {
+  let bytes = src.as_bytes();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+
+  while i < bytes.len() {
+    let start = i;
+    let c = bytes[i];
+
+    let kind = if c.is_ascii_whitespace() {
+      i += 1;
+      continue;
+    } else if src[i..].starts_with("--") {
+      i = match long_bracket_level(bytes, i + 2) {
+        Some(level) => long_bracket_end(src, i + 2, level),
+        None => src[i..].find('\n').map(|n| i + n).unwrap_or(src.len()),
+      };
+      TokenKind::Comment
+    } else if c == b'"' || c == b'\'' {
+      let mut value = String::new();
+      i += 1;
+      while i < bytes.len() && bytes[i] != c {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+          i += 1;
+        }
+        let ch = src[i..].chars().next().unwrap();
+        value.push(ch);
+        i += ch.len_utf8();
+      }
+      i += 1;
+      TokenKind::Str(value)
+    } else if let Some(level) = long_bracket_level(bytes, i) {
+      let content_start = i + level + 2;
+      i = long_bracket_end(src, content_start, level);
+      TokenKind::Str(src[content_start..i.saturating_sub(level + 2).max(content_start)].to_string())
+    } else if c == b'{' {
+      i += 1;
+      TokenKind::Open
+    } else if c == b'}' {
+      i += 1;
+      TokenKind::Close
+    } else if c == b',' || c == b';' {
+      i += 1;
+      TokenKind::Sep
+    } else {
+      while i < bytes.len()
+        && !bytes[i].is_ascii_whitespace()
+        && !matches!(bytes[i], b'"' | b'\'' | b'{' | b'}' | b',' | b';' | b'[' | b'-')
+      {
+        i += 1;
+      }
+      if i == start {
+        i += 1;
+      }
+      TokenKind::Other
+    };
+
+    tokens.push(Token {
+      kind,
+      start,
+      end: i.min(src.len()),
+    });
+  }
+
+  tokens
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  start: usize,
+  end: usize,
+  sep: Option<(usize, usize)>,
+  value: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Table {
+  open: usize,
+  close: usize,
+  entries: Vec<Entry>,
+}
+
+fn line_start(src: &str, pos: usize) -> usize {
+  src[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+fn line_end(src: &str, pos: usize) -> usize {
+  src[pos..].find('\n').map(|i| pos + i).unwrap_or(src.len())
+}
+
+fn indent_of(src: &str, pos: usize) -> &str {
+  let start = line_start(src, pos);
+  let line = &src[start..];
+  &line[..line.len() - line.trim_start().len()]
+}
+
+/// Finds `key = { ... }` written at the start of a line outside of any table.
+fn find_table(src: &str, key: &str) -> Option<Table> {
+  let tokens = tokenize(src);
+  let mut depth = 0usize;
+
+  for (idx, token) in tokens.iter().enumerate() {
+    match token.kind {
+      TokenKind::Open => depth += 1,
+      TokenKind::Close => depth = depth.saturating_sub(1),
+      _ => {}
+    }
+
+    let text = &src[token.start..token.end];
+    let is_key = depth == 0
+      && token.kind == TokenKind::Other
+      && (text == key || text == format!("{}=", key))
+      && src[line_start(src, token.start)..token.start].trim().is_empty();
+    if !is_key {
+      continue;
+    }
+
+    let mut rest = tokens[idx + 1..]
+      .iter()
+      .filter(|t| t.kind != TokenKind::Comment);
+    let open = if text.ends_with('=') {
+      rest.next()?
+    } else {
+      let eq = rest.next()?;
+      if &src[eq.start..eq.end] != "=" {
+        continue;
+      }
+      rest.next()?
+    };
+    if open.kind != TokenKind::Open {
+      continue;
+    }
+
+    return parse_table(&tokens, open.start);
+  }
+
+  None
+}
+
+fn parse_table(tokens: &[Token], open: usize) -> Option<Table> {
+  let first = tokens.iter().position(|t| t.start == open)? + 1;
+  let mut depth = 0usize;
+  let mut entries = Vec::new();
+  let mut current: Option<Entry> = None;
+
+  for token in &tokens[first..] {
+    match &token.kind {
+      TokenKind::Close if depth == 0 => {
+        if let Some(entry) = current.take() {
+          entries.push(entry);
+        }
+        return Some(Table {
+          open,
+          close: token.start,
+          entries,
+        });
+      }
+      TokenKind::Sep if depth == 0 => {
+        if let Some(mut entry) = current.take() {
+          entry.sep = Some((token.start, token.end));
+          entries.push(entry);
+        }
+      }
+      TokenKind::Comment => {}
+      kind => {
+        match kind {
+          TokenKind::Open => depth += 1,
+          TokenKind::Close => depth -= 1,
+          _ => {}
+        }
+
+        let entry = current.get_or_insert(Entry {
+          start: token.start,
+          end: token.end,
+          sep: None,
+          value: None,
+        });
+        entry.end = token.end;
+        if let (None, TokenKind::Str(value)) = (&entry.value, kind) {
+          entry.value = Some(value.clone());
+        }
+      }
+    }
+  }
+
+  None
+}
+
+/// The first string of every entry in `key = { ... }`, e.g. each dependency URL.
+pub fn table_values(src: &str, key: &str) -> Vec<String> {
+  find_table(src, key)
+    .map(|table| table.entries.into_iter().filter_map(|e| e.value).collect())
+    .unwrap_or_default()
+}
+
+/// Appends `entry` (Lua source, e.g. `"github:user/repo"`) to `key = { ... }`,
+/// following the indentation and trailing-comma style of the existing entries.
+/// The table is created at the end of the file when it does not exist.
+pub fn add_entry(src: &str, key: &str, entry: &str) -> String {
+  let Some(table) = find_table(src, key) else {
+    let separator = if src.is_empty() || src.ends_with("\n\n") {
+      ""
+    } else if src.ends_with('\n') {
+      "\n"
+    } else {
+      "\n\n"
+    };
+    return format!("{}{}{} = {{\n  {}\n}}\n", src, separator, key, entry);
+  };
+
+  let mut out = src.to_string();
+
+  let Some(last) = table.entries.last() else {
+    let indent = indent_of(src, table.open);
+    out.replace_range(
+      table.open + 1..table.close,
+      &format!("\n{}  {}\n{}", indent, entry, indent),
+    );
+    return out;
+  };
+
+  let trailing_sep = last.sep.is_some();
+  let last_end = last.sep.map(|(_, end)| end).unwrap_or(last.end);
+  let multiline = src[last_end..table.close].contains('\n');
+
+  if multiline {
+    let insert_at = line_end(src, last_end) + 1;
+    let indent = indent_of(src, last.start).to_string();
+    let line = format!(
+      "{}{}{}\n",
+      indent,
+      entry,
+      if trailing_sep { "," } else { "" }
+    );
+    out.insert_str(insert_at, &line);
+    if !trailing_sep {
+      out.insert(last.end, ',');
+    }
+  } else if trailing_sep {
+    out.insert_str(last_end, &format!(" {},", entry));
+  } else {
+    out.insert_str(last.end, &format!(", {}", entry));
+  }
+
+  out
+}
+
+/// Removes every entry of `key = { ... }` whose first string matches
+/// `predicate`, together with its separator and, when nothing else is left
+/// on it, its line. Returns the new source and how many entries were removed.
+pub fn remove_entries(src: &str, key: &str, predicate: impl Fn(&str) -> bool) -> (String, usize) {
+  let Some(table) = find_table(src, key) else {
+    return (src.to_string(), 0);
+  };
+
+  let mut ranges = Vec::new();
+  for (idx, entry) in table.entries.iter().enumerate() {
+    if !entry.value.as_deref().is_some_and(&predicate) {
+      continue;
+    }
+
+    let (mut start, mut end) = match entry.sep {
+      Some((_, sep_end)) => (entry.start, sep_end),
+      None => match idx.checked_sub(1).and_then(|i| table.entries[i].sep) {
+        Some((sep_start, _)) => (sep_start, entry.end),
+        None => (entry.start, entry.end),
+      },
+    };
+
+    let line_begin = line_start(src, entry.start);
+    let line_finish = line_end(src, end);
+    let rest = src[end..line_finish].trim();
+    if src[line_begin..entry.start].trim().is_empty() && (rest.is_empty() || rest.starts_with("--"))
+    {
+      if start < line_begin {
+        end = line_finish;
+      } else {
+        start = line_begin;
+        end = (line_finish + 1).min(src.len());
+      }
+    } else if src[end..].starts_with(' ') && entry.sep.is_some() {
+      end += 1;
+    }
+
+    ranges.push((start, end));
+  }
+
+  let mut out = src.to_string();
+  for (start, end) in ranges.iter().rev() {
+    out.replace_range(*start..*end, "");
+  }
+
+  (out, ranges.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CONF: &str = r#"manifest = {
+  name = "app", -- the name { with braces }
+  version = "0.1.0"
+}
+
+-- our dependencies
+dependencies = {
+  "github:a/one", -- first
+  { "https://example.com/two.lulib", sha256 = "abc" },
+  "github:a/three"
+}
+
+build = function()
+  local dependencies = { "ignored" }
+end
+"#;
+
+  #[test]
+  fn test_table_values() {
+    assert_eq!(
+      table_values(CONF, "dependencies"),
+      vec![
+        "github:a/one",
+        "https://example.com/two.lulib",
+        "github:a/three"
+      ]
+    );
+    assert!(table_values(CONF, "include").is_empty());
+  }
+
+  #[test]
+  fn test_add_entry() {
+    let added = add_entry(CONF, "dependencies", "\"github:a/four\"");
+    assert!(added.contains("  \"github:a/three\",\n  \"github:a/four\"\n}"));
+    assert!(added.contains("-- our dependencies"));
+
+    let inline = add_entry("include = { \"@one\" }\n", "include", "\"@two\"");
+    assert_eq!(inline, "include = { \"@one\", \"@two\" }\n");
+
+    let empty = add_entry("include = {}\n", "include", "\"@one\"");
+    assert_eq!(empty, "include = {\n  \"@one\"\n}\n");
+
+    let created = add_entry("manifest = {}\n", "include", "\"@one\"");
+    assert_eq!(created, "manifest = {}\n\ninclude = {\n  \"@one\"\n}\n");
+  }
+
+  #[test]
+  fn test_remove_entries() {
+    let (removed, count) = remove_entries(CONF, "dependencies", |v| v.ends_with("two.lulib"));
+    assert_eq!(count, 1);
+    assert!(removed.contains("  \"github:a/one\", -- first\n  \"github:a/three\"\n}"));
+
+    let (removed, _) = remove_entries(CONF, "dependencies", |v| v == "github:a/three");
+    assert!(removed.contains("sha256 = \"abc\" }\n}"));
+
+    let (removed, _) = remove_entries("include = { \"@a\", \"@b\" }", "include", |v| v == "@a");
+    assert_eq!(removed, "include = { \"@b\" }");
+  }
+}
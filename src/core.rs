@@ -1,8 +1,12 @@
+use crate::build_sandbox::{BUILD_SANDBOX_ENV, BuildSandbox};
 use crate::compiler::Compiler;
 use crate::conf::{LuluConf, find_lulu_conf, load_lulu_conf};
 use crate::ops::core::{register_consts, register_ops};
+use crate::permissions::Permissions;
 use mlua::{Lua, chunk};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 pub const STD_FILE: &str = include_str!("./builtins/std.lua");
 
@@ -23,8 +27,13 @@ pub struct LuluMod {
   pub name: String,
   pub source: LuluModSource,
   pub conf: Option<LuluConf>,
+  /// The file the module was compiled from.
+  pub path: Option<PathBuf>,
 }
 
+/// Set once `std.lua` ran in a VM.
+struct StdLoaded;
+
 #[derive(Debug, Clone)]
 pub struct Lulu {
   pub mods: Vec<LuluMod>,
@@ -32,7 +41,25 @@ pub struct Lulu {
   pub args: Vec<String>,
   pub current: Option<PathBuf>,
   pub compiler: Compiler,
-  std: String,
+  /// Files embedded in the bundle next to the modules, keyed by path.
+  pub assets: HashMap<String, Vec<u8>>,
+  /// Line maps of modules bundled with `--debug`, keyed by module name.
+  pub sourcemaps: HashMap<String, Vec<u32>>,
+  /// Set by the `--allow-*` flags of `lulu run`. Left `None`, the
+  /// `permissions` of the entry conf apply, or nothing is restricted.
+  pub permissions: Option<Permissions>,
+  /// The compiled `std.lua`, also loaded into sandboxes that compile code.
+  pub std: String,
+  /// The std modules `using { lulib.* }` can load, all of them when `None`.
+  pub std_modules: Option<Vec<String>>,
+}
+
+/// What `export!` declared is the module's value unless it returns one.
+fn module_value(value: mlua::Value, exports: mlua::Table) -> mlua::Value {
+  match value {
+    mlua::Value::Nil if !exports.is_empty() => mlua::Value::Table(exports),
+    value => value,
+  }
 }
 
 impl Lulu {
@@ -51,7 +78,11 @@ impl Lulu {
       args: args.unwrap_or_default(),
       current,
       compiler,
+      assets: HashMap::new(),
+      sourcemaps: HashMap::new(),
+      permissions: None,
       std,
+      std_modules: None,
     }
   }
 
@@ -122,10 +153,42 @@ impl Lulu {
       )
       .exec()?;
 
-    self.lua.load(self.std.clone()).set_name("std").exec()?;
+    // Modules can be added and preloaded again later on, but `std.lua`
+    // holds state such as the scheduler and only runs once per VM.
+    if self.lua.app_data_ref::<StdLoaded>().is_none() {
+      self.lua.load(self.std.clone()).set_name("std").exec()?;
+      register_consts(&self.lua)?;
+      self.lua.set_app_data(StdLoaded);
+    }
 
-    register_consts(&self.lua)?;
+    Ok(())
+  }
 
+  /// Loads the `plugins` of `conf`, which need the ffi permission and are
+  /// refused to sandboxed builds, and makes the macros of their modules
+  /// available to the compiler.
+  pub fn load_plugins(&mut self, conf: &LuluConf) -> mlua::Result<()> {
+    for plugin in conf.plugins.iter().flatten() {
+      if std::env::var_os(BUILD_SANDBOX_ENV).is_some() {
+        let what = format!("Loading the plugin \"{}\"", plugin);
+        return Err(match self.lua.app_data_ref::<Arc<BuildSandbox>>() {
+          Some(sandbox) => sandbox.deny(&what),
+          None => crate::build_sandbox::denied(&what),
+        });
+      }
+      self
+        .permissions
+        .clone()
+        .unwrap_or_else(Permissions::all)
+        .check_ffi(&format!("plugin \"{}\"", plugin))?;
+
+      let path = lookup_dylib(self.current.as_ref(), plugin)?;
+      for name in crate::plugins::load_plugin(&path)? {
+        if let Some(module) = crate::ops::std::get_std_module(&name) {
+          self.compiler.add_module_macros(&module);
+        }
+      }
+    }
     Ok(())
   }
 
@@ -138,6 +201,7 @@ impl Lulu {
       name,
       source: LuluModSource::Code(self.compiler.clone().compile(code.as_str(), None, None)),
       conf,
+      path: None,
     });
   }
 
@@ -146,6 +210,7 @@ impl Lulu {
       name,
       source: LuluModSource::Bytecode(bytecode),
       conf,
+      path: None,
     });
   }
 
@@ -160,7 +225,7 @@ impl Lulu {
     let source = match std::str::from_utf8(&raw) {
       Ok(code) => LuluModSource::Code(self.compiler.compile(
         code,
-        Some(std::fs::canonicalize(path)?.to_string_lossy().to_string()),
+        Some(std::fs::canonicalize(&path)?.to_string_lossy().to_string()),
         conf.clone(),
       )),
       Err(_) => LuluModSource::Bytecode(raw),
@@ -177,11 +242,32 @@ impl Lulu {
       name: modname,
       source,
       conf,
+      path: Some(path),
     });
     Ok(())
   }
 
   pub fn exec_mod(&self, name: &str) -> mlua::Result<mlua::Value> {
+    self.exec_mod_with_env(name).map(|(value, _)| value)
+  }
+
+  /// Runs `name` like [`Lulu::exec_mod`], also returning its `mod` table.
+  fn exec_mod_with_env(&self, name: &str) -> mlua::Result<(mlua::Value, mlua::Table)> {
+    let (chunk, exports, lmod_table) = self.load_mod(name)?;
+    Ok((module_value(chunk.eval()?, exports), lmod_table))
+  }
+
+  /// Runs `name` like [`Lulu::exec_mod`] as an async thread, so its top
+  /// level can wait on async functions, as worker modules do.
+  pub async fn exec_mod_async(&self, name: &str) -> mlua::Result<mlua::Value> {
+    let (chunk, exports, _) = self.load_mod(name)?;
+    let value = chunk.into_function()?.call_async(()).await?;
+    Ok(module_value(value, exports))
+  }
+
+  /// The chunk of `name` in its own env, with its `export!` table and its
+  /// `mod` table.
+  fn load_mod(&self, name: &str) -> mlua::Result<(mlua::Chunk<'_>, mlua::Table, mlua::Table)> {
     let lmod = self
       .mods
       .iter()
@@ -206,7 +292,51 @@ impl Lulu {
       env
     };
 
+    let strict = lmod.conf.as_ref().and_then(|c| c.strict).unwrap_or(false);
+    // Writes in a strict module stay in its env, and reading a name that
+    // nothing defined is an error instead of a silent nil.
+    if strict {
+      self
+        .lua
+        .load(chunk! {
+          local env, name, globals = ...
+          setmetatable(env, {
+            __index = function(_, key)
+              local value = globals[key]
+              if value == nil then
+                error("undefined global '" .. tostring(key) .. "' in module '" .. name .. "'", 2)
+              end
+              return value
+            end
+          })
+          local proxy = setmetatable({}, {
+            __index = globals,
+            __newindex = function(_, key)
+              error("module '" .. name .. "' can't assign the global '" .. tostring(key) .. "' in strict mode", 2)
+            end
+          })
+          env._G = proxy
+          // `getfenv(0)` and the env of std functions are the real globals.
+          env.getfenv = function(f)
+            local found
+            if f == nil then
+              found = getfenv(2)
+            elseif type(f) == "number" and f > 0 then
+              found = getfenv(f + 1)
+            else
+              found = getfenv(f)
+            end
+            if found == globals then
+              return proxy
+            end
+            return found
+          end
+        })
+        .call::<()>((env.clone(), name, self.lua.globals()))?;
+    }
+
     let lmod_table = self.lua.create_table()?;
+    let exports = self.lua.create_table()?;
 
     if let Some(conf) = lmod.conf.clone() {
       let p = self.lua.create_userdata::<LuluConf>(conf)?;
@@ -214,8 +344,10 @@ impl Lulu {
       lmod_table.set("conf", p)?;
     }
     lmod_table.set("name", name)?;
+    lmod_table.set("strict", strict)?;
+    lmod_table.set("exports", exports.clone())?;
 
-    env.set("mod", lmod_table)?;
+    env.set("mod", lmod_table.clone())?;
 
     let req_chunk = self.lua.load(chunk! {
       local name = ({...})[1]
@@ -238,36 +370,9 @@ impl Lulu {
       env.set("current_path", mlua::Value::Nil)?;
     }
     let current = self.current.clone();
-    let lookup_dylib = self.lua.create_function(move |_, name: String| {
-      let path = std::fs::canonicalize(current.clone().unwrap_or(PathBuf::from(".")))?;
-
-      let name = if name.starts_with("@") {
-        let prefix = if std::env::consts::OS == "windows" {
-          ""
-        } else {
-          "lib"
-        };
-        let ext = match std::env::consts::OS {
-          "windows" => ".dll",
-          "macos" => ".dylib",
-          _ => ".so",
-        };
-
-        format!("{}{}{}", prefix, &name[1..], ext)
-      } else {
-        name
-      };
-      let lib_folder = path.join(".lib/dylib").join(name.clone());
-      let dylib_here = path.join("dylib").join(name.clone());
-
-      if lib_folder.exists() {
-        Ok(lib_folder)
-      } else if dylib_here.exists() {
-        Ok(dylib_here)
-      } else {
-        Ok(name.into())
-      }
-    })?;
+    let lookup_dylib = self
+      .lua
+      .create_function(move |_, name: String| Ok(lookup_dylib(current.as_ref(), &name)?))?;
 
     env.set("lookup_dylib", lookup_dylib)?;
 
@@ -300,9 +405,62 @@ impl Lulu {
 
     env.set("using", using)?;
 
-    let chunk = chunk.set_environment(env);
+    Ok((chunk.set_environment(env), exports, lmod_table))
+  }
+
+  /// Compiles the module `name` again from its file, for `lulu run --hot`.
+  /// When it was already required, it runs again and replaces its entry in
+  /// `package.loaded`, after its `mod.on_reload` was given the old value.
+  pub fn reload_mod(&mut self, name: &str) -> mlua::Result<()> {
+    let idx = self
+      .mods
+      .iter()
+      .position(|m| m.name == name)
+      .ok_or_else(|| mlua::Error::RuntimeError(format!("Module {} not found", name)))?;
+    let path = self.mods[idx]
+      .path
+      .clone()
+      .ok_or_else(|| mlua::Error::RuntimeError(format!("Module {} has no source file", name)))?;
+
+    let code = std::fs::read_to_string(&path)?;
+    let source = self.compiler.compile(
+      &code,
+      Some(std::fs::canonicalize(&path)?.to_string_lossy().to_string()),
+      self.mods[idx].conf.clone(),
+    );
+    self.compiler.last_mod = None;
+    self.mods[idx].source = LuluModSource::Code(source);
+
+    // `exec_mod` in the preloads sees the modules as they were when the ops
+    // were registered, so the reloaded module gets a preload of its own.
+    let package: mlua::Table = self.lua.globals().get("package")?;
+    let lulu = self.clone();
+    let modname = name.to_string();
+    package.get::<mlua::Table>("preload")?.set(
+      name,
+      self
+        .lua
+        .create_function(move |_, ()| lulu.exec_mod(&modname))?,
+    )?;
+
+    let loaded: mlua::Table = package.get("loaded")?;
+    let old: mlua::Value = loaded.get(name)?;
+    if old.is_nil() {
+      return Ok(());
+    }
 
-    chunk.eval()
+    let (value, lmod_table) = self.exec_mod_with_env(name)?;
+    if let Some(on_reload) = lmod_table.get::<Option<mlua::Function>>("on_reload")? {
+      on_reload.call::<()>(old)?;
+    }
+    loaded.set(
+      name,
+      if value.is_nil() {
+        mlua::Value::Boolean(true)
+      } else {
+        value
+      },
+    )
   }
 
   pub fn entry_mod_path(&mut self, path: PathBuf) -> mlua::Result<String> {
@@ -322,6 +480,13 @@ impl Lulu {
         "".to_string()
       };
 
+      if self.permissions.is_none() {
+        self.permissions = c.permissions.clone();
+      }
+
+      // Before the modules compile, as plugins can bring macros.
+      self.load_plugins(&c)?;
+
       if let Some(mods) = c.mods.clone() {
         for (name, modpath) in mods {
           let mod_path = root_path.parent().unwrap().join(modpath);
@@ -341,6 +506,13 @@ impl Lulu {
         self.compiler.compile(&macros, None, None);
       }
 
+      if let Some(patterns) = &c.resources {
+        let root = root_path.parent().unwrap();
+        for (name, bytes) in crate::resources::collect(root, patterns)? {
+          self.assets.insert(name, bytes);
+        }
+      }
+
       if let Some(include) = c.include.clone() {
         for libpath in include {
           let lib_path = root_path
@@ -351,8 +523,11 @@ impl Lulu {
             } else {
               libpath
             });
-          let mods = crate::bundle::load_lulib(&lib_path)?;
-          crate::bundle::reg_bundle_nods(self, mods)?;
+          let bundle = crate::bundle::load_lulib_trusted(
+            &lib_path,
+            &crate::signing::TrustPolicy::from_conf(&c),
+          )?;
+          crate::bundle::reg_bundle_nods(self, bundle)?;
         }
       }
       Some(c)
@@ -378,34 +553,35 @@ impl Lulu {
     Ok(lmod.name.clone())
   }
 
+  /// Runs `name` and then drives the scheduler, see [`Lulu::drive_scheduler`].
   pub async fn exec_final(&mut self, name: &str) -> mlua::Result<mlua::Value> {
     let result = self.exec_mod(name);
+    self.drive_scheduler().await?;
+    result
+  }
 
-    let scheduler: mlua::Function = self
-      .lua
-      .globals()
-      .get::<mlua::Table>("coroutine")?
-      .get("resume")?;
-    let sched_co: mlua::Value = self
-      .lua
-      .globals()
-      .get::<mlua::Table>("Future")?
-      .get("scheduler")?;
+  /// Drives `Future.scheduler` as an async thread until every Lua future is
+  /// done. The scheduler yields `__poll_pending` when all futures wait on
+  /// async Rust functions, which parks this task until one of their wakers
+  /// fires instead of polling in a loop.
+  pub async fn drive_scheduler(&self) -> mlua::Result<()> {
+    let future: mlua::Table = self.lua.globals().get("Future")?;
+    let mut scheduler: mlua::Thread = future.get("scheduler")?;
+
+    // The scheduler finishes once it runs out of futures, and an embedder
+    // can start new ones after that.
+    if scheduler.status() == mlua::ThreadStatus::Finished {
+      scheduler = self
+        .lua
+        .create_thread(future.get::<mlua::Function>("schedule")?)?;
+      future.set("scheduler", &scheduler)?;
+    }
 
-    loop {
-      let active = scheduler.call::<mlua::Value>(sched_co.clone())?;
-      match active {
-        mlua::Value::Boolean(true) | mlua::Value::Nil => {
-          tokio::task::yield_now().await;
-        }
-        mlua::Value::Boolean(false) => {
-          break;
-        }
-        _ => break,
-      }
+    if scheduler.status() == mlua::ThreadStatus::Resumable {
+      scheduler.into_async::<mlua::Value>(())?.await?;
     }
 
-    result
+    Ok(())
   }
 
   pub async fn exec_entry_mod_path(&mut self, path: PathBuf) -> mlua::Result<()> {
@@ -432,3 +608,120 @@ impl Lulu {
     }
   }
 }
+
+/// Finds a dynamic library in the `.lib/dylib` or `dylib` directory of
+/// `current`, or leaves it to the system search path. `@name` stands for
+/// the platform's file name of the library `name`.
+pub fn lookup_dylib(current: Option<&PathBuf>, name: &str) -> std::io::Result<PathBuf> {
+  let path = std::fs::canonicalize(current.cloned().unwrap_or(PathBuf::from(".")))?;
+
+  let name = if let Some(name) = name.strip_prefix("@") {
+    let prefix = if std::env::consts::OS == "windows" {
+      ""
+    } else {
+      "lib"
+    };
+    let ext = match std::env::consts::OS {
+      "windows" => ".dll",
+      "macos" => ".dylib",
+      _ => ".so",
+    };
+
+    format!("{}{}{}", prefix, name, ext)
+  } else {
+    name.to_string()
+  };
+  let lib_folder = path.join(".lib/dylib").join(name.clone());
+  let dylib_here = path.join("dylib").join(name.clone());
+
+  if lib_folder.exists() {
+    Ok(lib_folder)
+  } else if dylib_here.exists() {
+    Ok(dylib_here)
+  } else {
+    Ok(name.into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::conf::{CodeType, load_lulu_conf_code};
+
+  #[test]
+  fn test_strict_mod_exports() {
+    let mut lulu = Lulu::new(None, None);
+    let conf = load_lulu_conf_code(
+      &lulu.lua,
+      CodeType::Code("manifest = { name = 'strict' }\nstrict = true".into()),
+    )
+    .unwrap();
+
+    lulu.add_mod_from_code(
+      "lib".into(),
+      "local function twice(x) return x * 2 end\nexport! twice;".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code(
+      crate::util::normalize_name("./lib.lua"),
+      "local function half(x) return x / 2 end\nexport! { half };".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code(
+      "destructure".into(),
+      "import! { half }, \"./lib.lua\";\nreturn half(8) == 4".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code(
+      "missing".into(),
+      "import! { half, third }, \"./lib.lua\";".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code("leak".into(), "leaked = 1".into(), Some(conf.clone()));
+    lulu.add_mod_from_code("global".into(), "_G.leaked = 1".into(), Some(conf.clone()));
+    lulu.add_mod_from_code(
+      "fenv".into(),
+      "getfenv(print).leaked = 1".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code(
+      "into".into(),
+      "local function set() into_global('leaked', 1) end\nset()".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code(
+      "rawfenv".into(),
+      "rawset(getfenv(0), 'leaked', 1)".into(),
+      Some(conf.clone()),
+    );
+    lulu.add_mod_from_code("undefined".into(), "return nothing_here".into(), Some(conf));
+    lulu.preload_mods().unwrap();
+
+    let eval = |code: &str| lulu.lua.load(code).eval::<mlua::Value>();
+    assert_eq!(
+      eval("return require('lib').twice(2)").unwrap().as_i64(),
+      Some(4)
+    );
+
+    assert_eq!(
+      eval("return require('destructure')").unwrap().as_boolean(),
+      Some(true)
+    );
+    let err = eval("require('missing')").unwrap_err().to_string();
+    assert!(err.contains("has no export 'third'"));
+
+    eval("require('leak')").unwrap();
+    assert!(eval("return leaked").unwrap().is_nil());
+
+    let err = eval("require('global')").unwrap_err().to_string();
+    assert!(err.contains("can't assign the global 'leaked'"));
+    let err = eval("require('fenv')").unwrap_err().to_string();
+    assert!(err.contains("can't assign the global 'leaked'"));
+    let err = eval("require('into')").unwrap_err().to_string();
+    assert!(err.contains("module 'into' can't assign the global 'leaked'"));
+    eval("require('rawfenv')").unwrap();
+    assert!(eval("return leaked").unwrap().is_nil());
+    let err = eval("require('undefined')").unwrap_err().to_string();
+    assert!(err.contains("undefined global 'nothing_here' in module 'undefined'"));
+  }
+}
@@ -0,0 +1,122 @@
+//! `lulu run --hot`: watches the source files of the loaded modules and
+//! reloads the ones that change into the running VM.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::core::Lulu;
+use crate::lulibs::threads::TOK_ASYNC_HANDLES;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The modification time of every module that was loaded from a file.
+fn snapshot(lulu: &Lulu) -> HashMap<String, Option<SystemTime>> {
+  lulu
+    .mods
+    .iter()
+    .filter_map(|m| Some((m.name.clone(), modified(m.path.as_ref()?))))
+    .collect()
+}
+
+/// Spawns the watcher. The entry module only runs once, so changes to it
+/// are reported instead of reloaded.
+pub fn watch(lulu: &Lulu, entry: &str) {
+  let mut lulu = lulu.clone();
+  let entry = entry.to_string();
+  let mut times = snapshot(&lulu);
+
+  let handle = tokio::spawn(async move {
+    loop {
+      tokio::time::sleep(POLL_INTERVAL).await;
+
+      let paths: Vec<(String, PathBuf)> = lulu
+        .mods
+        .iter()
+        .filter_map(|m| Some((m.name.clone(), m.path.clone()?)))
+        .collect();
+
+      for (name, path) in paths {
+        let time = modified(&path);
+        if time.is_none() || times.get(&name) == Some(&time) {
+          continue;
+        }
+        times.insert(name.clone(), time);
+
+        if name == entry {
+          eprintln!("[hot] {} changed, restart to apply it", name);
+          continue;
+        }
+        match lulu.reload_mod(&name) {
+          Ok(()) => eprintln!("[hot] reloaded {}", name),
+          Err(e) => eprintln!(
+            "[hot] failed to reload {}: {}",
+            name,
+            crate::sourcemap::rewrite_error(e, &lulu.sourcemaps)
+          ),
+        }
+      }
+    }
+  });
+
+  TOK_ASYNC_HANDLES.lock().unwrap().push(handle);
+}
+
+/// [`Lulu::exec_entry_mod_path`] with the watcher running. The process keeps
+/// running after the entry module finishes, until it is interrupted.
+pub async fn exec_entry_mod_path(lulu: &mut Lulu, path: PathBuf) -> mlua::Result<()> {
+  let mainname = lulu.entry_mod_path(path)?;
+  watch(lulu, &mainname);
+  lulu.exec_final(mainname.as_str()).await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reload_mod() {
+    let dir = std::env::temp_dir().join(format!("lulu-hot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("counter.lua");
+    let source = |tag: &str| {
+      format!(
+        "local M = {{ hits = 0, tag = '{tag}' }}\n\
+         function mod.on_reload(old) M.hits = old.hits end\n\
+         function M.hit() M.hits = M.hits + 1 return M.tag .. M.hits end\n\
+         return M"
+      )
+    };
+    std::fs::write(&path, source("a")).unwrap();
+
+    let mut lulu = Lulu::new(None, Some(dir.clone()));
+    lulu
+      .add_mod_from_file("counter".into(), path.clone(), None)
+      .unwrap();
+    lulu.preload_mods().unwrap();
+    let hit = |lulu: &Lulu| {
+      lulu
+        .lua
+        .load("return require('counter').hit()")
+        .eval::<String>()
+        .unwrap()
+    };
+    assert_eq!(hit(&lulu), "a1");
+
+    std::fs::write(&path, source("b")).unwrap();
+    lulu.reload_mod("counter").unwrap();
+    assert_eq!(hit(&lulu), "b2");
+
+    std::fs::write(&path, "return = 1").unwrap();
+    assert!(lulu.reload_mod("counter").is_err());
+    assert_eq!(hit(&lulu), "b3");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,188 @@
+//! Reads `.lulib` files and bundled executables for `lulu inspect`, without
+//! applying the trust policy so tampered or untrusted artifacts can still be
+//! looked at. Nothing in them runs, confs are decoded from their bytecode.
+
+use crate::bundle::{Bundle, bundle_version, parse_bundle_body, read_bundle_body};
+use crate::conf::{LuluConf, conf_to_string, read_lulu_conf_from_bytecode};
+use crate::signing::BundleSignature;
+use mlua::Lua;
+use std::path::{Path, PathBuf};
+
+/// A conf attached to one or more modules of the bundle.
+pub struct Package {
+  pub conf: LuluConf,
+  /// The conf decoded back into `lulu.conf.lua` source.
+  pub source: String,
+}
+
+impl Package {
+  fn manifest_field(&self, field: &str) -> Option<String> {
+    self
+      .conf
+      .manifest
+      .as_ref()
+      .and_then(|manifest| manifest.get::<Option<String>>(field).ok().flatten())
+  }
+
+  pub fn name(&self) -> Option<String> {
+    self.manifest_field("name")
+  }
+
+  pub fn version(&self) -> Option<String> {
+    self.manifest_field("version")
+  }
+}
+
+pub struct Inspection {
+  pub format_version: u32,
+  /// Size of the bundle itself, without the runtime of an executable.
+  pub size: usize,
+  /// The signature and whether it matches the bundle.
+  pub signature: Option<(BundleSignature, bool)>,
+  pub bundle: Bundle,
+  pub packages: Vec<Package>,
+  /// Module names, sorted, with the index of their package in `packages`.
+  pub modules: Vec<(String, Option<usize>)>,
+}
+
+pub fn inspect(lua: &Lua, path: &Path) -> mlua::Result<Inspection> {
+  let mut file = std::fs::File::open(path)?;
+  let (body, signature) = read_bundle_body(&mut file)
+    .map_err(|e| mlua::Error::external(format!("{}: {}", path.display(), e)))?;
+  let bundle = parse_bundle_body(&body)?;
+
+  let mut names: Vec<&String> = bundle.mods.keys().collect();
+  names.sort();
+
+  let mut confs: Vec<&[u8]> = Vec::new();
+  let mut packages = Vec::new();
+  let mut modules = Vec::new();
+  for name in names {
+    let package = match &bundle.mods[name].conf {
+      Some(conf) => Some(match confs.iter().position(|c| *c == conf.as_slice()) {
+        Some(idx) => idx,
+        None => {
+          let decoded = read_lulu_conf_from_bytecode(lua, conf).map_err(|e| {
+            mlua::Error::external(format!("Failed to decode the conf of {}: {}", name, e))
+          })?;
+          confs.push(conf);
+          packages.push(Package {
+            source: conf_to_string(&decoded)?,
+            conf: decoded,
+          });
+          packages.len() - 1
+        }
+      }),
+      None => None,
+    };
+    modules.push((name.clone(), package));
+  }
+
+  Ok(Inspection {
+    format_version: bundle_version(&body),
+    size: body.len(),
+    signature: signature.map(|signature| {
+      let valid = signature.verify(&body);
+      (signature, valid)
+    }),
+    packages,
+    modules,
+    bundle,
+  })
+}
+
+/// Where a bundle entry goes under an `--extract` directory, with `://` and
+/// `..` taken out so names cannot escape it.
+fn entry_path(dir: &Path, group: &str, name: &str) -> PathBuf {
+  dir
+    .join(group)
+    .join(crate::resources::normalize(&name.replace("://", "/")))
+}
+
+/// Writes modules as `mods/<name>.luac`, decoded confs as
+/// `conf/<package>.conf.lua` and resources under `resources/`, returning the
+/// written files.
+pub fn extract(inspection: &Inspection, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+  let mut written = Vec::new();
+  let mut write = |path: PathBuf, bytes: &[u8]| -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, bytes)?;
+    written.push(path);
+    Ok(())
+  };
+
+  for (name, _) in &inspection.modules {
+    let path = entry_path(dir, "mods", name);
+    let path = path.with_file_name(format!(
+      "{}.luac",
+      path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    write(path, &inspection.bundle.mods[name].bytes)?;
+  }
+
+  for (idx, package) in inspection.packages.iter().enumerate() {
+    let name = package.name().unwrap_or_else(|| idx.to_string());
+    write(
+      entry_path(dir, "conf", &format!("{}.conf.lua", name)),
+      package.source.as_bytes(),
+    )?;
+  }
+
+  let mut assets: Vec<_> = inspection.bundle.assets.iter().collect();
+  assets.sort_by(|a, b| a.0.cmp(b.0));
+  for (name, bytes) in assets {
+    write(entry_path(dir, "resources", name), bytes)?;
+  }
+
+  Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::core::LuLib;
+
+  #[test]
+  fn test_inspect_and_extract() {
+    let dir = std::env::temp_dir().join(format!("lulu-inspect-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let lua = Lua::new();
+    let conf = crate::util::lua_to_bytecode(
+      &lua,
+      "return { manifest = { name = 'pkg', version = '1.0.0' } }",
+    )
+    .unwrap();
+    let mut bundle = Bundle::default();
+    for name in ["pkg/main", "pkg/util", "bytes://../evil"] {
+      bundle.mods.insert(
+        name.to_string(),
+        LuLib {
+          bytes: name.as_bytes().to_vec(),
+          conf: (name != "bytes://../evil").then(|| conf.clone()),
+        },
+      );
+    }
+    bundle.assets.insert("data/a.txt".to_string(), b"a".to_vec());
+    let path = dir.join("pkg.lulib");
+    let mut file = std::fs::File::create(&path).unwrap();
+    crate::bundle::write_bundle(&mut file, &bundle, false).unwrap();
+
+    let inspection = inspect(&lua, &path).unwrap();
+    assert_eq!(inspection.format_version, 2);
+    assert_eq!(inspection.packages.len(), 1);
+    assert_eq!(inspection.packages[0].version().as_deref(), Some("1.0.0"));
+    assert_eq!(inspection.modules[1], ("pkg/main".to_string(), Some(0)));
+
+    let out = dir.join("out");
+    let written = extract(&inspection, &out).unwrap();
+    assert_eq!(written.len(), 5);
+    assert!(out.join("mods/evil.luac").is_file());
+    assert!(out.join("conf/pkg.conf.lua").is_file());
+    assert!(out.join("resources/data/a.txt").is_file());
+
+    std::fs::remove_dir_all(dir).ok();
+  }
+}
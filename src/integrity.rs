@@ -0,0 +1,216 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CHECKSUMS_FILE: &str = "checksums.json";
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(bytes);
+  format!("{:x}", hasher.finalize())
+}
+
+pub fn verify_sha256(source: &str, bytes: &[u8], expected: Option<&str>) -> Result<()> {
+  if let Some(expected) = expected {
+    let actual = sha256_hex(bytes);
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+      return Err(anyhow!(
+        "Integrity check failed for {}: expected sha256 {}, got {}",
+        source,
+        expected.trim(),
+        actual
+      ));
+    }
+  }
+  Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChecksumEntry {
+  pub sha256: String,
+  pub source: String,
+}
+
+/// Hashes of every artifact copied into a project's `.lib`, stored in
+/// `.lib/checksums.json` and re-checked by `lulu verify`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checksums {
+  #[serde(default)]
+  pub files: BTreeMap<String, ChecksumEntry>,
+}
+
+impl Checksums {
+  pub fn path(lib_dir: &Path) -> PathBuf {
+    lib_dir.join(CHECKSUMS_FILE)
+  }
+
+  pub fn load(lib_dir: &Path) -> Result<Self> {
+    let path = Self::path(lib_dir);
+    if !path.exists() {
+      return Ok(Checksums::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    serde_json::from_str(&content).with_context(|| format!("Malformed {}", path.display()))
+  }
+
+  pub fn save(&self, lib_dir: &Path) -> Result<()> {
+    fs::write(Self::path(lib_dir), serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  pub fn record(&mut self, lib_dir: &Path, file: &Path, source: &str) -> Result<()> {
+    let bytes = fs::read(file)?;
+    self.files.insert(
+      relative_key(lib_dir, file),
+      ChecksumEntry {
+        sha256: sha256_hex(&bytes),
+        source: source.to_string(),
+      },
+    );
+    Ok(())
+  }
+
+  /// Deletes every recorded file that came from `source` and forgets it.
+  pub fn remove_source(&mut self, lib_dir: &Path, source: &str) -> Result<Vec<String>> {
+    let keys: Vec<String> = self
+      .files
+      .iter()
+      .filter(|(_, entry)| entry.source == source)
+      .map(|(key, _)| key.clone())
+      .collect();
+
+    for key in &keys {
+      let path = lib_dir.join(key);
+      if path.exists() {
+        fs::remove_file(path)?;
+      }
+      self.files.remove(key);
+    }
+
+    Ok(keys)
+  }
+}
+
+fn relative_key(lib_dir: &Path, file: &Path) -> String {
+  file
+    .strip_prefix(lib_dir)
+    .unwrap_or(file)
+    .components()
+    .map(|c| c.as_os_str().to_string_lossy().to_string())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+  Ok,
+  Mismatch { expected: String, actual: String },
+  Missing,
+  Untracked,
+  Corrupt(String),
+}
+
+impl FileStatus {
+  pub fn is_failure(&self) -> bool {
+    matches!(
+      self,
+      FileStatus::Mismatch { .. } | FileStatus::Missing | FileStatus::Corrupt(_)
+    )
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+  pub entries: Vec<(String, FileStatus)>,
+}
+
+impl VerifyReport {
+  pub fn is_ok(&self) -> bool {
+    !self.entries.iter().any(|(_, status)| status.is_failure())
+  }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_type()?.is_dir() {
+      collect_files(&entry.path(), files)?;
+    } else {
+      files.push(entry.path());
+    }
+  }
+  Ok(())
+}
+
+/// Re-hashes everything under `<project>/.lib` against the recorded checksums.
+/// Files without a record are reported as untracked, except `.lulib` files that
+/// no longer parse as a bundle, which are reported as corrupt.
+pub fn verify_lib_dir(project_path: &Path) -> Result<VerifyReport> {
+  let lib_dir = project_path.join(".lib");
+  let mut report = VerifyReport::default();
+
+  if !lib_dir.exists() {
+    return Ok(report);
+  }
+
+  let checksums = Checksums::load(&lib_dir)?;
+
+  let mut files = Vec::new();
+  collect_files(&lib_dir, &mut files)?;
+  files.sort();
+
+  for file in files {
+    let key = relative_key(&lib_dir, &file);
+    if key == CHECKSUMS_FILE {
+      continue;
+    }
+
+    let status = if let Some(entry) = checksums.files.get(&key) {
+      let actual = sha256_hex(&fs::read(&file)?);
+      if actual.eq_ignore_ascii_case(&entry.sha256) {
+        FileStatus::Ok
+      } else {
+        FileStatus::Mismatch {
+          expected: entry.sha256.clone(),
+          actual,
+        }
+      }
+    } else if file.extension().and_then(|s| s.to_str()) == Some("lulib") {
+      match crate::bundle::load_lulib(&file) {
+        Ok(_) => FileStatus::Untracked,
+        Err(e) => FileStatus::Corrupt(e.to_string()),
+      }
+    } else {
+      FileStatus::Untracked
+    };
+
+    report.entries.push((key, status));
+  }
+
+  for key in checksums.files.keys() {
+    if !lib_dir.join(key).exists() {
+      report.entries.push((key.clone(), FileStatus::Missing));
+    }
+  }
+
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_verify_sha256() {
+    let digest = sha256_hex(b"lulu");
+
+    assert!(verify_sha256("test", b"lulu", None).is_ok());
+    assert!(verify_sha256("test", b"lulu", Some(&digest)).is_ok());
+    assert!(verify_sha256("test", b"lulu", Some(&digest.to_uppercase())).is_ok());
+    assert!(verify_sha256("test", b"not lulu", Some(&digest)).is_err());
+  }
+}
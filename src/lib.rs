@@ -1,12 +1,32 @@
+pub mod audit;
+pub mod build_sandbox;
 pub mod bundle;
+pub mod bytecode;
+pub mod cache;
 pub mod compiler;
 pub mod conf;
+pub mod conf_edit;
 pub mod lml;
+pub mod lockfile;
+pub mod minify;
 pub mod core;
+pub mod hot;
+pub mod inspect;
+pub mod integrity;
 pub mod ops;
+pub mod outdated;
 pub mod package_manager;
+pub mod permissions;
+pub mod plugins;
+pub mod progress;
 pub mod project;
+pub mod registry;
 pub mod resolver;
+pub mod resources;
+pub mod runtime;
+pub mod signing;
+pub mod target;
+pub mod treeshake;
 pub mod util;
 pub mod sourcemap;
 pub mod lulibs;
@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::package_manager::PackageInfo;
+
+pub const LOCKFILE: &str = "lulu.lock";
+pub const VENDOR_DIR: &str = "vendor";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedPackage {
+  pub url: String,
+  pub name: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub version: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub commit: Option<String>,
+  /// Where `lulu vendor` copied the package, relative to the project root.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub vendor: Option<String>,
+}
+
+/// The dependencies a project resolved to, stored in `lulu.lock` next to `lulu.conf.lua`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+  #[serde(default)]
+  pub packages: Vec<LockedPackage>,
+}
+
+impl Lockfile {
+  pub fn path(project_path: &Path) -> PathBuf {
+    project_path.join(LOCKFILE)
+  }
+
+  pub fn load(project_path: &Path) -> Result<Self> {
+    let path = Self::path(project_path);
+    if !path.exists() {
+      return Ok(Lockfile::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    serde_json::from_str(&content).with_context(|| format!("Malformed {}", path.display()))
+  }
+
+  pub fn save(&self, project_path: &Path) -> Result<()> {
+    fs::write(Self::path(project_path), serde_json::to_string_pretty(self)? + "\n")?;
+    Ok(())
+  }
+
+  pub fn get(&self, url: &str) -> Option<&LockedPackage> {
+    self.packages.iter().find(|p| p.url == url)
+  }
+
+  pub fn remove(&mut self, url: &str) -> Option<LockedPackage> {
+    let idx = self.packages.iter().position(|p| p.url == url)?;
+    Some(self.packages.remove(idx))
+  }
+
+  /// Records a resolved package, keeping the vendor path of an existing entry.
+  pub fn record(&mut self, package: &PackageInfo) {
+    match self.packages.iter_mut().find(|p| p.url == package.url) {
+      Some(locked) => {
+        locked.name = package.name.clone();
+        locked.version = package.version.clone();
+        locked.commit = package.commit.clone();
+      }
+      None => self.packages.push(LockedPackage {
+        url: package.url.clone(),
+        name: package.name.clone(),
+        version: package.version.clone(),
+        commit: package.commit.clone(),
+        vendor: None,
+      }),
+    }
+  }
+
+  /// The vendored copy of `url`, if it was vendored and is still on disk.
+  pub fn vendored_path(&self, project_path: &Path, url: &str) -> Option<PathBuf> {
+    let vendor = self.get(url)?.vendor.as_ref()?;
+    let path = project_path.join(vendor);
+    path.exists().then_some(path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_record_keeps_vendor() {
+    let mut lock = Lockfile::default();
+    let mut info = PackageInfo {
+      name: "pkg".to_string(),
+      version: Some("0.1.0".to_string()),
+      commit: None,
+      url: "registry:pkg".to_string(),
+      cache_path: PathBuf::new(),
+    };
+
+    lock.record(&info);
+    lock.packages[0].vendor = Some("vendor/pkg".to_string());
+
+    info.version = Some("0.2.0".to_string());
+    lock.record(&info);
+
+    assert_eq!(lock.packages.len(), 1);
+    assert_eq!(lock.packages[0].version.as_deref(), Some("0.2.0"));
+    assert_eq!(lock.packages[0].vendor.as_deref(), Some("vendor/pkg"));
+  }
+}
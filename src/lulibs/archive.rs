@@ -1,4 +1,5 @@
 use crate::ops::std::create_std_module;
+use crate::permissions::{check_read, check_write};
 use mlua::Error as LuaError;
 
 use std::fs::File;
@@ -13,7 +14,11 @@ pub fn into_module(){
       let zip_mod = lua.create_table()?;
       zip_mod.set(
         "create",
-        lua.create_function(|_, (archive_path, files): (String, Vec<String>)| {
+        lua.create_function(|lua, (archive_path, files): (String, Vec<String>)| {
+          check_write(lua, &archive_path)?;
+          for path in &files {
+            check_read(lua, path)?;
+          }
           let file = File::create(&archive_path).map_err(|e| LuaError::external(e))?;
           let mut zip = ZipWriter::new(file);
           let options: FileOptions<ExtendedFileOptions> =
@@ -36,7 +41,9 @@ pub fn into_module(){
 
       zip_mod.set(
         "extract",
-        lua.create_function(|_, (archive_path, dest_dir): (String, String)| {
+        lua.create_function(|lua, (archive_path, dest_dir): (String, String)| {
+          check_read(lua, &archive_path)?;
+          check_write(lua, &dest_dir)?;
           let file = File::open(&archive_path).map_err(|e| LuaError::external(e))?;
           let mut archive = zip::ZipArchive::new(file).map_err(|e| LuaError::external(e))?;
 
@@ -67,7 +74,11 @@ pub fn into_module(){
 
       tar_mod.set(
         "create",
-        lua.create_function(|_, (archive_path, files): (String, Vec<String>)| {
+        lua.create_function(|lua, (archive_path, files): (String, Vec<String>)| {
+          check_write(lua, &archive_path)?;
+          for path in &files {
+            check_read(lua, path)?;
+          }
           let tar_gz = File::create(&archive_path).map_err(|e| LuaError::external(e))?;
           let enc = GzEncoder::new(tar_gz, flate2::Compression::default());
           let mut tar = tar::Builder::new(enc);
@@ -83,7 +94,9 @@ pub fn into_module(){
 
       tar_mod.set(
         "extract",
-        lua.create_function(|_, (archive_path, dest_dir): (String, String)| {
+        lua.create_function(|lua, (archive_path, dest_dir): (String, String)| {
+          check_read(lua, &archive_path)?;
+          check_write(lua, &dest_dir)?;
           let tar_gz = std::fs::File::open(&archive_path).map_err(|e| LuaError::external(e))?;
           let dec = GzDecoder::new(tar_gz);
           let mut archive = tar::Archive::new(dec);
@@ -3,6 +3,7 @@ use serde_json;
 use regex::Regex;
 
 use crate::ops::std::create_std_module;
+use crate::permissions::{check_read, check_write};
 
 #[derive(Clone)]
 pub struct LuluSledDB {
@@ -406,7 +407,9 @@ impl mlua::UserData for LuluSledDBMulti {
 pub fn into_module(){
 
   create_std_module("kvdb")
-    .add_function("open", |_, name: String| {
+    .add_function("open", |lua, name: String| {
+      check_read(lua, &name)?;
+      check_write(lua, &name)?;
       Ok(LuluSledDB {
         db: sled::open(name).map_err(mlua::Error::external)?,
       })
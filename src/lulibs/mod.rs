@@ -14,3 +14,5 @@ pub mod rec;
 pub mod rust;
 pub mod serialize;
 pub mod threads;
+pub mod timers;
+pub mod workers;
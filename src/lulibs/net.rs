@@ -17,6 +17,7 @@ use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Message};
 use futures_util::{SinkExt, StreamExt};
 use crate::lulibs::bytes::LuluByteArray;
 use crate::lulibs::threads::TOK_ASYNC_HANDLES;
+use crate::permissions::check_net;
 use crate::ops::std::create_std_module;
 use std::collections::HashMap;
 use mlua::Error as LuaError;
@@ -112,7 +113,8 @@ impl mlua::UserData for LuluUdpSocket {
   fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
     methods.add_async_method(
       "send_to",
-      |_, this, (addr, data): (String, mlua::Value)| async move {
+      |lua, this, (addr, data): (String, mlua::Value)| async move {
+        check_net(&lua, &addr)?;
         let bytes = match data {
           mlua::Value::String(s) => s.as_bytes().to_vec(),
           mlua::Value::UserData(ud) => ud.borrow::<LuluByteArray>()?.bytes.clone(),
@@ -201,7 +203,7 @@ impl mlua::UserData for LuluWebSocket {
 }
 
 #[derive(Debug)]
-struct ServeError(String);
+pub struct ServeError(pub String);
 impl std::fmt::Display for ServeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
@@ -209,10 +211,58 @@ impl std::fmt::Display for ServeError {
 }
 impl std::error::Error for ServeError {}
 
-fn to_serve_error<E: std::fmt::Display>(e: E) -> ServeError {
+pub fn to_serve_error<E: std::fmt::Display>(e: E) -> ServeError {
     ServeError(e.to_string())
 }
 
+/// Accepts connections on `listener` forever, answering each request with
+/// `handler`. Handler errors become a 500 response.
+pub async fn serve_http<H, F>(listener: TcpListener, handler: H)
+where
+    H: Fn(Request<hyper::body::Incoming>) -> F + Clone + Send + Sync + 'static,
+    F: std::future::Future<Output = Result<Response<Full<Bytes>>, ServeError>> + Send + 'static,
+{
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let io = TokioIo::new(stream);
+        let handler = handler.clone();
+
+        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+            let response = handler(req);
+            async move {
+                Ok::<_, Infallible>(
+                    match response.await {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            eprintln!("Server error: {}", e);
+                            Response::builder()
+                                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                .body(Full::new(Bytes::from(format!("Internal Server Error: {}", e))))
+                                .unwrap()
+                        }
+                    }
+                )
+            }
+        });
+
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(io, service)
+                .await
+            {
+                eprintln!("Error serving connection: {:?}", err);
+            }
+        });
+    }
+}
+
 async fn handle_request(
     lua: Arc<mlua::Lua>,
     handler_key: Arc<mlua::RegistryKey>,
@@ -293,6 +343,7 @@ pub fn into_module(){
           let client = client.borrow::<Client>()?;
 
           let url: String = req_table.get("url")?;
+          check_net(&lua, &url)?;
           let method: Option<String> = req_table.get("method").ok();
           let body: Option<mlua::Value> = req_table.get("body").ok();
           let headers: Option<HashMap<String, String>> = req_table.get("headers").ok();
@@ -356,6 +407,7 @@ pub fn into_module(){
         "serve",
         lua.create_async_function(
           |lua, (addr, handler): (String, mlua::Function)| async move {
+            check_net(&lua, &addr)?;
             let handler_key = Arc::new(lua.create_registry_value(handler)?);
             let lua_state = Arc::new(lua.clone());
 
@@ -364,49 +416,12 @@ pub fn into_module(){
               .await
               .map_err(LuaError::external)?;
 
-            let server_task = tokio::spawn(async move {
-                loop {
-                    let (stream, _) = match listener.accept().await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            eprintln!("Failed to accept connection: {}", e);
-                            continue;
-                        }
-                    };
-
-                    let io = TokioIo::new(stream);
-                    let lua_state = lua_state.clone();
-                    let handler_key = handler_key.clone();
-
-                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
-                        let lua = lua_state.clone();
-                        let handler = handler_key.clone();
-                        async move {
-                            Ok::<_, Infallible>(
-                                match handle_request(lua, handler, req).await {
-                                    Ok(resp) => resp,
-                                    Err(e) => {
-                                        eprintln!("Server error: {}", e);
-                                        Response::builder()
-                                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                            .body(Full::new(Bytes::from(format!("Internal Server Error: {}", e))))
-                                            .unwrap()
-                                    }
-                                }
-                            )
-                        }
-                    });
-
-                    tokio::spawn(async move {
-                        if let Err(err) = http1::Builder::new()
-                            .serve_connection(io, service)
-                            .await
-                        {
-                            eprintln!("Error serving connection: {:?}", err);
-                        }
-                    });
-                }
-            });
+            let server_task = tokio::spawn(serve_http(
+                listener,
+                move |req: Request<hyper::body::Incoming>| {
+                    handle_request(lua_state.clone(), handler_key.clone(), req)
+                },
+            ));
 
             TOK_ASYNC_HANDLES.lock().unwrap().push(server_task);
             Ok(())
@@ -419,7 +434,8 @@ pub fn into_module(){
       let tcp_mod = lua.create_table()?;
       tcp_mod.set(
         "connect",
-        lua.create_async_function(|_, addr: String| async move {
+        lua.create_async_function(|lua, addr: String| async move {
+          check_net(&lua, &addr)?;
           let stream = TcpStream::connect(addr)
             .await
             .map_err(mlua::Error::external)?;
@@ -428,7 +444,8 @@ pub fn into_module(){
       )?;
       tcp_mod.set(
         "listen",
-        lua.create_async_function(|_, addr: String| async move {
+        lua.create_async_function(|lua, addr: String| async move {
+          check_net(&lua, &addr)?;
           let listener = TcpListener::bind(addr)
             .await
             .map_err(mlua::Error::external)?;
@@ -442,7 +459,8 @@ pub fn into_module(){
       let udp_mod = lua.create_table()?;
       udp_mod.set(
         "bind",
-        lua.create_async_function(|_, addr: String| async move {
+        lua.create_async_function(|lua, addr: String| async move {
+          check_net(&lua, &addr)?;
           let socket = UdpSocket::bind(addr).await.map_err(mlua::Error::external)?;
           Ok(LuluUdpSocket::new(socket))
         })?,
@@ -452,7 +470,8 @@ pub fn into_module(){
       let ws_mod = lua.create_table()?;
       ws_mod.set(
         "connect",
-        lua.create_async_function(|_, url: String| async move {
+        lua.create_async_function(|lua, url: String| async move {
+          check_net(&lua, &url)?;
           let (ws_stream, _) = connect_async(url).await.map_err(mlua::Error::external)?;
           Ok(LuluWebSocket::new(ws_stream))
         })?,
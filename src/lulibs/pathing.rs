@@ -2,12 +2,34 @@ use mlua::prelude::*;
 use std::path::PathBuf;
 
 use crate::ops::std::create_std_module;
+use crate::permissions::{check_read, check_write};
+use crate::resources::{RES_SCHEME, Resources};
 
 #[derive(Clone)]
 pub struct LuluPath {
   pub base: PathBuf,
 }
 
+impl LuluPath {
+  /// The resource path when this is a `res://` path.
+  fn resource(&self) -> Option<String> {
+    let path = self.base.to_string_lossy();
+    path
+      .starts_with(RES_SCHEME)
+      .then(|| crate::resources::normalize(&path))
+  }
+
+  fn check_writable(&self, lua: &Lua) -> mlua::Result<()> {
+    match self.resource() {
+      Some(res) => Err(mlua::Error::RuntimeError(format!(
+        "Resource '{}' is read-only",
+        res
+      ))),
+      None => check_write(lua, &self.base),
+    }
+  }
+}
+
 impl LuaUserData for LuluPath {
   fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
     methods.add_method_mut("join", |_, this, sub: String| {
@@ -28,11 +50,40 @@ impl LuaUserData for LuluPath {
       Ok(this.base.to_string_lossy().to_string())
     });
 
-    methods.add_method("exists", |_, this, _: ()| Ok(this.base.exists()));
+    methods.add_method("exists", |lua, this, _: ()| match this.resource() {
+      Some(res) => {
+        let resources = Resources::of(lua);
+        Ok(resources.get(&res).is_some() || resources.is_dir(&res))
+      }
+      None => {
+        check_read(lua, &this.base)?;
+        Ok(this.base.exists())
+      }
+    });
 
-    methods.add_method("is_file", |_, this, _: ()| Ok(this.base.is_file()));
+    methods.add_method("is_file", |lua, this, _: ()| match this.resource() {
+      Some(res) => Ok(Resources::of(lua).get(&res).is_some()),
+      None => {
+        check_read(lua, &this.base)?;
+        Ok(this.base.is_file())
+      }
+    });
 
-    methods.add_method("is_dir", |_, this, _: ()| Ok(this.base.is_dir()));
+    methods.add_method("is_dir", |lua, this, _: ()| match this.resource() {
+      Some(res) => Ok(Resources::of(lua).is_dir(&res)),
+      None => {
+        check_read(lua, &this.base)?;
+        Ok(this.base.is_dir())
+      }
+    });
+
+    methods.add_method("read", |lua, this, _: ()| match this.resource() {
+      Some(res) => lua.create_string(Resources::of(lua).read(&res)?),
+      None => {
+        check_read(lua, &this.base)?;
+        lua.create_string(std::fs::read(&this.base).map_err(mlua::Error::external)?)
+      }
+    });
 
     methods.add_method("filename", |lua, this, _: ()| {
       Ok(
@@ -90,23 +141,35 @@ impl LuaUserData for LuluPath {
     methods.add_method("list", |lua, this, _: ()| {
       let tbl = lua.create_table()?;
 
-      if this.base.is_dir() {
-        for entry in std::fs::read_dir(&this.base).map_err(mlua::Error::external)? {
-          let entry = entry.map_err(mlua::Error::external)?;
-          let path = LuluPath { base: entry.path() };
+      if let Some(res) = this.resource() {
+        for name in Resources::of(lua).list(&res) {
+          let path = LuluPath {
+            base: PathBuf::from(format!("{}{}", RES_SCHEME, name.trim_end_matches('/'))),
+          };
           tbl.push(lua.create_userdata(path)?)?;
         }
+      } else {
+        check_read(lua, &this.base)?;
+        if this.base.is_dir() {
+          for entry in std::fs::read_dir(&this.base).map_err(mlua::Error::external)? {
+            let entry = entry.map_err(mlua::Error::external)?;
+            let path = LuluPath { base: entry.path() };
+            tbl.push(lua.create_userdata(path)?)?;
+          }
+        }
       }
 
       Ok(tbl)
     });
 
-    methods.add_method("ensure_dir", |_, this, _: ()| {
+    methods.add_method("ensure_dir", |lua, this, _: ()| {
+      this.check_writable(lua)?;
       std::fs::create_dir_all(&this.base).map_err(mlua::Error::external)?;
       Ok(this.clone())
     });
 
-    methods.add_method("ensure_file", |_, this, content: Option<String>| {
+    methods.add_method("ensure_file", |lua, this, content: Option<String>| {
+      this.check_writable(lua)?;
       if let Some(parent) = this.base.parent() {
         std::fs::create_dir_all(parent).map_err(mlua::Error::external)?;
       }
@@ -122,7 +185,8 @@ impl LuaUserData for LuluPath {
       Ok(this.clone())
     });
 
-    methods.add_method("ensure", |_lua, this, content: Option<String>| {
+    methods.add_method("ensure", |lua, this, content: Option<String>| {
+      this.check_writable(lua)?;
       if this.base.ends_with(std::path::MAIN_SEPARATOR.to_string()) {
         if !this.base.exists() {
           std::fs::create_dir_all(&this.base).map_err(mlua::Error::external)?;
@@ -153,6 +217,15 @@ pub fn into_module() {
       })?;
       Ok(ud)
     })
+    .add_function("res", |lua, path: Option<String>| {
+      lua.create_userdata(LuluPath {
+        base: PathBuf::from(format!(
+          "{}{}",
+          RES_SCHEME,
+          crate::resources::normalize(path.as_deref().unwrap_or(""))
+        )),
+      })
+    })
     .add_function("root", |lua, _: ()| {
       lua.create_userdata(LuluPath {
         base: std::env::current_dir().unwrap(),
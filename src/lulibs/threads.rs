@@ -1,7 +1,14 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use tokio::sync::{Mutex as TokioMutex, Notify, broadcast, mpsc};
 use tokio::task::JoinHandle;
 use mlua::Error as LuaError;
+use mlua::ObjectLike;
 
+use crate::lulibs::timers::LuluTimer;
 use crate::ops::std::create_std_module;
 
 
@@ -17,6 +24,256 @@ pub struct LuluThreadHandle {
 
 impl mlua::UserData for LuluThreadHandle {}
 
+#[derive(Clone)]
+enum SenderKind {
+  Bounded(mpsc::Sender<mlua::Value>),
+  Unbounded(mpsc::UnboundedSender<mlua::Value>),
+  Broadcast(broadcast::Sender<mlua::Value>),
+}
+
+enum ReceiverKind {
+  Bounded(mpsc::Receiver<mlua::Value>),
+  Unbounded(mpsc::UnboundedReceiver<mlua::Value>),
+  Broadcast(broadcast::Receiver<mlua::Value>),
+}
+
+impl ReceiverKind {
+  /// Stops senders, leaving what they already sent to receive. Broadcast
+  /// receivers stop getting values once collected.
+  fn close(&mut self) {
+    match self {
+      ReceiverKind::Bounded(rx) => rx.close(),
+      ReceiverKind::Unbounded(rx) => rx.close(),
+      ReceiverKind::Broadcast(_) => {}
+    }
+  }
+}
+
+/// The sending half of `threads.channel` or `threads.broadcast`. The channel
+/// closes once every sender is closed or collected.
+#[derive(Clone)]
+pub struct LuluSender {
+  kind: Arc<Mutex<Option<SenderKind>>>,
+}
+
+impl LuluSender {
+  fn kind(&self) -> Option<SenderKind> {
+    self.kind.lock().unwrap().clone()
+  }
+
+  /// Sends `value`, waiting for room in a full bounded channel. Returns
+  /// false when the channel is closed.
+  async fn send(&self, value: mlua::Value) -> bool {
+    match self.kind() {
+      Some(SenderKind::Bounded(tx)) => tx.send(value).await.is_ok(),
+      Some(SenderKind::Unbounded(tx)) => tx.send(value).is_ok(),
+      Some(SenderKind::Broadcast(tx)) => tx.send(value).is_ok(),
+      None => false,
+    }
+  }
+
+  fn try_send(&self, value: mlua::Value) -> bool {
+    match self.kind() {
+      Some(SenderKind::Bounded(tx)) => tx.try_send(value).is_ok(),
+      Some(SenderKind::Unbounded(tx)) => tx.send(value).is_ok(),
+      Some(SenderKind::Broadcast(tx)) => tx.send(value).is_ok(),
+      None => false,
+    }
+  }
+}
+
+impl mlua::UserData for LuluSender {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_async_method("send", |_, this, value: mlua::Value| async move {
+      let sender = this.clone();
+      drop(this);
+      Ok(sender.send(value).await)
+    });
+    methods.add_method("try_send", |_, this, value: mlua::Value| Ok(this.try_send(value)));
+    // A separate handle, so closing one doesn't close the others.
+    methods.add_method("clone", |_, this, ()| {
+      Ok(LuluSender {
+        kind: Arc::new(Mutex::new(this.kind())),
+      })
+    });
+    methods.add_method("subscribe", |_, this, ()| match this.kind() {
+      Some(SenderKind::Broadcast(tx)) => Ok(LuluReceiver::new(ReceiverKind::Broadcast(tx.subscribe()))),
+      Some(_) => Err(LuaError::RuntimeError(
+        "subscribe is only available on broadcast channels".to_string(),
+      )),
+      None => Err(LuaError::RuntimeError("channel is closed".to_string())),
+    });
+    methods.add_method("close", |_, this, ()| {
+      this.kind.lock().unwrap().take();
+      Ok(())
+    });
+  }
+}
+
+/// The receiving half of a channel. `recv` gives `nil` once the channel is
+/// closed and drained.
+#[derive(Clone)]
+pub struct LuluReceiver {
+  kind: Arc<TokioMutex<ReceiverKind>>,
+  /// Set by `close`, which can't wait for the lock a pending `recv` holds.
+  closed: Arc<AtomicBool>,
+  on_close: Arc<Notify>,
+}
+
+impl LuluReceiver {
+  fn new(kind: ReceiverKind) -> Self {
+    Self {
+      kind: Arc::new(TokioMutex::new(kind)),
+      closed: Arc::new(AtomicBool::new(false)),
+      on_close: Arc::new(Notify::new()),
+    }
+  }
+
+  fn close(&self) {
+    self.closed.store(true, Ordering::SeqCst);
+    self.on_close.notify_waiters();
+    if let Ok(mut kind) = self.kind.try_lock() {
+      kind.close();
+    }
+  }
+
+  /// Waits for the next value. Broadcast receivers that fell behind skip
+  /// the values they missed.
+  async fn recv(&self) -> Option<mlua::Value> {
+    let mut kind = self.kind.lock().await;
+    let on_close = self.on_close.notified();
+    tokio::pin!(on_close);
+    on_close.as_mut().enable();
+    if !self.closed.load(Ordering::SeqCst) {
+      let received = tokio::select! {
+        value = Self::recv_from(&mut kind) => Some(value),
+        _ = on_close => None,
+      };
+      if let Some(value) = received {
+        return value;
+      }
+    }
+
+    kind.close();
+    match &mut *kind {
+      ReceiverKind::Broadcast(_) => None,
+      kind => Self::recv_from(kind).await,
+    }
+  }
+
+  async fn recv_from(kind: &mut ReceiverKind) -> Option<mlua::Value> {
+    match kind {
+      ReceiverKind::Bounded(rx) => rx.recv().await,
+      ReceiverKind::Unbounded(rx) => rx.recv().await,
+      ReceiverKind::Broadcast(rx) => loop {
+        match rx.recv().await {
+          Ok(value) => break Some(value),
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => break None,
+        }
+      },
+    }
+  }
+
+  fn try_recv(&self) -> Option<mlua::Value> {
+    let mut kind = self.kind.try_lock().ok()?;
+    if self.closed.load(Ordering::SeqCst) {
+      kind.close();
+    }
+    match &mut *kind {
+      ReceiverKind::Broadcast(_) if self.closed.load(Ordering::SeqCst) => None,
+      ReceiverKind::Bounded(rx) => rx.try_recv().ok(),
+      ReceiverKind::Unbounded(rx) => rx.try_recv().ok(),
+      ReceiverKind::Broadcast(rx) => loop {
+        match rx.try_recv() {
+          Ok(value) => break Some(value),
+          Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+          Err(_) => break None,
+        }
+      },
+    }
+  }
+}
+
+impl mlua::UserData for LuluReceiver {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_async_method("recv", |_, this, ()| async move {
+      let receiver = this.clone();
+      drop(this);
+      Ok(receiver.recv().await)
+    });
+    methods.add_method("try_recv", |_, this, ()| Ok(this.try_recv()));
+    methods.add_method("close", |_, this, ()| {
+      this.close();
+      Ok(())
+    });
+  }
+}
+
+type SelectCase = Pin<Box<dyn Future<Output = mlua::Value> + Send>>;
+
+/// Waits until one of `cases` is ready and returns its index with the value
+/// it produced. Receivers and timers register wakers; `Future`s are checked
+/// every time the scheduler resumes the select, since they only progress
+/// when it polls them.
+async fn select(cases: mlua::Table) -> mlua::Result<(usize, mlua::Value)> {
+  let mut waits: Vec<(usize, SelectCase)> = Vec::new();
+  let mut futures: Vec<(usize, mlua::Table)> = Vec::new();
+
+  for (idx, case) in cases.sequence_values::<mlua::Value>().enumerate() {
+    let idx = idx + 1;
+    match case? {
+      mlua::Value::UserData(ud) if ud.is::<LuluReceiver>() => {
+        let receiver = ud.borrow::<LuluReceiver>()?.clone();
+        waits.push((
+          idx,
+          Box::pin(async move { receiver.recv().await.unwrap_or(mlua::Value::Nil) }),
+        ));
+      }
+      mlua::Value::UserData(ud) if ud.is::<LuluTimer>() => {
+        let timer = ud.borrow::<LuluTimer>()?.clone();
+        waits.push((
+          idx,
+          Box::pin(async move {
+            timer.fired().await;
+            mlua::Value::UserData(ud)
+          }),
+        ));
+      }
+      mlua::Value::Table(future) if future.contains_key("co")? => futures.push((idx, future)),
+      other => {
+        return Err(LuaError::RuntimeError(format!(
+          "threads.select: case {} is a {}, expected a receiver, timer or Future",
+          idx,
+          other.type_name()
+        )));
+      }
+    }
+  }
+
+  let ready = std::future::poll_fn(|cx| {
+    for (idx, future) in &futures {
+      match future.get::<bool>("done") {
+        Ok(true) => return Poll::Ready(Ok((*idx, Some(future.clone()), mlua::Value::Nil))),
+        Ok(false) => {}
+        Err(e) => return Poll::Ready(Err(e)),
+      }
+    }
+    for (idx, wait) in waits.iter_mut() {
+      if let Poll::Ready(value) = wait.as_mut().poll(cx) {
+        return Poll::Ready(Ok((*idx, None, value)));
+      }
+    }
+    Poll::Pending
+  })
+  .await?;
+
+  match ready {
+    (idx, Some(future), _) => Ok((idx, future.call_method("last", ())?)),
+    (idx, None, value) => Ok((idx, value)),
+  }
+}
+
 pub fn into_module(){
 
   create_std_module("threads")
@@ -72,6 +329,48 @@ pub fn into_module(){
         })?,
       )?;
 
+      threads_mod.set(
+        "channel",
+        lua.create_function(|_, cap: Option<usize>| {
+          let (sender, receiver) = match cap {
+            Some(cap) => {
+              let (tx, rx) = mpsc::channel(cap.max(1));
+              (SenderKind::Bounded(tx), ReceiverKind::Bounded(rx))
+            }
+            None => {
+              let (tx, rx) = mpsc::unbounded_channel();
+              (SenderKind::Unbounded(tx), ReceiverKind::Unbounded(rx))
+            }
+          };
+          Ok((
+            LuluSender {
+              kind: Arc::new(Mutex::new(Some(sender))),
+            },
+            LuluReceiver::new(receiver),
+          ))
+        })?,
+      )?;
+
+      threads_mod.set(
+        "broadcast",
+        lua.create_function(|_, cap: Option<usize>| {
+          let (tx, rx) = broadcast::channel(cap.unwrap_or(64).max(1));
+          Ok((
+            LuluSender {
+              kind: Arc::new(Mutex::new(Some(SenderKind::Broadcast(tx)))),
+            },
+            LuluReceiver::new(ReceiverKind::Broadcast(rx)),
+          ))
+        })?,
+      )?;
+
+      threads_mod.set(
+        "select",
+        lua.create_async_function(|_, cases: mlua::Table| select(cases))?,
+      )?;
+
+      crate::lulibs::workers::register(lua, &threads_mod)?;
+
       threads_mod.set(
         "sleep",
         lua.create_async_function(|_, ms: u64| async move {
@@ -0,0 +1,103 @@
+use crate::ops::std::create_std_module;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+lazy_static::lazy_static! {
+  static ref TIMERS_START: Instant = Instant::now();
+}
+
+/// The handle returned by `timers.after` and `timers.every`. Cancelling it
+/// wakes the pending `wait` so the future driving the timer ends right away.
+#[derive(Clone, Default)]
+pub struct LuluTimer {
+  cancelled: Arc<AtomicBool>,
+  notify: Arc<Notify>,
+  /// How many `wait`s ran out, and how many of those `fired` resolved for.
+  fires: Arc<AtomicU64>,
+  seen: Arc<AtomicU64>,
+  fired: Arc<Notify>,
+}
+
+impl LuluTimer {
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+    self.notify.notify_one();
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Sleeps for `ms`, returning false if the timer got cancelled first.
+  pub async fn wait(&self, ms: u64) -> bool {
+    if self.is_cancelled() {
+      return false;
+    }
+
+    let fired = tokio::select! {
+      _ = tokio::time::sleep(Duration::from_millis(ms)) => !self.is_cancelled(),
+      _ = self.notify.notified() => false,
+    };
+    if fired {
+      self.fires.fetch_add(1, Ordering::SeqCst);
+      self.fired.notify_waiters();
+    }
+    fired
+  }
+
+  /// Resolves for a `wait` on this timer that ran out, right away when one
+  /// did that no earlier `fired` resolved for. This is what `threads.select`
+  /// waits on.
+  pub async fn fired(&self) {
+    loop {
+      let notified = self.fired.notified();
+      tokio::pin!(notified);
+      // Registered before checking, so a fire in between still wakes us.
+      notified.as_mut().enable();
+      let fires = self.fires.load(Ordering::SeqCst);
+      if self.seen.fetch_max(fires, Ordering::SeqCst) < fires {
+        return;
+      }
+      notified.await;
+    }
+  }
+}
+
+impl mlua::UserData for LuluTimer {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method("cancel", |_, this, ()| {
+      this.cancel();
+      Ok(())
+    });
+    methods.add_method("cancelled", |_, this, ()| Ok(this.is_cancelled()));
+    methods.add_async_method("wait", |_, this, ms: u64| async move {
+      // Release the borrow so `cancel` can be called while waiting.
+      let timer = this.clone();
+      drop(this);
+      Ok(timer.wait(ms).await)
+    });
+  }
+}
+
+pub fn into_module() {
+  create_std_module("timers")
+    .on_register(|lua, timers_mod| {
+      lazy_static::initialize(&TIMERS_START);
+
+      timers_mod.set(
+        "timer",
+        lua.create_function(|_, ()| Ok(LuluTimer::default()))?,
+      )?;
+
+      timers_mod.set(
+        "now",
+        lua.create_function(|_, ()| Ok(TIMERS_START.elapsed().as_millis() as u64))?,
+      )?;
+
+      Ok(timers_mod)
+    })
+    .add_file("timers.lua", include_str!("../builtins/timers.lua"))
+    .into();
+}
@@ -0,0 +1,580 @@
+//! `threads.worker` and `threads.pool`: modules running in their own `Lulu`
+//! VM on an OS thread, so CPU-bound Lua runs in parallel. Workers share no
+//! Lua state with the parent; messages are copied as JSON, except `Arc`
+//! values, which stay shared between both sides.
+
+use crate::conf::{CodeType, conf_to_string, load_lulu_conf_code};
+use crate::core::{Lulu, LuluMod, LuluModSource};
+use crate::lulibs::rust::LuluArc;
+use crate::permissions::Permissions;
+use mlua::{Error as LuaError, Lua};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as TokioMutex, mpsc};
+
+/// What a worker VM is built from, kept in the app data of every VM by
+/// `register_ops`.
+#[derive(Clone)]
+pub struct WorkerSource {
+  pub mods: Vec<(String, LuluModSource, Option<crate::conf::LuluConf>)>,
+  pub assets: HashMap<String, Vec<u8>>,
+  pub sourcemaps: HashMap<String, Vec<u32>>,
+  pub current: Option<PathBuf>,
+  /// Workers get the permissions of the VM that starts them.
+  pub permissions: Option<Permissions>,
+  pub std_modules: Option<Vec<String>>,
+}
+
+impl WorkerSource {
+  pub fn of(lulu: &Lulu) -> Self {
+    WorkerSource {
+      mods: lulu
+        .mods
+        .iter()
+        .map(|lmod| (lmod.name.clone(), lmod.source.clone(), lmod.conf.clone()))
+        .collect(),
+      assets: lulu.assets.clone(),
+      sourcemaps: lulu.sourcemaps.clone(),
+      current: lulu.current.clone(),
+      permissions: lulu.permissions.clone(),
+      std_modules: lulu.std_modules.clone(),
+    }
+  }
+
+  /// The full name of `name`, which may leave out the package prefix.
+  fn resolve(&self, name: &str) -> mlua::Result<String> {
+    let suffix = format!("/{}", name);
+    self
+      .mods
+      .iter()
+      .find(|(mod_name, _, _)| mod_name == name)
+      .or_else(|| {
+        self
+          .mods
+          .iter()
+          .find(|(mod_name, _, _)| mod_name.ends_with(&suffix))
+      })
+      .map(|(mod_name, _, _)| mod_name.clone())
+      .ok_or_else(|| LuaError::RuntimeError(format!("Worker module '{}' not found", name)))
+  }
+
+  /// Confs hold tables of the VM they were loaded in, so they cross over as
+  /// source and are loaded again by the worker.
+  fn seed(&self) -> mlua::Result<WorkerSeed> {
+    let mut mods = Vec::new();
+    for (name, source, conf) in &self.mods {
+      let conf = conf.as_ref().map(conf_to_string).transpose()?;
+      mods.push((name.clone(), source.clone(), conf));
+    }
+
+    Ok(WorkerSeed {
+      mods,
+      assets: self.assets.clone(),
+      sourcemaps: self.sourcemaps.clone(),
+      current: self.current.clone(),
+      permissions: self.permissions.clone(),
+      std_modules: self.std_modules.clone(),
+    })
+  }
+}
+
+struct WorkerSeed {
+  mods: Vec<(String, LuluModSource, Option<String>)>,
+  assets: HashMap<String, Vec<u8>>,
+  sourcemaps: HashMap<String, Vec<u32>>,
+  current: Option<PathBuf>,
+  permissions: Option<Permissions>,
+  std_modules: Option<Vec<String>>,
+}
+
+impl WorkerSeed {
+  fn build(&self) -> mlua::Result<Lulu> {
+    let mut lulu = Lulu::new(None, self.current.clone());
+    for (name, source, conf) in &self.mods {
+      let conf = match conf {
+        Some(code) => Some(load_lulu_conf_code(
+          &lulu.lua,
+          CodeType::Bytes(code.clone().into_bytes()),
+        )?),
+        None => None,
+      };
+      // Sources are already compiled, so they are added as they are.
+      lulu.mods.push(LuluMod {
+        name: name.clone(),
+        source: source.clone(),
+        conf,
+        path: None,
+      });
+    }
+    lulu.assets = self.assets.clone();
+    lulu.sourcemaps = self.sourcemaps.clone();
+    lulu.permissions = self.permissions.clone();
+    lulu.std_modules = self.std_modules.clone();
+    Ok(lulu)
+  }
+}
+
+/// Marks where an `Arc` sits in the JSON of a message.
+const ARC_KEY: &str = "__lulu_arc";
+/// Marks a table with keys other than strings, sent as `[key, value]` pairs
+/// so the keys keep their type.
+const PAIRS_KEY: &str = "__lulu_pairs";
+
+/// A value on its way between two VMs: its JSON, with the `Arc`s found in it
+/// kept aside so both sides share them.
+#[derive(Clone, Default)]
+pub struct WorkerMessage {
+  json: serde_json::Value,
+  arcs: Vec<LuluArc>,
+}
+
+impl WorkerMessage {
+  pub fn encode(value: &mlua::Value) -> mlua::Result<Self> {
+    let mut message = WorkerMessage::default();
+    message.json = message.encode_value(value, &mut HashSet::new())?;
+    Ok(message)
+  }
+
+  /// `visiting` holds the tables being encoded around `value`, as JSON can
+  /// copy a table seen twice but not one that holds itself.
+  fn encode_value(
+    &mut self,
+    value: &mlua::Value,
+    visiting: &mut HashSet<usize>,
+  ) -> mlua::Result<serde_json::Value> {
+    match value {
+      mlua::Value::UserData(ud) if ud.is::<LuluArc>() => {
+        self.arcs.push(ud.borrow::<LuluArc>()?.clone());
+        Ok(serde_json::json!({ ARC_KEY: self.arcs.len() - 1 }))
+      }
+      mlua::Value::Table(table) => {
+        let key = table.to_pointer() as usize;
+        if !visiting.insert(key) {
+          return Err(LuaError::RuntimeError(
+            "Cannot send a cyclic table to a worker".to_string(),
+          ));
+        }
+        let json = self.encode_table(table, visiting);
+        visiting.remove(&key);
+        json
+      }
+      value => serde_json::to_value(value)
+        .map_err(|e| LuaError::RuntimeError(format!("Cannot send value to a worker: {}", e))),
+    }
+  }
+
+  fn encode_table(
+    &mut self,
+    table: &mlua::Table,
+    visiting: &mut HashSet<usize>,
+  ) -> mlua::Result<serde_json::Value> {
+    let pairs = table
+      .pairs::<mlua::Value, mlua::Value>()
+      .collect::<mlua::Result<Vec<_>>>()?;
+    let len = table.raw_len();
+    if len > 0 && pairs.len() == len {
+      return (1..=len)
+        .map(|idx| self.encode_value(&table.raw_get(idx)?, visiting))
+        .collect::<mlua::Result<Vec<_>>>()
+        .map(serde_json::Value::Array);
+    }
+
+    if pairs.iter().all(|(key, _)| key.is_string()) {
+      let mut map = serde_json::Map::new();
+      for (key, value) in pairs {
+        let key = key.to_string()?;
+        map.insert(key, self.encode_value(&value, visiting)?);
+      }
+      return Ok(serde_json::Value::Object(map));
+    }
+
+    let mut entries = Vec::new();
+    for (key, value) in pairs {
+      if !matches!(
+        key,
+        mlua::Value::String(_)
+          | mlua::Value::Integer(_)
+          | mlua::Value::Number(_)
+          | mlua::Value::Boolean(_)
+      ) {
+        return Err(LuaError::RuntimeError(format!(
+          "Cannot send a table with {} keys to a worker",
+          key.type_name()
+        )));
+      }
+      entries.push(serde_json::Value::Array(vec![
+        self.encode_value(&key, visiting)?,
+        self.encode_value(&value, visiting)?,
+      ]));
+    }
+    Ok(serde_json::json!({ PAIRS_KEY: entries }))
+  }
+
+  pub fn decode(self, lua: &Lua) -> mlua::Result<mlua::Value> {
+    self.decode_value(lua, &self.json)
+  }
+
+  fn decode_value(&self, lua: &Lua, json: &serde_json::Value) -> mlua::Result<mlua::Value> {
+    Ok(match json {
+      serde_json::Value::Null => mlua::Value::Nil,
+      serde_json::Value::Bool(b) => mlua::Value::Boolean(*b),
+      serde_json::Value::Number(n) => match n.as_i64() {
+        Some(i) => mlua::Value::Integer(i),
+        None => mlua::Value::Number(n.as_f64().unwrap_or_default()),
+      },
+      serde_json::Value::String(s) => mlua::Value::String(lua.create_string(s)?),
+      serde_json::Value::Array(items) => {
+        let table = lua.create_table()?;
+        for (idx, item) in items.iter().enumerate() {
+          table.raw_set(idx + 1, self.decode_value(lua, item)?)?;
+        }
+        mlua::Value::Table(table)
+      }
+      serde_json::Value::Object(map) => {
+        if let Some(arc) = map
+          .get(ARC_KEY)
+          .and_then(|idx| self.arcs.get(idx.as_u64()? as usize))
+        {
+          return Ok(mlua::Value::UserData(lua.create_userdata(arc.clone())?));
+        }
+        let table = lua.create_table()?;
+        if let Some(serde_json::Value::Array(entries)) = map.get(PAIRS_KEY) {
+          for entry in entries {
+            if let serde_json::Value::Array(pair) = entry
+              && let [key, value] = pair.as_slice()
+            {
+              table.raw_set(self.decode_value(lua, key)?, self.decode_value(lua, value)?)?;
+            }
+          }
+          return Ok(mlua::Value::Table(table));
+        }
+        for (key, value) in map {
+          table.raw_set(key.as_str(), self.decode_value(lua, value)?)?;
+        }
+        mlua::Value::Table(table)
+      }
+    })
+  }
+}
+
+/// A message tagged with the job it belongs to, so `pool:map` can put
+/// replies back in order.
+struct Envelope {
+  job: u64,
+  message: WorkerMessage,
+}
+
+type Inbox = Arc<TokioMutex<mpsc::UnboundedReceiver<Envelope>>>;
+type WorkerResult = Result<WorkerMessage, String>;
+
+/// Sets the `worker` global of a worker VM: `id`, `args`, `recv()` for the
+/// next message from the parent and `send(value)` to reply to it.
+fn register_worker_global(
+  lua: &Lua,
+  id: usize,
+  args: WorkerMessage,
+  inbox: Inbox,
+  outbox: mpsc::UnboundedSender<Envelope>,
+) -> mlua::Result<()> {
+  let current_job = Arc::new(AtomicU64::new(0));
+  let worker = lua.create_table()?;
+  worker.set("id", id)?;
+  worker.set("args", args.decode(lua)?)?;
+
+  worker.set(
+    "recv",
+    lua.create_async_function({
+      let current_job = current_job.clone();
+      move |lua, ()| {
+        let inbox = inbox.clone();
+        let current_job = current_job.clone();
+        async move {
+          let envelope = inbox.lock().await.recv().await;
+          match envelope {
+            Some(envelope) => {
+              current_job.store(envelope.job, Ordering::SeqCst);
+              envelope.message.decode(&lua)
+            }
+            None => Ok(mlua::Value::Nil),
+          }
+        }
+      }
+    })?,
+  )?;
+
+  worker.set(
+    "send",
+    lua.create_function(move |_, value: mlua::Value| {
+      Ok(
+        outbox
+          .send(Envelope {
+            job: current_job.load(Ordering::SeqCst),
+            message: WorkerMessage::encode(&value)?,
+          })
+          .is_ok(),
+      )
+    })?,
+  )?;
+
+  lua.globals().set("worker", worker)
+}
+
+fn run_worker(
+  seed: Arc<WorkerSeed>,
+  module: String,
+  id: usize,
+  args: WorkerMessage,
+  inbox: Inbox,
+  outbox: mpsc::UnboundedSender<Envelope>,
+) -> WorkerResult {
+  let runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .build()
+    .map_err(|e| e.to_string())?;
+
+  runtime.block_on(async move {
+    let mut lulu = seed.build().map_err(|e| e.to_string())?;
+    let result = async {
+      lulu.preload_mods()?;
+      register_worker_global(&lulu.lua, id, args, inbox, outbox)?;
+      // The module waits on `worker.recv()` at its top level.
+      let value = lulu.exec_mod_async(&module).await;
+      lulu.drive_scheduler().await?;
+      WorkerMessage::encode(&value?)
+    }
+    .await;
+    result.map_err(|e| match crate::sourcemap::rewrite_error(e, &lulu.sourcemaps) {
+      LuaError::RuntimeError(message) => message,
+      e => e.to_string(),
+    })
+  })
+}
+
+/// The parent's handle on a worker or pool.
+#[derive(Clone)]
+pub struct LuluWorker {
+  module: String,
+  inbox: Arc<Mutex<Option<mpsc::UnboundedSender<Envelope>>>>,
+  outbox: Arc<TokioMutex<mpsc::UnboundedReceiver<Envelope>>>,
+  threads: Arc<Mutex<Vec<std::thread::JoinHandle<WorkerResult>>>>,
+  next_job: Arc<AtomicU64>,
+  pool: bool,
+}
+
+impl LuluWorker {
+  pub fn start(lua: &Lua, name: &str, size: usize, args: mlua::Value, pool: bool) -> mlua::Result<Self> {
+    let source = lua
+      .app_data_ref::<WorkerSource>()
+      .ok_or_else(|| LuaError::RuntimeError("Workers are not available here".to_string()))?
+      .clone();
+    let module = source.resolve(name)?;
+    let seed = Arc::new(source.seed()?);
+    let args = WorkerMessage::encode(&args)?;
+
+    let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+    let (outbox_tx, outbox_rx) = mpsc::unbounded_channel();
+    let inbox_rx = Arc::new(TokioMutex::new(inbox_rx));
+
+    let mut threads = Vec::new();
+    for id in 1..=size.max(1) {
+      let (seed, module, args) = (seed.clone(), module.clone(), args.clone());
+      let (inbox, outbox) = (inbox_rx.clone(), outbox_tx.clone());
+      threads.push(
+        std::thread::Builder::new()
+          .name(format!("lulu-worker-{}-{}", module, id))
+          .spawn(move || run_worker(seed, module, id, args, inbox, outbox))?,
+      );
+    }
+
+    Ok(LuluWorker {
+      module,
+      inbox: Arc::new(Mutex::new(Some(inbox_tx))),
+      outbox: Arc::new(TokioMutex::new(outbox_rx)),
+      threads: Arc::new(Mutex::new(threads)),
+      next_job: Arc::new(AtomicU64::new(1)),
+      pool,
+    })
+  }
+
+  fn send(&self, value: &mlua::Value) -> mlua::Result<u64> {
+    let job = self.next_job.fetch_add(1, Ordering::SeqCst);
+    let message = WorkerMessage::encode(value)?;
+    match &*self.inbox.lock().unwrap() {
+      Some(inbox) if inbox.send(Envelope { job, message }).is_ok() => Ok(job),
+      _ => Err(LuaError::RuntimeError(format!(
+        "Worker '{}' is closed",
+        self.module
+      ))),
+    }
+  }
+
+  /// Closes the inbox and waits for every thread, giving the value each
+  /// worker module returned.
+  async fn join(&self) -> mlua::Result<Vec<WorkerMessage>> {
+    self.inbox.lock().unwrap().take();
+    let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+    let module = self.module.clone();
+
+    tokio::task::spawn_blocking(move || {
+      threads
+        .into_iter()
+        .map(|thread| match thread.join() {
+          Ok(Ok(value)) => Ok(value),
+          Ok(Err(e)) => Err(LuaError::RuntimeError(format!("Worker '{}' failed: {}", module, e))),
+          Err(_) => Err(LuaError::RuntimeError(format!("Worker '{}' panicked", module))),
+        })
+        .collect()
+    })
+    .await
+    .map_err(LuaError::external)?
+  }
+}
+
+impl mlua::UserData for LuluWorker {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method("send", |_, this, value: mlua::Value| {
+      this.send(&value)?;
+      Ok(())
+    });
+
+    methods.add_async_method("recv", |lua, this, ()| async move {
+      let outbox = this.outbox.clone();
+      drop(this);
+      match outbox.lock().await.recv().await {
+        Some(envelope) => envelope.message.decode(&lua),
+        None => Ok(mlua::Value::Nil),
+      }
+    });
+
+    // Sends every item as a job and gives the replies in the same order.
+    methods.add_async_method("map", |lua, this, items: Vec<mlua::Value>| async move {
+      let worker = this.clone();
+      drop(this);
+
+      let mut jobs = HashMap::new();
+      for (idx, item) in items.iter().enumerate() {
+        jobs.insert(worker.send(item)?, idx + 1);
+      }
+
+      let results = lua.create_table()?;
+      let mut outbox = worker.outbox.lock().await;
+      while !jobs.is_empty() {
+        let envelope = outbox.recv().await.ok_or_else(|| {
+          LuaError::RuntimeError(format!(
+            "Workers of '{}' exited with {} jobs unanswered",
+            worker.module,
+            jobs.len()
+          ))
+        })?;
+        if let Some(idx) = jobs.remove(&envelope.job) {
+          results.set(idx, envelope.message.decode(&lua)?)?;
+        }
+      }
+      Ok(results)
+    });
+
+    methods.add_method("close", |_, this, ()| {
+      this.inbox.lock().unwrap().take();
+      Ok(())
+    });
+
+    methods.add_async_method("join", |lua, this, ()| async move {
+      let worker = this.clone();
+      drop(this);
+      let mut values = worker.join().await?;
+      if worker.pool {
+        let results = lua.create_table()?;
+        for (idx, value) in values.into_iter().enumerate() {
+          results.set(idx + 1, value.decode(&lua)?)?;
+        }
+        Ok(mlua::Value::Table(results))
+      } else {
+        values.pop().unwrap_or_default().decode(&lua)
+      }
+    });
+  }
+}
+
+/// Adds `worker` and `pool` to the `threads` module.
+pub fn register(lua: &Lua, threads_mod: &mlua::Table) -> mlua::Result<()> {
+  threads_mod.set(
+    "worker",
+    lua.create_function(|lua, (name, args): (String, mlua::Value)| {
+      LuluWorker::start(lua, &name, 1, args, false)
+    })?,
+  )?;
+
+  threads_mod.set(
+    "pool",
+    lua.create_function(
+      |lua, (name, size, args): (String, Option<usize>, mlua::Value)| {
+        let size = size.unwrap_or_else(|| {
+          std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+        });
+        LuluWorker::start(lua, &name, size, args, true)
+      },
+    )?,
+  )?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_message_roundtrip() {
+    let parent = Lua::new();
+    let worker = Lua::new();
+    let arc = LuluArc::new_mutex(crate::lulibs::rust::LuluArcValue::Int64(1));
+    parent.globals().set("shared", arc).unwrap();
+
+    let value: mlua::Value = parent
+      .load("return { name = 'a', ok = true, list = { 1, 2.5, 'x' }, shared = shared }")
+      .eval()
+      .unwrap();
+    let message = WorkerMessage::encode(&value).unwrap();
+    worker.globals().set("msg", message.decode(&worker).unwrap()).unwrap();
+
+    let (name, ok, len, half): (String, bool, usize, f64) = worker
+      .load("msg.shared:set(2) return msg.name, msg.ok, #msg.list, msg.list[2]")
+      .eval()
+      .unwrap();
+    assert_eq!((name.as_str(), ok, len, half), ("a", true, 3, 2.5));
+    assert_eq!(parent.load("return shared:get()").eval::<i64>().unwrap(), 2);
+
+    // Keys other than strings keep their type.
+    let value: mlua::Value = parent
+      .load("return { [10] = 'x', [2.5] = 'y', [true] = 'z', name = 'n', [-1] = { 'deep' } }")
+      .eval()
+      .unwrap();
+    let message = WorkerMessage::encode(&value).unwrap();
+    worker.globals().set("msg", message.decode(&worker).unwrap()).unwrap();
+    let keys: bool = worker
+      .load(
+        "return msg[10] == 'x' and msg['10'] == nil and msg[2.5] == 'y' \
+         and msg[true] == 'z' and msg.name == 'n' and msg[-1][1] == 'deep'",
+      )
+      .eval()
+      .unwrap();
+    assert!(keys);
+
+    // A table seen twice is copied twice, one holding itself can't be sent.
+    let value: mlua::Value = parent
+      .load("local t = { 1 } return { a = t, b = t }")
+      .eval()
+      .unwrap();
+    assert!(WorkerMessage::encode(&value).is_ok());
+    let value: mlua::Value = parent
+      .load("local t = {} t.self = { t } return t")
+      .eval()
+      .unwrap();
+    let error = WorkerMessage::encode(&value).err().unwrap().to_string();
+    assert!(error.contains("Cannot send a cyclic table to a worker"));
+  }
+}
@@ -1,28 +1,52 @@
-use crate::bundle::{bundle_lulu_or_exec, load_lulib, run_bundle, set_exec_path};
-use crate::cli::{CacheCommand, Cli, Commands};
+use crate::bundle::{
+  BundleOptions, bundle_lulu_or_exec, load_lulib_trusted, run_bundle, set_exec_path,
+};
+use crate::build_sandbox::{ALLOW_BUILD_SCRIPTS_ENV, BUILD_DENIED_EXIT, BuildSandbox};
+use crate::cli::{CacheCommand, Cli, Commands, RegistryCommand};
 use crate::conf::load_lulu_conf;
 use crate::core::Lulu;
 use crate::ops::{TOK_ASYNC_HANDLES, core::register_consts};
 use crate::package_manager::PackageManager;
+use crate::target::{TARGET_ENV, Target};
 use clap::Parser;
 use mlua::Result;
 use mlua::prelude::LuaError;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 mod builders;
+mod build_sandbox;
+mod audit;
 mod bundle;
+mod bytecode;
+mod cache;
 mod cli;
 pub mod compiler;
 pub mod conf;
+mod conf_edit;
 pub mod core;
+mod hot;
+mod inspect;
+mod integrity;
 mod lml;
+mod lockfile;
 mod lulibs;
+mod minify;
 mod ops;
+mod outdated;
 mod package_manager;
+mod permissions;
+mod plugins;
+mod progress;
 mod project;
+mod registry;
 mod resolver;
+mod resources;
+mod signing;
+mod sourcemap;
+mod target;
+mod treeshake;
 mod util;
 
 macro_rules! into_exec_command {
@@ -38,8 +62,7 @@ macro_rules! into_exec_command {
       for (k, v) in map.iter() {
         cmd.env(k, v);
       }
-      cmd.status()?;
-      Ok(())
+      Ok(cmd.status()?.code())
     })?
   }};
 
@@ -56,8 +79,7 @@ macro_rules! into_exec_command {
       for (k, v) in map.iter() {
         cmd.env(k, v);
       }
-      cmd.status()?;
-      Ok(())
+      Ok(cmd.status()?.code())
     })?
   }};
 }
@@ -66,25 +88,32 @@ macro_rules! into_exec_command {
 async fn main() -> Result<()> {
   crate::ops::std::init_std_modules();
   if let Some(mods) = bundle::load_embedded_scripts() {
-    handle_error!(
-      run_bundle(
-        mods,
-        &mut Lulu::new(
-          Some(std::env::args().skip(1).collect()),
-          Some(std::env::current_exe()?.parent().unwrap().to_path_buf())
-        )
-      )
-      .await
+    let mut lulu = Lulu::new(
+      Some(std::env::args().skip(1).collect()),
+      Some(std::env::current_exe()?.parent().unwrap().to_path_buf()),
     );
+    let result = run_bundle(mods, &mut lulu).await;
+    handle_error!(result.map_err(|e| sourcemap::rewrite_error(e, &lulu.sourcemaps)));
   } else {
     let cli = Cli::parse();
+    if let Some(packages) = &cli.allow_build_scripts {
+      // SAFETY: no other thread reads the environment this early.
+      unsafe { std::env::set_var(ALLOW_BUILD_SCRIPTS_ENV, packages.join(",")) };
+    }
 
     match &cli.command {
-      Commands::Run { file, args, build } => {
+      Commands::Run {
+        file,
+        args,
+        build,
+        hot,
+        permissions,
+      } => {
+        let permissions = permissions.permissions();
         handle_error!(if *build {
           let lua = mlua::Lua::new();
           let conf = load_lulu_conf(&lua, file.join("lulu.conf.lua"))?;
-          let name = conf.manifest.unwrap().get::<String>("name")?;
+          let name = conf.manifest.clone().unwrap().get::<String>("name")?;
           std::process::Command::new(std::env::current_exe()?)
             .arg("build")
             .arg(file.clone())
@@ -96,36 +125,55 @@ async fn main() -> Result<()> {
           };
 
           if runpath.ends_with(".lulib") {
-            let mods = load_lulib(&runpath)?;
-            run_bundle(mods, &mut Lulu::new(Some(args.clone()), Some(runpath))).await?;
+            let mods = load_lulib_trusted(&runpath, &signing::TrustPolicy::from_conf(&conf))?;
+            let mut lulu = Lulu::new(Some(args.clone()), Some(runpath));
+            lulu.permissions = permissions;
+            run_bundle(mods, &mut lulu).await?;
           } else {
             std::process::Command::new(runpath).args(args).status()?;
           }
           Ok(())
         } else if file.extension().and_then(|s| s.to_str()) == Some("lulib") {
-          let mods = load_lulib(file)?;
-          run_bundle(
-            mods,
-            &mut Lulu::new(
-              Some(args.clone()),
-              Some(file.parent().unwrap().to_path_buf()),
-            ),
-          )
-          .await
+          let policy = match conf::find_lulu_conf(std::env::current_dir()?) {
+            Some(conf_path) => {
+              signing::TrustPolicy::from_conf(&load_lulu_conf(&mlua::Lua::new(), conf_path)?)
+            }
+            None => signing::TrustPolicy::from_keyring(),
+          };
+          let mods = load_lulib_trusted(file, &policy)?;
+          let mut lulu = Lulu::new(
+            Some(args.clone()),
+            Some(file.parent().unwrap().to_path_buf()),
+          );
+          lulu.permissions = permissions;
+          let result = run_bundle(mods, &mut lulu).await;
+          result.map_err(|e| sourcemap::rewrite_error(e, &lulu.sourcemaps))
         } else if file.is_dir() {
           let mut lulu = Lulu::new(Some(args.clone()), Some(file.to_path_buf()));
+          lulu.permissions = permissions;
           let filepath = if file.join("init.lua").exists() {
             file.join("init.lua")
           } else {
             file.join("main.lua")
           };
-          lulu.exec_entry_mod_path(filepath.clone()).await
+          let result = if *hot {
+            hot::exec_entry_mod_path(&mut lulu, filepath.clone()).await
+          } else {
+            lulu.exec_entry_mod_path(filepath.clone()).await
+          };
+          result.map_err(|e| sourcemap::rewrite_error(e, &lulu.sourcemaps))
         } else {
           let mut lulu = Lulu::new(
             Some(args.clone()),
             Some(file.parent().unwrap().to_path_buf()),
           );
-          lulu.exec_entry_mod_path(file.clone()).await
+          lulu.permissions = permissions;
+          let result = if *hot {
+            hot::exec_entry_mod_path(&mut lulu, file.clone()).await
+          } else {
+            lulu.exec_entry_mod_path(file.clone()).await
+          };
+          result.map_err(|e| sourcemap::rewrite_error(e, &lulu.sourcemaps))
         });
       }
       Commands::Compile { file } => {
@@ -142,20 +190,177 @@ async fn main() -> Result<()> {
         lulu.compiler.current_test = test.clone();
         handle_error!(lulu.exec_entry_mod_path(file.clone()).await);
       }
-      Commands::Bundle { file, output } => {
+      Commands::Bundle {
+        file,
+        output,
+        sign,
+        target,
+        compress,
+        keep,
+        release,
+        minify,
+        debug,
+      } => {
+        let target = match target.as_deref().map(Target::parse) {
+          Some(Ok(target)) => target,
+          Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+          None => Target::from_env(),
+        };
+
         let mut lulu = Lulu::new(None, None);
-        bundle_lulu_or_exec(&mut lulu, file.clone(), output.clone())?;
+        // Started by the `bundle` of a sandboxed build.
+        let sandbox = BuildSandbox::from_env();
+        let result = match &sandbox {
+          Some(sandbox) => sandbox
+            .check_write(output)
+            .and_then(|_| sandbox.apply_lulu(&lulu)),
+          None => Ok(()),
+        }
+        .and_then(|_| {
+          bundle_lulu_or_exec(
+            &mut lulu,
+            file.clone(),
+            output.clone(),
+            &BundleOptions {
+              sign: sign.clone(),
+              target: Some(target),
+              compress: *compress,
+              keep: keep.clone(),
+              release: *release,
+              minify: *minify,
+              debug: *debug,
+            },
+          )
+        });
+        if let (Err(e), Some(sandbox)) = (&result, &sandbox)
+          && sandbox.was_denied()
+        {
+          eprintln!("{}", e);
+          std::process::exit(BUILD_DENIED_EXIT);
+        }
+        result?;
+      }
+      Commands::Keygen { path } => match signing::generate_key(path) {
+        Ok(public_key) => {
+          println!("Private key written to {}", path.display());
+          println!("Public key: {}", public_key);
+        }
+        Err(e) => eprintln!("Failed to generate key: {}", e),
+      },
+      Commands::Inspect { file, extract } => {
+        let lua = mlua::Lua::new();
+        let inspection = match inspect::inspect(&lua, file) {
+          Ok(inspection) => inspection,
+          Err(e) => {
+            eprintln!("Failed to inspect {}: {}", file.display(), e);
+            std::process::exit(1);
+          }
+        };
+
+        println!(
+          "{}: bundle v{}, {} modules, {} resources, {}",
+          file.display(),
+          inspection.format_version,
+          inspection.modules.len(),
+          inspection.bundle.assets.len(),
+          cache::format_size(inspection.size as u64)
+        );
+        match &inspection.signature {
+          Some((signature, valid)) => println!(
+            "Signed by {}{}",
+            signing::encode_key(&signature.public_key),
+            if *valid { "" } else { " (signature does NOT match the contents)" }
+          ),
+          None => println!("Unsigned"),
+        }
+
+        for (idx, package) in inspection.packages.iter().enumerate() {
+          println!(
+            "\nPackage {} {}",
+            package.name().unwrap_or_else(|| format!("#{}", idx)),
+            package.version().unwrap_or_default()
+          );
+          if let Some(include) = &package.conf.include {
+            println!("  include: {}", include.join(", "));
+          }
+          for dep in package.conf.dependencies.iter().flatten() {
+            match &dep.sha256 {
+              Some(sha256) => println!("  dependency: {} (sha256 {})", dep.url, sha256),
+              None => println!("  dependency: {}", dep.url),
+            }
+          }
+        }
+
+        let name_w = inspection
+          .modules
+          .iter()
+          .map(|(name, _)| name.len())
+          .chain(inspection.bundle.assets.keys().map(String::len))
+          .max()
+          .unwrap_or(0)
+          .max(4);
+        println!("\n{:name_w$}  {:>10}  PACKAGE", "MODULE", "SIZE");
+        for (name, package) in &inspection.modules {
+          println!(
+            "{:name_w$}  {:>10}  {}",
+            name,
+            cache::format_size(inspection.bundle.mods[name].bytes.len() as u64),
+            package
+              .and_then(|idx| inspection.packages[idx].name())
+              .unwrap_or("-".to_string())
+          );
+        }
+
+        if !inspection.bundle.assets.is_empty() {
+          let mut assets: Vec<_> = inspection.bundle.assets.iter().collect();
+          assets.sort_by(|a, b| a.0.cmp(b.0));
+          println!("\n{:name_w$}  {:>10}", "RESOURCE", "SIZE");
+          for (name, bytes) in assets {
+            println!("{:name_w$}  {:>10}", name, cache::format_size(bytes.len() as u64));
+          }
+        }
+
+        if let Some(dir) = extract {
+          let written = inspect::extract(&inspection, dir)?;
+          println!("\nExtracted {} files to {}", written.len(), dir.display());
+        }
       }
-      Commands::Resolve { item } => {
-        let pkg_manager = PackageManager::new().map_err(|e| mlua::Error::external(e))?;
+      Commands::Resolve {
+        item,
+        offline,
+        jobs,
+      } => {
+        let is_source = item.starts_with("http")
+          || item.starts_with("github:")
+          || item.starts_with(registry::REGISTRY_PREFIX);
+        let mut pkg_manager = PackageManager::new()
+          .map_err(|e| mlua::Error::external(e))?
+          .with_registry(registry::registry_for_project(Path::new(if is_source {
+            "."
+          } else {
+            item.as_str()
+          })));
+        pkg_manager.offline |= *offline;
+        if let Some(jobs) = jobs {
+          pkg_manager.downloader.concurrency = *jobs;
+        }
 
         async {
-          if item.starts_with("http") || item.starts_with("github:") {
+          if is_source {
             let path = std::path::PathBuf::from(".");
 
-            match pkg_manager.install_package(item.as_str(), &path).await {
+            match pkg_manager
+              .install_package(&conf::Dependency::from(item.as_str()), &path)
+              .await
+            {
               Ok(_) => {}
-              Err(e) => eprintln!("Failed to resolve dependency \"{}\": {}", item, e),
+              Err(e) => {
+                eprintln!("Failed to resolve dependency \"{}\": {:#}", item, e);
+                std::process::exit(1);
+              }
             };
           } else {
             let path = std::path::PathBuf::from(item);
@@ -174,7 +379,10 @@ async fn main() -> Result<()> {
                   .await
                 {
                   Ok(_) => {}
-                  Err(e) => eprintln!("Failed to resolve dependencies: {}", e),
+                  Err(e) => {
+                    eprintln!("Failed to resolve dependencies: {}", e);
+                    std::process::exit(1);
+                  }
                 }
               } else {
                 eprintln!("No dependencies found in {}", conf_path.display());
@@ -186,7 +394,40 @@ async fn main() -> Result<()> {
         }
         .await;
       }
-      Commands::Build { path } => {
+      Commands::Vendor { path, offline } => {
+        let conf_path = path.join("lulu.conf.lua");
+        let Ok(conf_string) = std::fs::read_to_string(&conf_path) else {
+          eprintln!("Could not read configuration file: {}", conf_path.display());
+          std::process::exit(1);
+        };
+
+        let lua = mlua::Lua::new();
+        let dependencies =
+          conf::load_lulu_conf_dependiencies(&lua, conf_string)?.unwrap_or_default();
+        if dependencies.is_empty() {
+          println!("No dependencies to vendor in {}", conf_path.display());
+          return Ok(());
+        }
+
+        let mut pkg_manager = PackageManager::new()
+          .map_err(mlua::Error::external)?
+          .with_registry(registry::registry_for_project(path));
+        pkg_manager.offline |= *offline;
+
+        match pkg_manager.vendor_packages(&dependencies, path).await {
+          Ok(packages) => {
+            for package in packages {
+              println!("  vendored {} ({})", package.name, package.url);
+            }
+            println!("Vendored dependencies are recorded in {}", lockfile::LOCKFILE);
+          }
+          Err(e) => {
+            eprintln!("Failed to vendor dependencies: {:#}", e);
+            std::process::exit(1);
+          }
+        }
+      }
+      Commands::Build { path, target } => {
         let conf_path = path.join("lulu.conf.lua");
         crate::builders::register_default_builders();
 
@@ -195,26 +436,67 @@ async fn main() -> Result<()> {
           return Ok(());
         }
 
+        let target = match target.as_deref().map(Target::parse) {
+          Some(Ok(target)) => target,
+          Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+          None => Target::from_env(),
+        };
+
         let conf_string = std::fs::read_to_string(conf_path.clone())?;
         let lua = mlua::Lua::new();
 
         register_consts(&lua)?;
+        lua.globals().set("TARGET_OS", target.os.clone())?;
+        lua.globals().set("TARGET_ARCH", target.arch.clone())?;
+        lua.globals().set("TARGET_FAMILY", target.family())?;
         crate::util::create_lib_folders(&path)?;
 
+        let sandbox = BuildSandbox::from_env();
+        if let Some(sandbox) = &sandbox {
+          sandbox.apply(&lua)?;
+        }
+
         if let Some(build_fn_lua) = conf::load_lulu_conf_builder(&lua, conf_string.clone())? {
           let main = conf::load_lulu_conf_code(&lua, conf::CodeType::Code(conf_string))?;
+          if let Some(sandbox) = &sandbox
+            && main
+              .build_permissions
+              .as_ref()
+              .is_some_and(|p| p.get("net").copied().unwrap_or(false))
+          {
+            sandbox.allow_net();
+          }
           let name = main
             .manifest
             .unwrap_or(lua.create_table()?)
             .get::<String>("name")?;
 
           let env = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+          if let Some(sandbox) = &sandbox {
+            env.lock().unwrap().extend(sandbox.child_env());
+          }
+          if !target.is_host() {
+            env
+              .lock()
+              .unwrap()
+              .insert(TARGET_ENV.to_string(), target.to_string());
+          }
           let lulu_arc = Arc::new(Mutex::new(Lulu::new(None, None)));
+          if let Some(sandbox) = &sandbox {
+            sandbox.apply_lulu(&lulu_arc.lock().unwrap())?;
+          }
 
           let env_ref = env.clone();
+          let sb = sandbox.clone();
           lua.globals().set(
             "set_env",
             lua.create_function(move |_, (name, value): (String, String)| {
+              if let Some(sandbox) = &sb {
+                sandbox.check_env(&name)?;
+              }
               let mut map = env_ref.lock().unwrap();
               map.insert(name, value);
               Ok(())
@@ -223,6 +505,18 @@ async fn main() -> Result<()> {
 
           let bundle_path = path.clone();
           let bundle = into_exec_command!(lua, env, (file: String, output: String), "bundle", bundle_path.clone().join(file), bundle_path.join(output));
+          let bundle = match &sandbox {
+            Some(sandbox) => {
+              let sandbox = sandbox.clone();
+              let bundle_path = path.clone();
+              lua.create_function(move |_, (file, output): (String, String)| {
+                sandbox.check_write(bundle_path.join(&output))?;
+                let code = bundle.call::<Option<i32>>((file.clone(), output))?;
+                sandbox.check_child(&format!("Bundling {}", file), code)
+              })?
+            }
+            None => bundle,
+          };
 
           // let bname = name.clone();
           // let bundle_main_path = path.clone();
@@ -251,9 +545,27 @@ async fn main() -> Result<()> {
 
           let ipath = path.clone();
           let larc = lulu_arc.clone();
+          lua.globals().set(
+            "include_asset",
+            lua.create_function(move |_, (name, file): (String, String)| {
+              let file_path = ipath.join(file);
+              let mut lulu = larc.lock().unwrap();
+              for (asset, bytes) in crate::util::read_files_recursively(&file_path, &name)? {
+                lulu.assets.insert(asset, bytes);
+              }
+              Ok(())
+            })?,
+          )?;
+
+          let ipath = path.clone();
+          let larc = lulu_arc.clone();
+          let sb = sandbox.clone();
           lua.globals().set(
             "execute_file",
             lua.create_function(move |_, file: String| {
+              if let Some(sandbox) = &sb {
+                return Err(sandbox.deny("execute_file"));
+              }
               let file_path = ipath.join(file.clone());
               let code = std::fs::read(file_path)?;
               let lulu = larc.lock().unwrap();
@@ -262,21 +574,28 @@ async fn main() -> Result<()> {
             })?,
           )?;
 
+          let sb = sandbox.clone();
           lua.globals().set(
             "download_file_async",
-            lua.create_async_function(async move |_, url: String| {
-              PackageManager::new()
-                .map_err(|e| {
-                  eprintln!("Failed to initialize package manager: {}", e);
-                  mlua::Error::external(e)
-                })?
-                .clone()
-                .download_file(&url)
-                .await
-                .map_err(|e| {
-                  eprintln!("Failed to download file: {}", e);
-                  mlua::Error::external(e)
-                })
+            lua.create_async_function(move |_, url: String| {
+              let sb = sb.clone();
+              async move {
+                if let Some(sandbox) = &sb {
+                  sandbox.check_net("download_file")?;
+                }
+                PackageManager::new()
+                  .map_err(|e| {
+                    eprintln!("Failed to initialize package manager: {}", e);
+                    mlua::Error::external(e)
+                  })?
+                  .clone()
+                  .download_file(&url)
+                  .await
+                  .map_err(|e| {
+                    eprintln!("Failed to download file: {}", e);
+                    mlua::Error::external(e)
+                  })
+              }
             })?,
           )?;
 
@@ -297,22 +616,38 @@ async fn main() -> Result<()> {
           )?;
 
           let bw_path = path.clone();
+          let sb = sandbox.clone();
           lua.globals().set(
             "build_with",
             lua.create_function(
               move |_, (builder, path, args): (String, String, Option<Vec<String>>)| {
+                if let Some(sandbox) = &sb {
+                  return Err(sandbox.deny(&format!("build_with(\"{}\")", builder)));
+                }
                 let path = bw_path.join(path);
-                crate::builders::build_path(builder, path, args.unwrap_or(Vec::new()))?;
+                let dylibs =
+                  crate::builders::build_path(builder, path, args.unwrap_or(Vec::new()))?;
+                if !dylibs.is_empty() {
+                  let lib_dir = bw_path.join(".lib/dylib");
+                  std::fs::create_dir_all(&lib_dir)?;
+                  for dylib in dylibs {
+                    std::fs::copy(&dylib, lib_dir.join(dylib.file_name().unwrap()))?;
+                  }
+                }
                 Ok(())
               },
             )?,
           )?;
 
           let exec_path = path.clone();
+          let sb = sandbox.clone();
           lua.globals().set(
             "exec_command",
             lua.create_function(
               move |_, (command, args, path): (String, Vec<String>, Option<String>)| {
+                if let Some(sandbox) = &sb {
+                  return Err(sandbox.deny(&format!("exec_command(\"{}\")", command)));
+                }
                 let exec_path = if let Some(path) = path {
                   exec_path.join(path)
                 } else {
@@ -376,11 +711,15 @@ async fn main() -> Result<()> {
           )?;
 
           let copy_path = path.clone();
+          let sb = sandbox.clone();
           lua.globals().set(
             "copy_all",
             lua.create_function(move |_, (file, dest): (String, String)| {
               let path = copy_path.join(file);
               let dest = copy_path.join(dest);
+              if let Some(sandbox) = &sb {
+                sandbox.check_write(&dest)?;
+              }
 
               crate::util::copy_recursively(path, dest).map_err(mlua::Error::external)?;
 
@@ -389,23 +728,13 @@ async fn main() -> Result<()> {
           )?;
 
           let collect_path = path.clone();
+          let lib_target = target.clone();
           lua.globals().set(
             "collect_libs",
             lua.create_function(move |_, files: HashMap<String, Vec<String>>| {
-              let current_os = std::env::consts::OS;
-
-              let libs = if let Some(url) = files.get(current_os) {
-                Ok(url)
-              } else if let Some(url) =
-                files.get(&format!("{}-{}", current_os, std::env::consts::ARCH))
-              {
-                Ok(url)
-              } else {
-                Err(mlua::Error::external(format!(
-                  "No lib found for OS: {}",
-                  current_os
-                )))
-              }?;
+              let libs = lib_target.pick(&files).ok_or_else(|| {
+                mlua::Error::external(format!("No lib found for target: {}", lib_target))
+              })?;
 
               for file in libs.iter() {
                 let path = collect_path.join(file);
@@ -429,58 +758,56 @@ async fn main() -> Result<()> {
             })?,
           )?;
 
+          let sb = sandbox.clone();
+          let stub_target = target.clone();
           let stubs_fn =
-            lua.create_async_function(async move |_, stubs: HashMap<String, String>| {
-              let current_os = std::env::consts::OS;
+            lua.create_async_function(move |_, stubs: HashMap<String, String>| {
+              let sb = sb.clone();
+              let stub_target = stub_target.clone();
+              async move {
+                let url = stub_target.pick(&stubs).ok_or_else(|| {
+                  mlua::Error::external(format!("No stub found for target: {}", stub_target))
+                })?;
 
-              let url = if let Some(url) = stubs.get(current_os) {
-                Ok(url)
-              } else if let Some(url) =
-                stubs.get(&format!("{}-{}", current_os, std::env::consts::ARCH))
-              {
-                Ok(url)
-              } else {
-                Err(mlua::Error::external(format!(
-                  "No stub found for OS: {}",
-                  current_os
-                )))
-              }?;
-
-              let path = if url.starts_with("http") {
-                let cache_path = PackageManager::new()
-                  .map_err(|e| {
-                    eprintln!("Failed to initialize package manager: {}", e);
-                    mlua::Error::external(e)
-                  })?
-                  .download_file(url)
-                  .await
-                  .map_err(|e| {
-                    eprintln!("Failed to download file: {}", e);
-                    mlua::Error::external(e)
-                  })?;
+                let path = if url.starts_with("http") {
+                  if let Some(sandbox) = &sb {
+                    sandbox.check_net("stubs")?;
+                  }
+                  let cache_path = PackageManager::new()
+                    .map_err(|e| {
+                      eprintln!("Failed to initialize package manager: {}", e);
+                      mlua::Error::external(e)
+                    })?
+                    .download_file(url)
+                    .await
+                    .map_err(|e| {
+                      eprintln!("Failed to download file: {}", e);
+                      mlua::Error::external(e)
+                    })?;
 
-                let file_name = url
-                  .split('/')
-                  .last()
-                  .ok_or_else(|| mlua::Error::external("Invalid URL: missing file name"))?;
+                  let file_name = url
+                    .split('/')
+                    .last()
+                    .ok_or_else(|| mlua::Error::external("Invalid URL: missing file name"))?;
 
-                let file_path = cache_path.join(file_name);
+                  let file_path = cache_path.join(file_name);
 
-                #[cfg(unix)]
-                {
-                  use std::os::unix::fs::PermissionsExt;
-                  let mut perms = std::fs::metadata(file_path.clone())?.permissions();
-                  perms.set_mode(perms.mode() | 0o111);
-                  std::fs::set_permissions(file_path.clone(), perms)?;
-                }
+                  #[cfg(unix)]
+                  {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mut perms = std::fs::metadata(file_path.clone())?.permissions();
+                    perms.set_mode(perms.mode() | 0o111);
+                    std::fs::set_permissions(file_path.clone(), perms)?;
+                  }
 
-                file_path
-              } else {
-                Path::new(url).to_path_buf()
-              };
+                  file_path
+                } else {
+                  Path::new(url).to_path_buf()
+                };
 
-              set_exec_path(path);
-              Ok(())
+                set_exec_path(path);
+                Ok(())
+              }
             })?;
 
           lua.globals().set("stubs_async", stubs_fn)?;
@@ -514,26 +841,46 @@ async fn main() -> Result<()> {
           let bname = name.clone();
           let bundle_main_path = path.clone();
           let larc = lulu_arc.clone();
+          let sb = sandbox.clone();
+          let bundle_target = target.clone();
           lua.globals().set(
             "bundle_main",
-            lua.create_function(move |_, (file, lulib): (String, Option<bool>)| {
-              let is_lulib = if let Some(lulib) = lulib {
-                lulib
-              } else {
-                false
-              };
-              let mut lulu = larc.lock().unwrap();
-              bundle_lulu_or_exec(
-                &mut lulu,
-                bundle_main_path.join(file).to_path_buf(),
-                Path::new(&format!(
-                  ".lib/{}{}",
-                  bname.clone(),
-                  if is_lulib { ".lulib" } else { "" }
-                ))
-                .to_path_buf(),
-              )
-            })?,
+            lua.create_function(
+              move |lua, (file, lulib, options): (String, Option<bool>, Option<mlua::Table>)| {
+                let is_lulib = if let Some(lulib) = lulib {
+                  lulib
+                } else {
+                  false
+                };
+                let options = options.unwrap_or(lua.create_table()?);
+                let flag = |name: &str| -> Result<bool> {
+                  Ok(options.get::<Option<bool>>(name)?.unwrap_or(false))
+                };
+                if let Some(sandbox) = &sb {
+                  sandbox.check_write(bundle_main_path.join(".lib").join(&bname))?;
+                }
+                let mut lulu = larc.lock().unwrap();
+                bundle_lulu_or_exec(
+                  &mut lulu,
+                  bundle_main_path.join(file).to_path_buf(),
+                  Path::new(&format!(
+                    ".lib/{}{}",
+                    bname.clone(),
+                    if is_lulib { ".lulib" } else { "" }
+                  ))
+                  .to_path_buf(),
+                  &BundleOptions {
+                    sign: std::env::var("LULU_SIGNING_KEY").ok().map(PathBuf::from),
+                    target: Some(bundle_target.clone()),
+                    compress: flag("compress")?,
+                    keep: options.get::<Option<Vec<String>>>("keep")?.unwrap_or_default(),
+                    release: flag("release")?,
+                    minify: flag("minify")?,
+                    debug: flag("debug")?,
+                  },
+                )
+              },
+            )?,
           )?;
 
           let build_path = path.clone();
@@ -544,6 +891,27 @@ async fn main() -> Result<()> {
           let resolve_dependencies =
             into_exec_command!(lua, env, (), "resolve", resolve_path.clone());
 
+          let (build, resolve_dependencies) = match &sandbox {
+            Some(sandbox) => {
+              let build_sandbox = sandbox.clone();
+              let build_path = path.clone();
+              let build = lua.create_function(move |_, file: String| {
+                build_sandbox.check_write(build_path.join(&file))?;
+                let code = build.call::<Option<i32>>(file.clone())?;
+                build_sandbox.check_child(&format!("Building {}", file), code)
+              })?;
+
+              let resolve_sandbox = sandbox.clone();
+              let resolve_dependencies = lua.create_function(move |_, ()| {
+                resolve_sandbox.check_net("resolve_dependencies")?;
+                resolve_dependencies.call::<()>(())
+              })?;
+
+              (build, resolve_dependencies)
+            }
+            None => (build, resolve_dependencies),
+          };
+
           let exists_path = path.clone();
           let exists_func =
             lua.create_function(move |_, name: String| Ok(exists_path.join(name).exists()))?;
@@ -560,14 +928,23 @@ async fn main() -> Result<()> {
           lua.globals().set("build", build)?;
           lua.globals().set("exists", exists_func)?;
 
-          handle_error!(build_fn_lua.call::<()>(()));
+          let result = build_fn_lua.call::<()>(());
+          if let (Err(e), Some(sandbox)) = (&result, &sandbox)
+            && sandbox.was_denied()
+          {
+            eprintln!("{}", e);
+            std::process::exit(BUILD_DENIED_EXIT);
+          }
+          handle_error!(result);
         }
       }
       Commands::Update { packages, project } => {
-        let pkg_manager = PackageManager::new().map_err(|e| {
-          eprintln!("Failed to initialize package manager: {}", e);
-          mlua::Error::external(e)
-        })?;
+        let pkg_manager = PackageManager::new()
+          .map_err(|e| {
+            eprintln!("Failed to initialize package manager: {}", e);
+            mlua::Error::external(e)
+          })?
+          .with_registry(registry::registry_for_project(project));
 
         async {
           for package in packages {
@@ -576,15 +953,353 @@ async fn main() -> Result<()> {
             }
           }
 
-          match pkg_manager.install_packages(packages, project).await {
+          // Packages keep the sha256 pins of the project's dependencies.
+          let pinned = std::fs::read_to_string(project.join("lulu.conf.lua"))
+            .ok()
+            .and_then(|conf_string| {
+              conf::load_lulu_conf_dependiencies(&mlua::Lua::new(), conf_string).ok()
+            })
+            .flatten()
+            .unwrap_or_default();
+          let dependencies: Vec<conf::Dependency> = packages
+            .iter()
+            .map(|p| {
+              pinned
+                .iter()
+                .find(|dependency| dependency.url == *p)
+                .cloned()
+                .unwrap_or_else(|| conf::Dependency::from(p.as_str()))
+            })
+            .collect();
+
+          match pkg_manager.install_packages(&dependencies, project).await {
             Ok(_) => {}
             Err(e) => {
               eprintln!("Package update failed: {}", e);
+              std::process::exit(1);
             }
           }
         }
         .await;
       }
+      Commands::Add {
+        packages,
+        sha256,
+        project,
+      } => {
+        let conf_path = project.join("lulu.conf.lua");
+        let Ok(mut conf_string) = std::fs::read_to_string(&conf_path) else {
+          eprintln!("Could not read configuration file: {}", conf_path.display());
+          std::process::exit(1);
+        };
+
+        if sha256.is_some() && packages.len() > 1 {
+          eprintln!("--sha256 can only be used when adding a single package");
+          std::process::exit(1);
+        }
+
+        let existing = conf_edit::table_values(&conf_string, "dependencies");
+        let mut added = Vec::new();
+        for package in packages {
+          if existing.contains(package) {
+            println!("{} is already a dependency", package);
+            continue;
+          }
+
+          let entry = match sha256 {
+            Some(digest) => format!("{{ {:?}, sha256 = {:?} }}", package, digest),
+            None => format!("{:?}", package),
+          };
+          conf_string = conf_edit::add_entry(&conf_string, "dependencies", &entry);
+          added.push(conf::Dependency {
+            url: package.clone(),
+            sha256: sha256.clone(),
+          });
+        }
+
+        if added.is_empty() {
+          return Ok(());
+        }
+
+        let pkg_manager = PackageManager::new()
+          .map_err(mlua::Error::external)?
+          .with_registry(registry::registry_for_project(project));
+        let installed = match pkg_manager.install_packages(&added, project).await {
+          Ok(installed) => installed,
+          Err(e) => {
+            eprintln!("Failed to install packages: {}", e);
+            std::process::exit(1);
+          }
+        };
+
+        let includes = conf_edit::table_values(&conf_string, "include");
+        for dependency in &added {
+          let Some(package) = installed.iter().find(|p| p.url == dependency.url) else {
+            conf_string =
+              conf_edit::remove_entries(&conf_string, "dependencies", |v| v == dependency.url).0;
+            eprintln!("{} was not added", dependency.url);
+            continue;
+          };
+
+          let include = format!("@{}", package.name);
+          let lulib = project
+            .join(".lib/lulib")
+            .join(format!("{}.lulib", package.name));
+          if lulib.exists() && !includes.contains(&include) {
+            conf_string = conf_edit::add_entry(&conf_string, "include", &format!("{:?}", include));
+          }
+          println!("Added {} ({})", package.name, package.url);
+        }
+
+        std::fs::write(&conf_path, conf_string)?;
+      }
+      Commands::Remove { packages, project } => {
+        let conf_path = project.join("lulu.conf.lua");
+        let Ok(mut conf_string) = std::fs::read_to_string(&conf_path) else {
+          eprintln!("Could not read configuration file: {}", conf_path.display());
+          std::process::exit(1);
+        };
+
+        let lockfile = lockfile::Lockfile::load(project).map_err(mlua::Error::external)?;
+        let pkg_manager = PackageManager::new().map_err(mlua::Error::external)?;
+
+        for package in packages {
+          let locked = lockfile
+            .packages
+            .iter()
+            .find(|p| &p.name == package || &p.url == package);
+          let url = locked.map(|p| p.url.clone()).unwrap_or(package.clone());
+          let name = locked.map(|p| p.name.clone()).unwrap_or(package.clone());
+
+          let (updated, count) =
+            conf_edit::remove_entries(&conf_string, "dependencies", |v| v == url);
+          if count == 0 {
+            eprintln!("{} is not a dependency of {}", package, conf_path.display());
+            continue;
+          }
+          conf_string = updated;
+          conf_string = conf_edit::remove_entries(&conf_string, "include", |v| {
+            v == format!("@{}", name)
+          })
+          .0;
+
+          match pkg_manager.remove_package_artifacts(project, &url, &name) {
+            Ok(removed) => {
+              for file in removed {
+                println!("  removed {}", file);
+              }
+              println!("Removed {} ({})", name, url);
+            }
+            Err(e) => eprintln!("Failed to clean up {}: {}", name, e),
+          }
+        }
+
+        std::fs::write(&conf_path, conf_string)?;
+      }
+      Commands::Outdated { path } => {
+        let lockfile = lockfile::Lockfile::load(path).map_err(mlua::Error::external)?;
+        if lockfile.packages.is_empty() {
+          println!(
+            "No resolved packages in {}, run `lulu resolve` first",
+            path.join(lockfile::LOCKFILE).display()
+          );
+          return Ok(());
+        }
+
+        let registry = registry::registry_for_project(path);
+        let mut rows = vec![(
+          "Package".to_string(),
+          "Current".to_string(),
+          "Latest".to_string(),
+          String::new(),
+        )];
+        for package in &lockfile.packages {
+          match outdated::check_package(package, registry.as_deref()).await {
+            Ok(entry) => rows.push((
+              entry.name,
+              entry.current,
+              entry.latest.unwrap_or("-".to_string()),
+              if entry.outdated { "outdated" } else { "" }.to_string(),
+            )),
+            Err(e) => rows.push((
+              package.name.clone(),
+              "-".to_string(),
+              "-".to_string(),
+              format!("error: {}", e),
+            )),
+          }
+        }
+
+        let width = |f: fn(&(String, String, String, String)) -> &String| {
+          rows.iter().map(|r| f(r).len()).max().unwrap_or(0)
+        };
+        let (name_w, current_w, latest_w) = (width(|r| &r.0), width(|r| &r.1), width(|r| &r.2));
+        for (name, current, latest, status) in &rows {
+          let line = format!(
+            "{:name_w$}  {:current_w$}  {:latest_w$}  {}",
+            name, current, latest, status
+          );
+          println!("{}", line.trim_end());
+        }
+      }
+      Commands::Audit { path, db } => {
+        let Some(db_path) = db.clone().or_else(audit::default_db_path) else {
+          eprintln!("No advisory database, pass --db or set {}", audit::ADVISORY_DB_ENV);
+          std::process::exit(1);
+        };
+
+        let advisories = match audit::load_advisories(&db_path) {
+          Ok(advisories) => advisories,
+          Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+          }
+        };
+        let lockfile = lockfile::Lockfile::load(path).map_err(mlua::Error::external)?;
+        let findings = audit::audit(&lockfile, &advisories);
+
+        if findings.is_empty() {
+          println!(
+            "No known advisories for {} packages ({} advisories checked)",
+            lockfile.packages.len(),
+            advisories.len()
+          );
+        } else {
+          for (package, advisory) in &findings {
+            eprintln!(
+              "  {} {} {}",
+              advisory.id.as_deref().unwrap_or("ADVISORY"),
+              package.name,
+              package
+                .version
+                .as_deref()
+                .or(package.commit.as_deref())
+                .unwrap_or("")
+            );
+            eprintln!("    {}", advisory.description);
+            if let Some(url) = &advisory.url {
+              eprintln!("    {}", url);
+            }
+          }
+          let vulnerable: HashSet<&str> = findings
+            .iter()
+            .map(|(package, _)| package.name.as_str())
+            .collect();
+          eprintln!(
+            "{} vulnerable packages found ({} advisories matched)",
+            vulnerable.len(),
+            findings.len()
+          );
+          std::process::exit(1);
+        }
+      }
+      Commands::Verify { path } => match integrity::verify_lib_dir(path) {
+        Ok(report) => {
+          if report.entries.is_empty() {
+            println!("Nothing to verify in {}", path.join(".lib").display());
+          }
+
+          for (file, status) in &report.entries {
+            match status {
+              integrity::FileStatus::Ok => println!("  ok        {}", file),
+              integrity::FileStatus::Untracked => println!("  untracked {}", file),
+              integrity::FileStatus::Missing => eprintln!("  missing   {}", file),
+              integrity::FileStatus::Mismatch { expected, actual } => eprintln!(
+                "  MISMATCH  {} (expected sha256 {}, got {})",
+                file, expected, actual
+              ),
+              integrity::FileStatus::Corrupt(e) => eprintln!("  CORRUPT   {} ({})", file, e),
+            }
+          }
+
+          if !report.is_ok() {
+            eprintln!("Verification failed");
+            std::process::exit(1);
+          }
+        }
+        Err(e) => {
+          eprintln!("Failed to verify {}: {}", path.display(), e);
+          std::process::exit(1);
+        }
+      },
+      Commands::Publish {
+        path,
+        registry,
+        token,
+      } => {
+        let lua = mlua::Lua::new();
+        let conf = load_lulu_conf(&lua, path.join("lulu.conf.lua"))?;
+        let manifest = conf
+          .manifest
+          .ok_or_else(|| mlua::Error::RuntimeError("lulu.conf.lua has no manifest".into()))?;
+        let name = manifest.get::<String>("name")?;
+        let version = manifest.get::<String>("version")?;
+
+        let Some(registry_url) = registry
+          .as_deref()
+          .map(|r| r.trim_end_matches('/').to_string())
+          .or_else(|| registry::registry_for_project(path))
+        else {
+          eprintln!(
+            "No registry to publish to, pass --registry or set `registry` in lulu.conf.lua"
+          );
+          std::process::exit(1);
+        };
+
+        let pkg_manager = PackageManager::new().map_err(mlua::Error::external)?;
+        if let Err(e) = pkg_manager.build_package(path).await {
+          eprintln!("Failed to build {}: {}", name, e);
+          std::process::exit(1);
+        }
+
+        let artifact = path.join(".lib").join(format!("{}.lulib", name));
+        if !artifact.exists() {
+          eprintln!(
+            "Nothing to publish: the build did not produce {}",
+            artifact.display()
+          );
+          std::process::exit(1);
+        }
+
+        let token = token
+          .clone()
+          .or_else(|| std::env::var(registry::REGISTRY_TOKEN_ENV).ok());
+        match registry::publish(&registry_url, &name, &version, &artifact, token.as_deref()).await {
+          Ok(()) => println!("Published {}@{} to {}", name, version, registry_url),
+          Err(e) => {
+            eprintln!("Failed to publish: {}", e);
+            std::process::exit(1);
+          }
+        }
+      }
+      Commands::Registry { registry_command } => match registry_command {
+        RegistryCommand::Serve {
+          dir,
+          addr,
+          token,
+          allow_anonymous_publish,
+          max_upload_size,
+        } => {
+          let max_upload_size = match cache::parse_size(max_upload_size) {
+            Ok(size) => size as usize,
+            Err(e) => {
+              eprintln!("{}", e);
+              std::process::exit(1);
+            }
+          };
+          let options = registry::ServeOptions {
+            token: token
+              .clone()
+              .or_else(|| std::env::var(registry::REGISTRY_TOKEN_ENV).ok()),
+            allow_anonymous_publish: *allow_anonymous_publish,
+            max_upload_size,
+          };
+          if let Err(e) = registry::serve(dir.clone(), addr, options).await {
+            eprintln!("Registry failed: {}", e);
+            std::process::exit(1);
+          }
+        }
+      },
       Commands::New {
         name,
         git,
@@ -604,19 +1319,109 @@ async fn main() -> Result<()> {
             Ok(()) => println!("Package cache cleared successfully"),
             Err(e) => eprintln!("Failed to clear cache: {}", e),
           },
-          CacheCommand::List => match pkg_manager.list_cached_packages() {
-            Ok(packages) => {
-              if packages.is_empty() {
+          CacheCommand::List => match pkg_manager.cache_entries() {
+            Ok(entries) => {
+              if entries.is_empty() {
                 println!("No cached packages found");
               } else {
-                println!("Cached packages:");
-                for package in packages {
-                  println!("  - {}", package);
+                let mut rows = vec![[
+                  "NAME".to_string(),
+                  "VERSION".to_string(),
+                  "SIZE".to_string(),
+                  "FETCHED".to_string(),
+                  "SOURCE".to_string(),
+                ]];
+                let mut total = 0;
+                for entry in &entries {
+                  total += entry.meta.size;
+                  rows.push([
+                    entry.meta.name.clone().unwrap_or(entry.key.clone()),
+                    entry.meta.version.clone().unwrap_or("-".to_string()),
+                    cache::format_size(entry.meta.size),
+                    cache::format_age(entry.meta.fetched_at),
+                    if entry.meta.url.is_empty() {
+                      "unknown".to_string()
+                    } else {
+                      entry.meta.url.clone()
+                    },
+                  ]);
                 }
+
+                let widths: Vec<usize> = (0..4)
+                  .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap_or(0))
+                  .collect();
+                for [name, version, size, fetched, source] in &rows {
+                  println!(
+                    "{:w0$}  {:w1$}  {:>w2$}  {:w3$}  {}",
+                    name,
+                    version,
+                    size,
+                    fetched,
+                    source,
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2],
+                    w3 = widths[3],
+                  );
+                }
+                println!(
+                  "\n{} entries, {}",
+                  entries.len(),
+                  cache::format_size(total)
+                );
               }
             }
             Err(e) => eprintln!("Failed to list cached packages: {}", e),
           },
+          CacheCommand::Prune {
+            older_than,
+            max_size,
+            dry_run,
+          } => {
+            if older_than.is_none() && max_size.is_none() {
+              eprintln!("Pass --older-than and/or --max-size");
+              std::process::exit(1);
+            }
+
+            let limits = older_than
+              .as_deref()
+              .map(cache::parse_duration)
+              .transpose()
+              .and_then(|older_than| {
+                Ok((older_than, max_size.as_deref().map(cache::parse_size).transpose()?))
+              });
+            let (older_than, max_size) = match limits {
+              Ok(limits) => limits,
+              Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+              }
+            };
+
+            match pkg_manager.prune_cache(older_than, max_size, *dry_run) {
+              Ok(pruned) => {
+                let freed: u64 = pruned.iter().map(|e| e.meta.size).sum();
+                for entry in &pruned {
+                  println!(
+                    "{} {} ({})",
+                    if *dry_run { "Would remove" } else { "Removed" },
+                    entry.meta.name.as_deref().unwrap_or(&entry.key),
+                    cache::format_size(entry.meta.size)
+                  );
+                }
+                println!(
+                  "{} {} entries, {}",
+                  if *dry_run { "Would free" } else { "Freed" },
+                  pruned.len(),
+                  cache::format_size(freed)
+                );
+              }
+              Err(e) => {
+                eprintln!("Failed to prune cache: {}", e);
+                std::process::exit(1);
+              }
+            }
+          }
           CacheCommand::Remove { package_url } => {
             match pkg_manager.clear_package_cache(package_url) {
               Ok(()) => println!("Package cache cleared for: {}", package_url),
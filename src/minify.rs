@@ -0,0 +1,137 @@
+//! Shrinks the Lua code of modules for `lulu bundle --release --minify` by
+//! dropping comments and redundant whitespace. Line breaks are kept so line
+//! numbers in errors and source maps stay valid.
+
+fn is_word(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+/// Whether removing the space between `prev` and `next` could merge two
+/// tokens, as in `a b`, `- -x`, `1 ..x` or `t[ [[s]] ]`.
+fn needs_space(prev: char, next: char) -> bool {
+  (is_word(prev) || prev == '.') && (is_word(next) || next == '.')
+    || (prev == '-' && next == '-')
+    || (prev == '[' && (next == '[' || next == '='))
+    || ((prev == '~' || prev == '<' || prev == '>' || prev == '=') && next == '=')
+}
+
+/// The level of a long bracket opening at `chars[i]` (`[[` is 0, `[==[` is
+/// 2), or `None` when it is not one.
+fn long_bracket(chars: &[char], i: usize) -> Option<usize> {
+  if chars.get(i) != Some(&'[') {
+    return None;
+  }
+  let mut level = 0;
+  while chars.get(i + 1 + level) == Some(&'=') {
+    level += 1;
+  }
+  (chars.get(i + 1 + level) == Some(&'[')).then_some(level)
+}
+
+/// The index just past the long bracket of `level` closing after `start`.
+fn long_bracket_end(chars: &[char], start: usize, level: usize) -> usize {
+  let mut i = start;
+  while i < chars.len() {
+    if chars[i] == ']'
+      && chars[i + 1..].iter().take(level).all(|c| *c == '=')
+      && chars.get(i + 1 + level) == Some(&']')
+    {
+      return i + 2 + level;
+    }
+    i += 1;
+  }
+  chars.len()
+}
+
+pub fn minify(code: &str) -> String {
+  let chars: Vec<char> = code.chars().collect();
+  let mut out = String::with_capacity(code.len());
+  let mut pending_space = false;
+  let mut i = 0;
+
+  let push = |out: &mut String, pending_space: &mut bool, text: &[char]| {
+    if *pending_space
+      && let (Some(prev), Some(next)) = (out.chars().last(), text.first())
+      && prev != '\n'
+      && needs_space(prev, *next)
+    {
+      out.push(' ');
+    }
+    *pending_space = false;
+    out.extend(text);
+  };
+
+  while i < chars.len() {
+    let c = chars[i];
+    match c {
+      '\n' => {
+        while out.ends_with([' ', '\t']) {
+          out.pop();
+        }
+        out.push('\n');
+        pending_space = false;
+        i += 1;
+      }
+      ' ' | '\t' | '\r' => {
+        pending_space = true;
+        i += 1;
+      }
+      '-' if chars.get(i + 1) == Some(&'-') => {
+        // Comments are dropped, keeping the line breaks of long ones.
+        let end = match long_bracket(&chars, i + 2) {
+          Some(level) => long_bracket_end(&chars, i + 2, level),
+          None => (i..chars.len()).find(|j| chars[*j] == '\n').unwrap_or(chars.len()),
+        };
+        for _ in chars[i..end].iter().filter(|c| **c == '\n') {
+          out.push('\n');
+        }
+        pending_space = true;
+        i = end;
+      }
+      '"' | '\'' => {
+        let mut end = i + 1;
+        while end < chars.len() && chars[end] != c {
+          if chars[end] == '\\' {
+            end += 1;
+          }
+          end += 1;
+        }
+        let end = (end + 1).min(chars.len());
+        push(&mut out, &mut pending_space, &chars[i..end]);
+        i = end;
+      }
+      '[' if long_bracket(&chars, i).is_some() => {
+        let level = long_bracket(&chars, i).unwrap();
+        let end = long_bracket_end(&chars, i, level);
+        push(&mut out, &mut pending_space, &chars[i..end]);
+        i = end;
+      }
+      _ => {
+        push(&mut out, &mut pending_space, &chars[i..i + 1]);
+        i += 1;
+      }
+    }
+  }
+
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_minify() {
+    let code = "local  a = 1 -- one\n--[[ long\ncomment ]]\nlocal s = \"a  --  b\" .. [[ x  y ]]\nreturn a - -a, 1 .. 2, t[ [[k]] ]\n";
+    let minified = minify(code);
+    assert_eq!(
+      minified,
+      "local a=1\n\n\nlocal s=\"a  --  b\"..[[ x  y ]]\nreturn a- -a,1 .. 2,t[ [[k]]]\n"
+    );
+
+    let lua = mlua::Lua::new();
+    lua.globals().set("t", lua.create_table_from([("k", 3)]).unwrap()).unwrap();
+    let run = |code: &str| lua.load(code).eval::<(i64, String, i64)>().unwrap();
+    assert_eq!(run(code), run(&minified));
+  }
+}
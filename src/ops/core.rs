@@ -3,8 +3,10 @@ use crate::lulibs::collections::{LuluHashMap, LuluHashSet};
 use crate::lulibs::rec::LuluRec;
 use crate::lulibs::rust::{LuluArc, lua_to_lulu};
 use crate::ops::process::register_exec;
+use crate::ops::sandbox::register_sandbox;
 use crate::ops::std::create_std;
 use crate::package_manager::PackageManager;
+use crate::permissions::{Permissions, check_net, check_read, check_write};
 use crate::util::copy_recursively;
 use crate::{core::Lulu, core::LuluModSource, ops::std::get_std_module};
 use base64::prelude::*;
@@ -43,6 +45,14 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
       let imported = Arc::new(Mutex::new(Vec::new()));
       move |lua, (env, name): (String, Option<String>)| {
         if let Some(module) = get_std_module(&env) {
+          if let Some(allowed) = &lulu_rc.std_modules
+            && !allowed.contains(&env)
+          {
+            return Err(mlua::Error::RuntimeError(format!(
+              "Std module '{}' is not available in this runtime",
+              env
+            )));
+          }
           let mut imports = imported.lock().unwrap();
           let name = if let Some(name) = name {
             name.clone()
@@ -105,6 +115,12 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
           LuluModSource::Code(code) => code.as_bytes().to_vec(),
         };
         Ok(LuluByteArray { bytes })
+      } else if let Some(bytes) = lulu.assets.get(&crate::resources::normalize(
+        name.strip_prefix("bytes://").unwrap_or(&name),
+      )) {
+        Ok(LuluByteArray {
+          bytes: bytes.clone(),
+        })
       } else {
         Err(mlua::Error::RuntimeError(format!(
           "Module '{}' not found",
@@ -119,35 +135,63 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
     Ok(())
   })?;
   lua.globals().set("sleep", sleep_fn)?;
+  // What async Rust functions yield while they wait, see `Future:step`.
+  lua.globals().set("__poll_pending", Lua::poll_pending())?;
 
   lua.globals().set("__get_mods__", gmods)?;
   lua.globals().set("bytes_from", bytes_from_mods)?;
+  crate::resources::register(lua, &lulu.assets)?;
   lua.globals().set("exec_mod", execmod)?;
   lua.globals().set("argv", lulu.args.clone())?;
-
-  let ptr_of = lua.create_function(|lua, value: mlua::Value| {
-    let ptr = lua.create_registry_value(value)?;
-    Ok(Box::into_raw(Box::new(ptr)) as usize)
+  lua.set_app_data(crate::lulibs::workers::WorkerSource::of(lulu));
+  lulu
+    .permissions
+    .clone()
+    .unwrap_or_else(Permissions::all)
+    .apply(lua)?;
+
+  // Only pointers handed out by `ptr_of` are followed, any other number
+  // would read or write arbitrary memory.
+  let issued = Arc::new(Mutex::new(HashSet::new()));
+  let checked = |issued: &Arc<Mutex<HashSet<usize>>>, ptr: usize| {
+    if issued.lock().unwrap().contains(&ptr) {
+      Ok(ptr as *mut mlua::RegistryKey)
+    } else {
+      Err(mlua::Error::RuntimeError(format!(
+        "Invalid pointer {:#x}",
+        ptr
+      )))
+    }
+  };
+
+  let ptr_of = lua.create_function({
+    let issued = issued.clone();
+    move |lua, value: mlua::Value| {
+      let ptr = lua.create_registry_value(value)?;
+      let ptr = Box::into_raw(Box::new(ptr)) as usize;
+      issued.lock().unwrap().insert(ptr);
+      Ok(ptr)
+    }
   })?;
   lua.globals().set("ptr_of", ptr_of)?;
 
-  let ptr_deref = lua.create_function(|lua, ptr: usize| {
-    let value_ptr = ptr as *mut mlua::RegistryKey;
-    if value_ptr.is_null() {
-      return Ok(mlua::Value::Nil);
+  let ptr_deref = lua.create_function({
+    let issued = issued.clone();
+    move |lua, ptr: usize| {
+      if ptr == 0 {
+        return Ok(mlua::Value::Nil);
+      }
+      let key = unsafe { &*checked(&issued, ptr)? };
+      lua.registry_value::<mlua::Value>(key)
     }
-    let key = unsafe { &*value_ptr };
-    let value = lua.registry_value::<mlua::Value>(&key)?;
-    Ok(value)
   })?;
   lua.globals().set("ptr_deref", ptr_deref)?;
 
-  let ptr_set = lua.create_function(|lua, (ptr, new_val): (usize, mlua::Value)| {
-    let value_ptr = ptr as *mut mlua::RegistryKey;
-    if value_ptr.is_null() {
+  let ptr_set = lua.create_function(move |lua, (ptr, new_val): (usize, mlua::Value)| {
+    if ptr == 0 {
       return Err(mlua::Error::RuntimeError("Null pointer".to_string()));
     }
-    let key = unsafe { &mut *value_ptr };
+    let key = unsafe { &mut *checked(&issued, ptr)? };
     lua.replace_registry_value(key, new_val.clone())?;
     Ok(new_val)
   })?;
@@ -155,17 +199,24 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "reads",
-    lua.create_function(|_, path: String| Ok(fs::read_to_string(path)?))?,
+    lua.create_function(|lua, path: String| {
+      check_read(lua, &path)?;
+      Ok(fs::read_to_string(path)?)
+    })?,
   )?;
 
   lua.globals().set(
     "exists",
-    lua.create_function(|_, path: String| Ok(std::path::Path::new(&path).exists()))?,
+    lua.create_function(|lua, path: String| {
+      check_read(lua, &path)?;
+      Ok(std::path::Path::new(&path).exists())
+    })?,
   )?;
 
   lua.globals().set(
     "mkdir",
-    lua.create_function(|_, path: String| {
+    lua.create_function(|lua, path: String| {
+      check_write(lua, &path)?;
       fs::create_dir_all(&path)?;
       Ok(())
     })?,
@@ -173,7 +224,9 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "cp",
-    lua.create_function(|_, (src, dest): (String, String)| {
+    lua.create_function(|lua, (src, dest): (String, String)| {
+      check_read(lua, &src)?;
+      check_write(lua, &dest)?;
       copy_recursively(&src, &dest)?;
       Ok(())
     })?,
@@ -181,7 +234,9 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "cp_raw",
-    lua.create_function(|_, (src, dest): (String, String)| {
+    lua.create_function(|lua, (src, dest): (String, String)| {
+      check_read(lua, &src)?;
+      check_write(lua, &dest)?;
       fs::copy(&src, &dest)?;
       Ok(())
     })?,
@@ -189,7 +244,9 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "rename",
-    lua.create_function(|_, (old, new): (String, String)| {
+    lua.create_function(|lua, (old, new): (String, String)| {
+      check_write(lua, &old)?;
+      check_write(lua, &new)?;
       fs::rename(&old, &new)?;
       Ok(())
     })?,
@@ -197,7 +254,9 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "mv",
-    lua.create_function(|_, (src, dest): (String, String)| {
+    lua.create_function(|lua, (src, dest): (String, String)| {
+      check_write(lua, &src)?;
+      check_write(lua, &dest)?;
       fs::copy(&src, &dest)?;
       fs::remove_file(&src)?;
       Ok(())
@@ -206,7 +265,8 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "rm",
-    lua.create_function(|_, path: String| {
+    lua.create_function(|lua, path: String| {
+      check_write(lua, &path)?;
       let p = std::path::Path::new(&path);
       if p.is_dir() {
         fs::remove_dir_all(p)?;
@@ -219,7 +279,8 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
   lua.globals().set(
     "read",
-    lua.create_function(|_, path: String| {
+    lua.create_function(|lua, path: String| {
+      check_read(lua, &path)?;
       let mut file = fs::File::open(&path)?;
       let mut buffer = Vec::new();
       file.read_to_end(&mut buffer)?;
@@ -270,14 +331,22 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
 
         let cache_path = pkg_manager.get_package_cache_path(&url);
         if !pkg_manager.is_cached(&url) {
+          check_net(&lua, &url)?;
           pkg_manager
-            .fetch_package(&url, &cache_path)
+            .fetch_package(&url, &cache_path, None)
             .await
             .map_err(mlua::Error::external)?;
+          let name = pkg_manager
+            .get_package_info(&cache_path, &url)
+            .map(|info| info.name)
+            .unwrap_or_default();
           pkg_manager
-            .build_package(&cache_path)
+            .build_dependency(&cache_path, &name)
             .await
             .map_err(mlua::Error::external)?;
+          pkg_manager
+            .write_cache_meta(&url, &cache_path, None)
+            .map_err(mlua::Error::external)?;
         }
 
         let cache_lulib_dir = cache_path.join(".lib");
@@ -287,10 +356,11 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
             if entry.file_type()?.is_file()
               && entry.path().extension().and_then(|s| s.to_str()) == Some("lulib")
             {
-              let mods = crate::bundle::load_lulib(&entry.path())?;
-              crate::bundle::reg_bundle_nods(&mut lulu_clone, mods.clone())?;
+              let bundle = crate::bundle::load_lulib(&entry.path())?;
+              crate::bundle::reg_bundle_nods(&mut lulu_clone, bundle.clone())?;
 
-              let (modname, _) = mods
+              let (modname, _) = bundle
+                .mods
                 .iter()
                 .find(|(m, _)| m.ends_with("init"))
                 .ok_or_else(|| mlua::Error::RuntimeError(format!("No init was found")))?;
@@ -382,26 +452,7 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
     lua.create_function(|_, v: mlua::Table| LuluRec::try_from(v))?,
   )?;
 
-  lua.globals().set(
-    "exec_sandboxed",
-    lua.create_function(
-      |lua, (code, name, env): (String, Option<String>, Option<mlua::Table>)| {
-        let mut chunk = lua.load(code);
-
-        if let Some(name) = name {
-          chunk = chunk.set_name(name);
-        }
-
-        if let Some(env) = env {
-          chunk = chunk.set_environment(env);
-        } else {
-          chunk = chunk.set_environment(lua.create_table()?);
-        }
-
-        chunk.eval::<mlua::Value>()
-      },
-    )?,
-  )?;
+  register_sandbox(lua, &lulu.compiler, &lulu.std)?;
 
   lua.globals().set(
     "setup_downloader",
@@ -423,6 +474,10 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
           pm.downloader.progress_bar_size = progress_bar_size;
         }
 
+        if let Ok(concurrency) = options.get::<usize>("concurrency") {
+          pm.downloader.concurrency = concurrency;
+        }
+
         if let Ok(progress_bar_colors) = options.get::<Vec<u8>>("progressbar_colors") {
           pm.downloader.progress_bar_colors = (
             (
@@ -448,6 +503,7 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
   lua.globals().set(
     "download_file",
     lua.create_async_function(async |lua, url: String| {
+      check_net(&lua, &url)?;
       let pm = lua.globals().get::<mlua::AnyUserData>("__lulu_pac_man")?;
       let pm = pm.borrow::<PackageManager>()?;
       pm.download_file(&url).await.map_err(|e| {
@@ -459,9 +515,11 @@ pub fn register_ops(lua: &Lua, lulu: &Lulu) -> mlua::Result<()> {
   lua.globals().set(
     "download_uncached",
     lua.create_async_function(async |lua, (url, path): (String, String)| {
+      check_net(&lua, &url)?;
+      check_write(&lua, &path)?;
       let pm = lua.globals().get::<mlua::AnyUserData>("__lulu_pac_man")?;
       let pm = pm.borrow::<PackageManager>()?;
-      pm.download_url(&url, &std::path::Path::new(&path))
+      pm.download_url(&url, &std::path::Path::new(&path), None)
         .await
         .map_err(|e| {
           eprintln!("Failed to download file: {}", e);
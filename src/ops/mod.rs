@@ -1,5 +1,6 @@
 pub mod core;
 pub mod process;
+pub mod sandbox;
 pub mod std;
 pub mod sys;
 
@@ -1,3 +1,4 @@
+use crate::permissions::check_run;
 use mlua::prelude::*;
 use std::io::{BufRead, BufReader, Write};
 use std::process::{Child, ChildStdin, Stdio};
@@ -51,6 +52,7 @@ pub fn register_exec(lua: &Lua) -> mlua::Result<()> {
 
       let program = &parts[0];
       let args = &parts[1..];
+      check_run(lua, program)?;
 
       let inherit = inherit.unwrap_or(false);
 
@@ -93,7 +95,12 @@ pub fn register_exec(lua: &Lua) -> mlua::Result<()> {
 
   lua.globals().set(
     "spawn",
-    lua.create_function(|_, command: String| spawn_process_with_buffer(&command))?,
+    lua.create_function(|lua, command: String| {
+      if let Some(program) = split_command(&command).first() {
+        check_run(lua, program)?;
+      }
+      spawn_process_with_buffer(&command)
+    })?,
   )?;
 
   Ok(())
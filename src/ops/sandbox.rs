@@ -0,0 +1,515 @@
+//! `Sandbox` and `exec_sandboxed`: code running in its own Lua state, with
+//! limits on instructions, wall-clock time and memory, only the standard
+//! libraries it was given, and optionally compiled with the macros of the
+//! project. Values cross between the states as copies and functions as
+//! proxies that call back into the state they came from.
+
+use crate::compiler::Compiler;
+use crate::lulibs::bytes::LuluByteArray;
+use crate::lulibs::rust::LuluArc;
+use crate::permissions::Permissions;
+use mlua::{ChunkMode, HookTriggers, Lua, LuaOptions, StdLib, VmState};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many instructions run between two checks of the limits.
+const HOOK_STEP: u32 = 1000;
+
+/// The standard libraries sandboxes get when no `std` list is given.
+const DEFAULT_STD: [&str; 5] = ["string", "table", "math", "coroutine", "bit"];
+/// Everything a `std` list may name. `os` only brings its time functions,
+/// and `io` is checked against the permissions of the parent.
+const KNOWN_STD: [&str; 7] = ["string", "table", "math", "coroutine", "bit", "os", "io"];
+
+/// Wraps what can catch errors so a sandbox that ran out of instructions or
+/// time cannot keep going by catching the error, and keeps `load` from
+/// accepting bytecode.
+const PRELUDE: &str = r#"
+local tripped = ...
+local pcall, xpcall, load = pcall, xpcall, load
+local resume = coroutine and coroutine.resume
+
+local function check(...)
+  local err = tripped()
+  if err then
+    error(err, 0)
+  end
+  return ...
+end
+
+_G.pcall = function(...)
+  return check(pcall(...))
+end
+_G.xpcall = function(...)
+  return check(xpcall(...))
+end
+if resume then
+  coroutine.resume = function(...)
+    return check(resume(...))
+  end
+end
+
+_G.load = function(chunk, name, _, env)
+  if env ~= nil then
+    return load(chunk, name, "t", env)
+  end
+  return load(chunk, name, "t")
+end
+_G.loadstring = function(chunk, name)
+  return load(chunk, name, "t")
+end
+"#;
+
+#[derive(Default)]
+pub struct SandboxOptions {
+  /// Instructions one call into the sandbox may run.
+  pub instructions: Option<u64>,
+  /// Wall-clock time one call into the sandbox may take.
+  pub timeout: Option<Duration>,
+  /// Bytes the whole sandbox state may allocate.
+  pub memory: Option<usize>,
+  pub std: Option<Vec<String>>,
+  /// Whether code goes through the `Compiler`, which also loads the std.
+  pub compile: bool,
+}
+
+impl mlua::FromLua for SandboxOptions {
+  fn from_lua(value: mlua::Value, _: &Lua) -> mlua::Result<Self> {
+    let table = match value {
+      mlua::Value::Nil => return Ok(SandboxOptions::default()),
+      mlua::Value::Table(table) => table,
+      other => {
+        return Err(mlua::Error::FromLuaConversionError {
+          from: other.type_name(),
+          to: "SandboxOptions".to_string(),
+          message: Some("sandbox options must be a table".to_string()),
+        });
+      }
+    };
+
+    let std = table.get::<Option<Vec<String>>>("std")?;
+    if let Some(unknown) = std
+      .iter()
+      .flatten()
+      .find(|name| !KNOWN_STD.contains(&name.as_str()))
+    {
+      return Err(mlua::Error::RuntimeError(format!(
+        "Unknown sandbox std module '{}', expected one of {}",
+        unknown,
+        KNOWN_STD.join(", ")
+      )));
+    }
+
+    Ok(SandboxOptions {
+      instructions: table.get("instructions")?,
+      timeout: table
+        .get::<Option<u64>>("timeout")?
+        .map(Duration::from_millis),
+      memory: table.get("memory")?,
+      std,
+      compile: table.get::<Option<bool>>("compile")?.unwrap_or(false),
+    })
+  }
+}
+
+/// What one call into the sandbox has used so far. Calls that come back
+/// into the sandbox from the parent while it runs share the budget.
+#[derive(Default)]
+struct SandboxLimits {
+  instructions: Option<u64>,
+  timeout: Option<Duration>,
+  used: AtomicU64,
+  started: Mutex<Option<Instant>>,
+  depth: AtomicUsize,
+}
+
+struct Entered(Arc<SandboxLimits>);
+
+impl Drop for Entered {
+  fn drop(&mut self) {
+    self.0.depth.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl SandboxLimits {
+  fn enter(self: &Arc<Self>) -> Entered {
+    if self.depth.fetch_add(1, Ordering::SeqCst) == 0 {
+      self.used.store(0, Ordering::SeqCst);
+      *self.started.lock().unwrap() = Some(Instant::now());
+    }
+    Entered(self.clone())
+  }
+
+  fn exceeded(&self) -> Option<String> {
+    if let Some(limit) = self.instructions
+      && self.used.load(Ordering::SeqCst) > limit
+    {
+      return Some(format!(
+        "Sandbox exceeded its limit of {} instructions",
+        limit
+      ));
+    }
+    if let Some(timeout) = self.timeout
+      && let Some(started) = *self.started.lock().unwrap()
+      && started.elapsed() > timeout
+    {
+      return Some(format!(
+        "Sandbox exceeded its time limit of {}ms",
+        timeout.as_millis()
+      ));
+    }
+    None
+  }
+}
+
+/// Copies values from one state to another. Functions become proxies, and
+/// `out` tells whether values are leaving the sandbox, in which case calling
+/// such a proxy enters the sandbox and counts against its limits.
+struct Transfer<'a> {
+  from: &'a Lua,
+  to: &'a Lua,
+  limits: &'a Arc<SandboxLimits>,
+  out: bool,
+  seen: HashMap<usize, mlua::Table>,
+}
+
+impl<'a> Transfer<'a> {
+  fn new(from: &'a Lua, to: &'a Lua, limits: &'a Arc<SandboxLimits>, out: bool) -> Self {
+    Transfer {
+      from,
+      to,
+      limits,
+      out,
+      seen: HashMap::new(),
+    }
+  }
+
+  fn values(&mut self, values: mlua::MultiValue) -> mlua::Result<mlua::MultiValue> {
+    values.into_iter().map(|value| self.value(value)).collect()
+  }
+
+  fn value(&mut self, value: mlua::Value) -> mlua::Result<mlua::Value> {
+    Ok(match value {
+      mlua::Value::String(s) => mlua::Value::String(self.to.create_string(s.as_bytes())?),
+      mlua::Value::Table(table) => mlua::Value::Table(self.table(table)?),
+      mlua::Value::Function(func) => mlua::Value::Function(self.function(func)?),
+      mlua::Value::UserData(ud) if ud.is::<LuluArc>() => {
+        mlua::Value::UserData(self.to.create_userdata(ud.borrow::<LuluArc>()?.clone())?)
+      }
+      mlua::Value::UserData(ud) if ud.is::<LuluByteArray>() => mlua::Value::UserData(
+        self
+          .to
+          .create_userdata(ud.borrow::<LuluByteArray>()?.clone())?,
+      ),
+      mlua::Value::Error(err) => mlua::Value::String(self.to.create_string(err.to_string())?),
+      value @ (mlua::Value::Nil
+      | mlua::Value::Boolean(_)
+      | mlua::Value::Integer(_)
+      | mlua::Value::Number(_)
+      | mlua::Value::LightUserData(_)) => value,
+      other => {
+        return Err(mlua::Error::RuntimeError(format!(
+          "A {} cannot be passed {} a sandbox",
+          other.type_name(),
+          if self.out { "out of" } else { "into" }
+        )));
+      }
+    })
+  }
+
+  fn table(&mut self, table: mlua::Table) -> mlua::Result<mlua::Table> {
+    let key = table.to_pointer() as usize;
+    if let Some(copy) = self.seen.get(&key) {
+      return Ok(copy.clone());
+    }
+
+    let copy = self.to.create_table()?;
+    self.seen.insert(key, copy.clone());
+    for pair in table.pairs::<mlua::Value, mlua::Value>() {
+      let (k, v) = pair?;
+      copy.raw_set(self.value(k)?, self.value(v)?)?;
+    }
+    if let Some(meta) = table.metatable() {
+      copy.set_metatable(Some(self.table(meta)?))?;
+    }
+    Ok(copy)
+  }
+
+  fn function(&mut self, func: mlua::Function) -> mlua::Result<mlua::Function> {
+    let home = self.from.weak();
+    let limits = self.limits.clone();
+    let out = self.out;
+    self
+      .to
+      .create_function(move |caller, args: mlua::MultiValue| {
+        let home = home
+          .try_upgrade()
+          .ok_or_else(|| mlua::Error::RuntimeError("The sandbox was dropped".to_string()))?;
+        let _entered = out.then(|| limits.enter());
+        let args = Transfer::new(caller, &home, &limits, !out).values(args)?;
+        let results = func.call::<mlua::MultiValue>(args)?;
+        Transfer::new(&home, caller, &limits, out).values(results)
+      })
+  }
+}
+
+pub struct LuluSandbox {
+  lua: Lua,
+  limits: Arc<SandboxLimits>,
+  compiler: Option<Mutex<Compiler>>,
+}
+
+impl LuluSandbox {
+  /// Creates the sandbox state. `compiler` holds the compiler to run code
+  /// through and the compiled std to load when `compile` is set.
+  pub fn new(
+    parent: &Lua,
+    options: SandboxOptions,
+    compiler: Option<(Compiler, &str)>,
+  ) -> mlua::Result<Self> {
+    let lua = Lua::new_with(StdLib::ALL_SAFE, LuaOptions::default())?;
+    // Count hooks do not run inside traces, so the JIT stays off.
+    lua.load("jit.off()").exec()?;
+
+    let compiler = match compiler {
+      Some((compiler, std)) if options.compile => {
+        lua.load(std).set_name("std").exec()?;
+        Some(Mutex::new(compiler))
+      }
+      _ => None,
+    };
+
+    let std = options
+      .std
+      .unwrap_or_else(|| DEFAULT_STD.iter().map(|name| name.to_string()).collect());
+    let allows = |name: &str| std.iter().any(|allowed| allowed == name);
+
+    if allows("io")
+      && let Some(permissions) = parent.app_data_ref::<Permissions>()
+    {
+      permissions.apply(&lua)?;
+    }
+
+    let globals = lua.globals();
+    for name in ["string", "table", "math", "coroutine", "bit", "io"] {
+      if !allows(name) {
+        globals.set(name, mlua::Value::Nil)?;
+      }
+    }
+    if !allows("io") {
+      globals.set("dofile", mlua::Value::Nil)?;
+      globals.set("loadfile", mlua::Value::Nil)?;
+    }
+    let os: mlua::Table = globals.get("os")?;
+    if allows("os") {
+      let time = lua.create_table()?;
+      for name in ["clock", "time", "date", "difftime"] {
+        time.set(name, os.get::<mlua::Function>(name)?)?;
+      }
+      globals.set("os", time)?;
+    } else {
+      globals.set("os", mlua::Value::Nil)?;
+    }
+    for name in ["jit", "package", "require", "module"] {
+      globals.set(name, mlua::Value::Nil)?;
+    }
+
+    let limits = Arc::new(SandboxLimits {
+      instructions: options.instructions,
+      timeout: options.timeout,
+      ..Default::default()
+    });
+
+    let tripped = limits.clone();
+    lua
+      .load(PRELUDE)
+      .set_name("lulu::sandbox")
+      .call::<()>(lua.create_function(move |_, ()| Ok(tripped.exceeded()))?)?;
+
+    if limits.instructions.is_some() || limits.timeout.is_some() {
+      let hook = limits.clone();
+      lua.set_global_hook(
+        HookTriggers::new().every_nth_instruction(HOOK_STEP),
+        move |_, _| {
+          hook.used.fetch_add(HOOK_STEP as u64, Ordering::SeqCst);
+          match hook.exceeded() {
+            Some(err) => Err(mlua::Error::RuntimeError(err)),
+            None => Ok(VmState::Continue),
+          }
+        },
+      )?;
+    }
+
+    if let Some(memory) = options.memory {
+      lua.set_memory_limit(memory)?;
+    }
+
+    Ok(LuluSandbox {
+      lua,
+      limits,
+      compiler,
+    })
+  }
+
+  pub fn set(&self, parent: &Lua, key: mlua::Value, value: mlua::Value) -> mlua::Result<()> {
+    let mut transfer = Transfer::new(parent, &self.lua, &self.limits, false);
+    let (key, value) = (transfer.value(key)?, transfer.value(value)?);
+    self.lua.globals().set(key, value)
+  }
+
+  pub fn get(&self, parent: &Lua, key: mlua::Value) -> mlua::Result<mlua::Value> {
+    let key = Transfer::new(parent, &self.lua, &self.limits, false).value(key)?;
+    let value = self.lua.globals().get(key)?;
+    Transfer::new(&self.lua, parent, &self.limits, true).value(value)
+  }
+
+  pub fn eval(&self, parent: &Lua, code: &str, name: &str) -> mlua::Result<mlua::MultiValue> {
+    let code = match &self.compiler {
+      Some(compiler) => compiler.lock().unwrap().compile(code, None, None),
+      None => code.to_string(),
+    };
+
+    let results = {
+      let _entered = self.limits.enter();
+      self
+        .lua
+        .load(code)
+        .set_name(name)
+        .set_mode(ChunkMode::Text)
+        .eval::<mlua::MultiValue>()?
+    };
+    Transfer::new(&self.lua, parent, &self.limits, true).values(results)
+  }
+}
+
+impl mlua::UserData for LuluSandbox {
+  fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+    methods.add_method(
+      "set",
+      |lua, this, (key, value): (mlua::Value, mlua::Value)| this.set(lua, key, value),
+    );
+    methods.add_method("get", |lua, this, key: mlua::Value| this.get(lua, key));
+    methods.add_method(
+      "eval",
+      |lua, this, (code, name): (String, Option<String>)| {
+        this.eval(lua, &code, name.as_deref().unwrap_or("lulu::sandbox"))
+      },
+    );
+    methods.add_method("memory", |_, this, ()| Ok(this.lua.used_memory()));
+  }
+}
+
+/// Sets `create_sandbox(options)` and `exec_sandboxed(code, name, env, options)`.
+pub fn register_sandbox(lua: &Lua, compiler: &Compiler, std: &str) -> mlua::Result<()> {
+  let source = Arc::new((compiler.clone(), std.to_string()));
+
+  let create = source.clone();
+  lua.globals().set(
+    "create_sandbox",
+    lua.create_function(move |lua, options: SandboxOptions| {
+      LuluSandbox::new(lua, options, Some((create.0.clone(), &create.1)))
+    })?,
+  )?;
+
+  lua.globals().set(
+    "exec_sandboxed",
+    lua.create_function(
+      move |lua,
+            (code, name, env, options): (
+        String,
+        Option<String>,
+        Option<mlua::Table>,
+        SandboxOptions,
+      )| {
+        let sandbox = LuluSandbox::new(lua, options, Some((source.0.clone(), &source.1)))?;
+        if let Some(env) = env {
+          for pair in env.pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            sandbox.set(lua, key, value)?;
+          }
+        }
+        sandbox.eval(lua, &code, name.as_deref().unwrap_or("lulu::sandbox"))
+      },
+    )?,
+  )?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mlua::IntoLua;
+
+  #[test]
+  fn test_sandbox_limits() {
+    let parent = Lua::new();
+    let options = |code: &str| parent.load(code).eval::<SandboxOptions>().unwrap();
+
+    let sandbox = LuluSandbox::new(&parent, options("{ instructions = 100000 }"), None).unwrap();
+    let err = sandbox
+      .eval(
+        &parent,
+        "while true do pcall(function() while true do end end) end",
+        "loop",
+      )
+      .unwrap_err()
+      .to_string();
+    assert!(err.contains("limit of 100000 instructions"), "{}", err);
+    // The budget is per call.
+    assert!(sandbox.eval(&parent, "return 1", "ok").is_ok());
+
+    let sandbox = LuluSandbox::new(&parent, options("{ timeout = 50 }"), None).unwrap();
+    let started = Instant::now();
+    assert!(sandbox.eval(&parent, "while true do end", "loop").is_err());
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let sandbox = LuluSandbox::new(&parent, options("{ memory = 4 * 1024 * 1024 }"), None).unwrap();
+    let err = sandbox
+      .eval(
+        &parent,
+        "local t = {} for i = 1, 1e7 do t[i] = i end",
+        "alloc",
+      )
+      .unwrap_err();
+    assert!(matches!(err, mlua::Error::MemoryError(_)), "{}", err);
+
+    let sandbox = LuluSandbox::new(&parent, options("{ std = { 'math' } }"), None).unwrap();
+    let missing: (bool, bool, bool) = parent
+      .unpack_multi(
+        sandbox
+          .eval(
+            &parent,
+            "return string == nil, os == nil, math ~= nil",
+            "std",
+          )
+          .unwrap(),
+      )
+      .unwrap();
+    assert_eq!(missing, (true, true, true));
+    let bytecode = sandbox.eval(&parent, "return load('\\27LJ') == nil", "bytecode");
+    assert!(parent.unpack_multi::<bool>(bytecode.unwrap()).unwrap());
+    assert!(
+      parent
+        .load("{ std = { 'debug' } }")
+        .eval::<SandboxOptions>()
+        .is_err()
+    );
+
+    let double = parent.create_function(|_, n: i64| Ok(n * 2)).unwrap();
+    sandbox
+      .set(
+        &parent,
+        "double".into_lua(&parent).unwrap(),
+        mlua::Value::Function(double),
+      )
+      .unwrap();
+    let results = sandbox
+      .eval(&parent, "return { value = double(21) }", "proxy")
+      .unwrap();
+    let table: mlua::Table = parent.unpack_multi(results).unwrap();
+    assert_eq!(table.get::<i64>("value").unwrap(), 42);
+  }
+}
@@ -266,6 +266,8 @@ pub fn init_std_modules() {
 
   crate::lulibs::threads::into_module();
 
+  crate::lulibs::timers::into_module();
+
   crate::lulibs::console::into_module();
 
   crate::lulibs::pathing::into_module();
@@ -0,0 +1,157 @@
+use anyhow::{Result, anyhow};
+use std::cmp::Ordering;
+use tokio::process::Command;
+
+use crate::lockfile::LockedPackage;
+use crate::registry::{REGISTRY_PREFIX, compare_versions, fetch_index, parse_registry_dep};
+use crate::resolver::parse_github_dep;
+
+#[derive(Debug, Clone)]
+pub struct OutdatedEntry {
+  pub name: String,
+  pub current: String,
+  pub latest: Option<String>,
+  pub outdated: bool,
+}
+
+/// Lists the refs of a remote git repository as `(commit, ref)` pairs.
+async fn ls_remote(
+  git_url: &str,
+  options: &[&str],
+  patterns: &[&str],
+) -> Result<Vec<(String, String)>> {
+  let output = Command::new("git")
+    .arg("ls-remote")
+    .args(options)
+    .arg(git_url)
+    .args(patterns)
+    .output()
+    .await?;
+
+  if !output.status.success() {
+    return Err(anyhow!(
+      "git ls-remote {} failed: {}",
+      git_url,
+      String::from_utf8_lossy(&output.stderr).lines().next().unwrap_or("")
+    ));
+  }
+
+  Ok(
+    String::from_utf8_lossy(&output.stdout)
+      .lines()
+      .filter_map(|line| {
+        let (commit, name) = line.split_once('\t')?;
+        Some((commit.to_string(), name.to_string()))
+      })
+      .collect(),
+  )
+}
+
+fn latest_tag(refs: &[(String, String)]) -> Option<String> {
+  refs
+    .iter()
+    .filter_map(|(_, name)| name.strip_prefix("refs/tags/"))
+    .max_by(|a, b| compare_versions(a.trim_start_matches('v'), b.trim_start_matches('v')))
+    .map(str::to_string)
+}
+
+fn short(commit: &str) -> &str {
+  &commit[..commit.len().min(7)]
+}
+
+fn describe(package: &LockedPackage) -> String {
+  match (&package.version, &package.commit) {
+    (Some(version), Some(commit)) => format!("{} ({})", version, short(commit)),
+    (Some(version), None) => version.clone(),
+    (None, Some(commit)) => short(commit).to_string(),
+    (None, None) => "-".to_string(),
+  }
+}
+
+async fn check_git(
+  package: &LockedPackage,
+  git_url: &str,
+  branch: Option<&str>,
+) -> Result<OutdatedEntry> {
+  let mut entry = OutdatedEntry {
+    name: package.name.clone(),
+    current: describe(package),
+    latest: None,
+    outdated: false,
+  };
+
+  let tags = ls_remote(git_url, &["--tags", "--refs"], &[]).await?;
+  if let (Some(tag), Some(version)) = (latest_tag(&tags), &package.version) {
+    entry.outdated =
+      compare_versions(tag.trim_start_matches('v'), version.trim_start_matches('v'))
+        == Ordering::Greater;
+    entry.latest = Some(tag);
+  }
+
+  if !entry.outdated {
+    let head_ref = branch.unwrap_or("HEAD");
+    let heads = ls_remote(git_url, &[], &[head_ref]).await?;
+    if let (Some((head, _)), Some(commit)) = (heads.first(), &package.commit) {
+      entry.outdated = head != commit;
+      entry.latest = Some(match &entry.latest {
+        Some(tag) => format!("{} ({} {})", tag, head_ref, short(head)),
+        None => format!("{} {}", head_ref, short(head)),
+      });
+    }
+  }
+
+  Ok(entry)
+}
+
+/// Compares a locked package against the newest registry version, git tag or
+/// branch head. Packages downloaded from plain URLs have no upstream to ask.
+pub async fn check_package(package: &LockedPackage, registry: Option<&str>) -> Result<OutdatedEntry> {
+  if package.url.starts_with(REGISTRY_PREFIX) {
+    let (name, _) = parse_registry_dep(&package.url)
+      .ok_or_else(|| anyhow!("Invalid registry dependency: {}", package.url))?;
+    let registry = registry.ok_or_else(|| anyhow!("No registry configured"))?;
+    let index = fetch_index(registry, &name).await?;
+    let latest = index.latest().map(|(version, _)| version.clone());
+
+    return Ok(OutdatedEntry {
+      name: package.name.clone(),
+      current: describe(package),
+      outdated: match (&latest, &package.version) {
+        (Some(latest), Some(current)) => compare_versions(latest, current) == Ordering::Greater,
+        _ => false,
+      },
+      latest,
+    });
+  }
+
+  if let Some(github) = parse_github_dep(&package.url) {
+    let git_url = format!("https://github.com/{}/{}.git", github.username, github.repo);
+    return check_git(package, &git_url, github.branch.as_deref()).await;
+  }
+
+  if package.url.ends_with(".git") {
+    return check_git(package, &package.url, None).await;
+  }
+
+  Ok(OutdatedEntry {
+    name: package.name.clone(),
+    current: describe(package),
+    latest: None,
+    outdated: false,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_latest_tag() {
+    let refs = vec![
+      ("a".to_string(), "refs/tags/v1.9.0".to_string()),
+      ("b".to_string(), "refs/tags/v1.10.0".to_string()),
+      ("c".to_string(), "refs/heads/main".to_string()),
+    ];
+    assert_eq!(latest_tag(&refs), Some("v1.10.0".to_string()));
+  }
+}
@@ -1,23 +1,34 @@
 use anyhow::{Context, Result, anyhow};
 use flate2::read::GzDecoder;
+use futures_util::StreamExt;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output};
+use std::time::Duration;
 use tar::Archive;
 use zip::ZipArchive;
 
-use crate::conf::{FetchField, load_lulu_conf, load_lulu_fetch_field};
+use crate::build_sandbox::{
+  self, ALLOW_BUILD_SCRIPTS_ENV, BUILD_DENIED_EXIT, BUILD_SANDBOX_ENV, BuildSandbox,
+};
+use crate::cache::{self, CacheEntry, CacheMeta};
+use crate::conf::{Dependency, FetchField, load_lulu_conf, load_lulu_fetch_field};
+use crate::integrity::{Checksums, verify_sha256};
+use crate::lockfile::{Lockfile, VENDOR_DIR};
+use crate::progress::MultiProgress;
+use crate::registry::{REGISTRY_ENV, REGISTRY_PREFIX, blob_url, fetch_index, parse_registry_dep};
 use crate::resolver::{GitHubDependency, create_dirs, parse_github_dep};
+use crate::target::Target;
 
 #[derive(Debug, Clone)]
 pub struct PackageInfo {
   pub name: String,
   pub version: Option<String>,
-  #[allow(unused)]
+  pub commit: Option<String>,
   pub url: String,
   #[allow(unused)]
   pub cache_path: PathBuf,
@@ -28,7 +39,8 @@ pub struct Downloader {
   pub download_text: String,
   pub progress_bar_colors: ((u8, u8, u8), (u8, u8, u8)),
   pub format: String,
-  pub progress_bar_size: usize
+  pub progress_bar_size: usize,
+  pub concurrency: usize,
 }
 
 impl Default for Downloader {
@@ -40,15 +52,66 @@ impl Default for Downloader {
         (137, 216, 139)
       ),
       progress_bar_size: 30,
-      format: "^D \x1b[33m^N\x1b[0m ^P ^C kb / ^T kb".to_string()
+      format: "^D \x1b[33m^N\x1b[0m ^P ^C kb / ^T kb".to_string(),
+      concurrency: std::env::var("LULU_JOBS")
+        .ok()
+        .and_then(|jobs| jobs.parse().ok())
+        .unwrap_or(4),
     }
   }
 }
 
+impl Downloader {
+  pub fn render(&self, name: &str, downloaded: u64, total_size: u64) -> String {
+    let progress_bar = if total_size > 0 {
+      let pct = downloaded as f64 / total_size as f64;
+      let bar_width = self.progress_bar_size;
+      let filled = ((pct * bar_width as f64).round() as usize).min(bar_width);
+      let empty = bar_width - filled;
+
+      let mut bar = String::new();
+      for i in 0..filled {
+        let t = i as f64 / bar_width as f64;
+        let r = (self.progress_bar_colors.0.0 as f64) + ((self.progress_bar_colors.1.0 as f64) - (self.progress_bar_colors.0.0 as f64) ) * t;
+        let g = (self.progress_bar_colors.0.1 as f64) + ((self.progress_bar_colors.1.1 as f64) - (self.progress_bar_colors.0.1 as f64) ) * t;
+        let b = (self.progress_bar_colors.0.2 as f64) + ((self.progress_bar_colors.1.2 as f64) - (self.progress_bar_colors.0.2 as f64) ) * t;
+
+        bar.push_str(&format!(
+          "\x1b[38;2;{};{};{}m#\x1b[0m",
+          r.round() as u8,
+          g.round() as u8,
+          b.round() as u8
+        ));
+      }
+      bar.push_str(&" ".repeat(empty));
+
+      bar
+    } else {
+      "[unknown]".to_string()
+    };
+
+    let downloaded_kb = downloaded / 1024;
+    let total_kb = if total_size > 0 { total_size / 1024 } else { 0 };
+
+    self
+      .format
+      .replace("^D", &self.download_text)
+      .replace("^N", name)
+      .replace("^P", &progress_bar)
+      .replace("^C", &downloaded_kb.to_string())
+      .replace("^T", &total_kb.to_string())
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct PackageManager {
   cache_dir: PathBuf,
   pub downloader: Downloader,
+  pub registry: Option<String>,
+  pub offline: bool,
+  /// Packages whose build scripts run outside the sandbox, `*` for all.
+  pub allow_build_scripts: Vec<String>,
+  progress: MultiProgress,
 }
 
 impl PackageManager {
@@ -59,9 +122,22 @@ impl PackageManager {
     Ok(PackageManager {
       cache_dir,
       downloader: Downloader::default(),
+      registry: std::env::var(REGISTRY_ENV)
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string()),
+      offline: std::env::var("LULU_OFFLINE").is_ok_and(|v| v == "1" || v == "true"),
+      allow_build_scripts: build_sandbox::allowed_build_scripts(),
+      progress: MultiProgress::default(),
     })
   }
 
+  pub fn with_registry(mut self, registry: Option<String>) -> Self {
+    if registry.is_some() {
+      self.registry = registry;
+    }
+    self
+  }
+
   fn get_cache_directory() -> Result<PathBuf> {
     let base = if cfg!(windows) {
       std::env::var("APPDATA")
@@ -84,9 +160,15 @@ impl PackageManager {
     Ok(base.join("lulu"))
   }
 
+  /// Packages fetched for a `--target` other than the host are cached apart,
+  /// as their platform includes differ.
   fn cache_key(&self, url: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(url.as_bytes());
+    let target = Target::from_env();
+    if !target.is_host() {
+      hasher.update(format!("#{}", target).as_bytes());
+    }
     format!("{:x}", hasher.finalize())[..16].to_string()
   }
 
@@ -99,15 +181,65 @@ impl PackageManager {
     cache_path.exists() && cache_path.join("lulu.conf.lua").exists()
   }
 
-  pub async fn install_package(&self, url: &str, project_path: &Path) -> Result<PackageInfo> {
+  pub async fn install_package(
+    &self,
+    dependency: &Dependency,
+    project_path: &Path,
+  ) -> Result<PackageInfo> {
+    let url = dependency.url.as_str();
+
+    if let Some(vendor_path) = Lockfile::load(project_path)?.vendored_path(project_path, url) {
+      let package_info = self.get_package_info(&vendor_path, url)?;
+      self
+        .copy_package_artifacts(&vendor_path, project_path, &package_info)
+        .await?;
+      return Ok(package_info);
+    }
+
     let cache_path = self.get_package_cache_path(url);
+    let sha256 = dependency.sha256.as_deref();
+    let mut fresh = !self.is_cached(url);
+
+    // Entries cached before the pin was added, or under another pin, are
+    // fetched again so the pin is checked.
+    if !fresh
+      && sha256.is_some()
+      && !CacheMeta::load(&cache_path).is_some_and(|meta| meta.verified_for(sha256))
+    {
+      if self.offline {
+        return Err(anyhow!(
+          "\"{}\" is cached without being verified against its sha256 pin (offline mode)",
+          url
+        ));
+      }
+      fs::remove_dir_all(&cache_path)?;
+      fresh = true;
+    }
+
+    if fresh {
+      if self.offline {
+        return Err(anyhow!(
+          "\"{}\" is not in the cache at {} (offline mode)",
+          url,
+          self.cache_dir.display()
+        ));
+      }
+
+      let fetched = self.fetch_package(url, &cache_path, sha256).await;
 
-    if !self.is_cached(url) {
-      self.fetch_package(url, &cache_path).await?;
+      if fetched.is_err() {
+        fs::remove_dir_all(&cache_path).ok();
+      }
+      fetched?;
     }
 
     let package_info = self.get_package_info(&cache_path, url)?;
-    self.build_package(&cache_path).await?;
+    self
+      .build_dependency(&cache_path, &package_info.name)
+      .await?;
+    if fresh || CacheMeta::load(&cache_path).is_none() {
+      self.write_cache_meta(url, &cache_path, sha256)?;
+    }
     self
       .copy_package_artifacts(&cache_path, project_path, &package_info)
       .await?;
@@ -126,35 +258,53 @@ impl PackageManager {
     };
 
     if download_needed {
-      self.download_url(url, &cache_path).await?;
+      self.download_url(url, &cache_path, None).await?;
+      self.write_cache_meta(url, &cache_path, None)?;
     }
 
     Ok(cache_path)
   }
 
-  pub async fn download_url(&self, url: &str, cache_path: &Path) -> Result<()> {
+  pub async fn download_url(
+    &self,
+    url: &str,
+    cache_path: &Path,
+    sha256: Option<&str>,
+  ) -> Result<()> {
     Ok(if url.ends_with(".lulib") {
       create_dirs(cache_path)?;
-      self.download_lulib_package(url, cache_path, None).await?;
+      self
+        .download_lulib_package(url, sha256, cache_path, None)
+        .await?;
     } else if url.ends_with(".zip") {
-      self.download_and_extract_zip(url, cache_path).await?;
+      self.download_and_extract_zip(url, sha256, cache_path).await?;
     } else if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
-      self.download_and_extract_tar_gz(url, cache_path).await?;
+      self
+        .download_and_extract_tar_gz(url, sha256, cache_path)
+        .await?;
     } else {
-      self.download_rogue_file(url, cache_path).await?;
+      self.download_rogue_file(url, sha256, cache_path).await?;
     })
   }
 
-  pub async fn fetch_package(&self, url: &str, cache_path: &Path) -> Result<()> {
+  pub async fn fetch_package(
+    &self,
+    url: &str,
+    cache_path: &Path,
+    sha256: Option<&str>,
+  ) -> Result<()> {
     fs::create_dir_all(cache_path)?;
 
     if url.starts_with("github:") {
-      self.handle_github_repo(url, cache_path).await?;
+      self.handle_github_repo(url, cache_path, sha256).await?;
+    } else if url.starts_with(REGISTRY_PREFIX) {
+      self.fetch_registry_package(url, cache_path, sha256).await?;
     } else if url.starts_with("http://") || url.starts_with("https://") {
       if url.ends_with(".git") {
+        Self::reject_git_sha256(url, sha256)?;
         self.clone_git_repo(url, cache_path).await?;
       } else {
-        self.download_url(url, cache_path).await?
+        self.download_url(url, cache_path, sha256).await?
       }
     } else {
       return Err(anyhow!("Unsupported package source: {}", url));
@@ -163,7 +313,67 @@ impl PackageManager {
     Ok(())
   }
 
-  async fn handle_github_repo(&self, github_url: &str, cache_path: &Path) -> Result<()> {
+  fn reject_git_sha256(url: &str, sha256: Option<&str>) -> Result<()> {
+    if sha256.is_some() {
+      return Err(anyhow!(
+        "Cannot verify sha256 for \"{}\": it is fetched as a git checkout, pin a commit with \"#<commit>\" instead",
+        url
+      ));
+    }
+    Ok(())
+  }
+
+  async fn fetch_registry_package(
+    &self,
+    url: &str,
+    cache_path: &Path,
+    sha256: Option<&str>,
+  ) -> Result<()> {
+    let (name, version) =
+      parse_registry_dep(url).ok_or_else(|| anyhow!("Invalid registry dependency: {}", url))?;
+    let registry = self.registry.as_deref().ok_or_else(|| {
+      anyhow!(
+        "No registry configured for \"{}\", set `registry` in lulu.conf.lua or {}",
+        url,
+        REGISTRY_ENV
+      )
+    })?;
+
+    let index = fetch_index(registry, &name).await?;
+    let (version, entry) = index.resolve(version.as_deref()).ok_or_else(|| {
+      anyhow!(
+        "No version {} of \"{}\" in {}",
+        version.as_deref().unwrap_or("at all"),
+        name,
+        registry
+      )
+    })?;
+
+    let blob = blob_url(registry, &name, version, &entry.file);
+    let bytes = self.download_bytes(&blob, Some(&entry.file)).await?;
+    verify_sha256(&blob, &bytes, Some(&entry.sha256))?;
+    verify_sha256(url, &bytes, sha256)?;
+
+    let lib_dir = cache_path.join(".lib");
+    fs::create_dir_all(&lib_dir)?;
+    fs::write(lib_dir.join(&entry.file), &bytes)?;
+    fs::write(
+      cache_path.join("lulu.conf.lua"),
+      format!(
+        "manifest = {{\n  name = {:?},\n  version = {:?}\n}}\n",
+        name, version
+      ),
+    )?;
+
+    Ok(())
+  }
+
+  async fn handle_github_repo(
+    &self,
+    github_url: &str,
+    cache_path: &Path,
+    sha256: Option<&str>,
+  ) -> Result<()> {
     let github_dep = parse_github_dep(github_url)
       .ok_or_else(|| anyhow!("Invalid GitHub URL format: {}", github_url))?;
 
@@ -177,11 +387,23 @@ impl PackageManager {
 
         if let Ok(Some(fetch)) = load_lulu_fetch_field(&lua, conf_content.clone()) {
           match fetch {
-            FetchField::Code => self.clone_github_repo_code(&github_dep, cache_path).await?,
-            FetchField::Lulib { url, include } => {
+            FetchField::Code => {
+              Self::reject_git_sha256(github_url, sha256)?;
+              self.clone_github_repo_code(&github_dep, cache_path).await?
+            }
+            FetchField::Lulib {
+              url,
+              sha256: lulib_sha256,
+              include,
+            } => {
               create_dirs(cache_path)?;
               self
-                .download_lulib_package(&url, cache_path, include)
+                .download_lulib_package(
+                  &url,
+                  sha256.or(lulib_sha256.as_deref()),
+                  cache_path,
+                  include,
+                )
                 .await?;
 
               let conf_path = cache_path.join("lulu.conf.lua");
@@ -189,11 +411,13 @@ impl PackageManager {
             }
           }
         } else {
+          Self::reject_git_sha256(github_url, sha256)?;
           println!("Repository has no fetch field, cloning to prepare build");
           self.clone_github_repo_code(&github_dep, cache_path).await?
         }
       }
       _ => {
+        Self::reject_git_sha256(github_url, sha256)?;
         println!("Could not fetch lulu.conf.lua from GitHub, falling back to cloning repository");
         self.clone_github_repo_code(&github_dep, cache_path).await?
       }
@@ -205,8 +429,9 @@ impl PackageManager {
   async fn download_lulib_package(
     &self,
     url: &str,
+    sha256: Option<&str>,
     cache_path: &Path,
-    include: Option<HashMap<String, Vec<String>>>,
+    include: Option<HashMap<String, Vec<Dependency>>>,
   ) -> Result<()> {
     // Extract package name from URL
     let pathname = PathBuf::from(url.replace("http://", "").replace("https://", ""));
@@ -219,33 +444,29 @@ impl PackageManager {
     fs::create_dir_all(lulib_path.parent().unwrap())?;
 
     let bytes = self.download_bytes(url, None).await?;
+    verify_sha256(url, &bytes, sha256)?;
     fs::write(&lulib_path, &bytes)?;
 
     if let Some(include_map) = include {
-      let current_platform = self.get_current_platform();
-      if let Some(files) = if let Some(files) = include_map.get(current_platform) {
-        Some(files)
-      } else if let Some(files) =
-        include_map.get(&format!("{}-{}", current_platform, std::env::consts::ARCH))
-      {
-        Some(files)
-      } else {
-        None
-      } {
-        let platform_dir = cache_path.join(current_platform);
+      let target = Target::from_env();
+      if let Some(files) = target.pick(&include_map) {
+        let platform_dir = cache_path.join(target.to_string());
         fs::create_dir_all(&platform_dir)?;
 
-        for file_url in files {
-          let filename = Path::new(file_url)
+        for file in files {
+          let filename = Path::new(&file.url)
             .file_name()
             .unwrap()
             .to_string_lossy()
             .to_string();
           let dest = platform_dir.join(filename);
 
-          if let Ok(bytes) = self.download_bytes(url, None).await {
-            let _ = fs::write(dest, &bytes);
-          }
+          let bytes = self
+            .download_bytes(&file.url, None)
+            .await
+            .with_context(|| format!("Failed to download included file {}", file.url))?;
+          verify_sha256(&file.url, &bytes, file.sha256.as_deref())?;
+          fs::write(dest, &bytes)?;
         }
       }
     }
@@ -304,6 +525,24 @@ impl PackageManager {
     Ok(())
   }
 
+  /// The commit a cloned package is checked out at.
+  pub fn git_head(repo_path: &Path) -> Option<String> {
+    if !repo_path.join(".git").exists() {
+      return None;
+    }
+
+    let output = Command::new("git")
+      .current_dir(repo_path)
+      .args(["rev-parse", "HEAD"])
+      .output()
+      .ok()?;
+
+    output
+      .status
+      .success()
+      .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+  }
+
   fn git_checkout(&self, repo_path: &Path, ref_name: &str) -> Result<()> {
     let output = Command::new("git")
       .current_dir(repo_path)
@@ -321,8 +560,14 @@ impl PackageManager {
     Ok(())
   }
 
-  async fn download_and_extract_zip(&self, url: &str, cache_path: &Path) -> Result<()> {
+  async fn download_and_extract_zip(
+    &self,
+    url: &str,
+    sha256: Option<&str>,
+    cache_path: &Path,
+  ) -> Result<()> {
     let bytes = self.download_bytes(url, None).await?;
+    verify_sha256(url, &bytes, sha256)?;
 
     let temp_file = cache_path.join("download.zip");
     fs::write(&temp_file, &bytes)?;
@@ -350,8 +595,14 @@ impl PackageManager {
     Ok(())
   }
 
-  async fn download_and_extract_tar_gz(&self, url: &str, cache_path: &Path) -> Result<()> {
+  async fn download_and_extract_tar_gz(
+    &self,
+    url: &str,
+    sha256: Option<&str>,
+    cache_path: &Path,
+  ) -> Result<()> {
     let bytes = self.download_bytes(url, None).await?;
+    verify_sha256(url, &bytes, sha256)?;
 
     let decoder = GzDecoder::new(&bytes[..]);
     let mut archive = Archive::new(decoder);
@@ -376,7 +627,10 @@ impl PackageManager {
   }
 
   pub async fn download_bytes(&self, url: &str, name: Option<&str>) -> Result<Vec<u8>> {
-    let response = reqwest::get(url).await?;
+    let response = reqwest::get(url)
+      .await?
+      .error_for_status()
+      .with_context(|| format!("Failed to download {}", url))?;
     let total_size = response.content_length().unwrap_or(0);
     let mut bytes = Vec::with_capacity(total_size as usize);
 
@@ -394,55 +648,41 @@ impl PackageManager {
         .to_string()
     };
 
-    while let Some(chunk) = resp.chunk().await? {
-      bytes.extend_from_slice(&chunk);
-      downloaded += chunk.len() as u64;
-
-      let progress_bar = if total_size > 0 {
-        let pct = downloaded as f64 / total_size as f64;
-        let bar_width = self.downloader.progress_bar_size;
-        let filled = (pct * bar_width as f64).round() as usize;
-        let empty = bar_width - filled;
-
-        let mut bar = String::new();
-        for i in 0..filled {
-          let t = i as f64 / bar_width as f64;
-          let r = (self.downloader.progress_bar_colors.0.0 as f64) + ((self.downloader.progress_bar_colors.1.0 as f64) - (self.downloader.progress_bar_colors.0.0 as f64) ) * t;
-          let g = (self.downloader.progress_bar_colors.0.1 as f64) + ((self.downloader.progress_bar_colors.1.1 as f64) - (self.downloader.progress_bar_colors.0.1 as f64) ) * t;
-          let b = (self.downloader.progress_bar_colors.0.2 as f64) + ((self.downloader.progress_bar_colors.1.2 as f64) - (self.downloader.progress_bar_colors.0.2 as f64) ) * t;
-
-          bar.push_str(&format!(
-            "\x1b[38;2;{};{};{}m#\x1b[0m",
-            r.round() as u8,
-            g.round() as u8,
-            b.round() as u8
-          ));
-        }
-        bar.push_str(&" ".repeat(empty));
-
-        bar
-      } else {
-        "[unknown]".to_string()
-      };
-
-      let downloaded_kb = downloaded / 1024;
-      let total_kb = if total_size > 0 { total_size / 1024 } else { 0 };
+    let id = self.progress.start();
+    let received: Result<()> = async {
+      while let Some(chunk) = resp.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        downloaded += chunk.len() as u64;
 
-      let formatted = self.downloader
-          .format
-          .replace("^D", &self.downloader.download_text)
-          .replace("^N", &name)
-          .replace("^P", &progress_bar)
-          .replace("^C", &downloaded_kb.to_string())
-          .replace("^T", &total_kb.to_string());
-
-      print!("\r{}", formatted);
+        self
+          .progress
+          .update(id, self.downloader.render(&name, downloaded, total_size));
+      }
+      Ok(())
     }
+    .await;
+
+    self.progress.finish(
+      id,
+      self.downloader.render(&name, downloaded, total_size),
+      &format!(
+        "{} {} ({} kb)",
+        self.downloader.download_text,
+        name,
+        downloaded / 1024
+      ),
+    );
+    received?;
 
     Ok(bytes)
   }
 
-  async fn download_rogue_file(&self, url: &str, cache_path: &Path) -> Result<()> {
+  async fn download_rogue_file(
+    &self,
+    url: &str,
+    sha256: Option<&str>,
+    cache_path: &Path,
+  ) -> Result<()> {
     let parsed_url = reqwest::Url::parse(url)?;
 
     let file_name = parsed_url
@@ -455,6 +695,7 @@ impl PackageManager {
     let file_path = cache_path.join(file_name);
 
     let bytes = self.download_bytes(url, Some(file_name)).await?;
+    verify_sha256(url, &bytes, sha256)?;
 
     fs::write(file_path, bytes)?;
 
@@ -484,6 +725,7 @@ impl PackageManager {
     }
 
     let lua = mlua::Lua::new();
+    BuildSandbox::new(cache_path).apply(&lua)?;
     let conf = load_lulu_conf(&lua, conf_path)?;
 
     let name = if let Some(manifest) = &conf.manifest {
@@ -503,27 +745,44 @@ impl PackageManager {
     Ok(PackageInfo {
       name,
       version,
+      commit: Self::git_head(cache_path),
       url: url.to_string(),
       cache_path: cache_path.to_path_buf(),
     })
   }
 
-  pub async fn build_package(&self, cache_path: &Path) -> Result<()> {
+  async fn run_build(&self, cache_path: &Path, sandboxed: bool) -> Result<Option<Output>> {
     let conf_path = cache_path.join("lulu.conf.lua");
 
     if !conf_path.exists() {
-      return Ok(());
+      return Ok(None);
     }
 
     fs::create_dir_all(cache_path.join(".lib/lulib"))?;
     fs::create_dir_all(cache_path.join(".lib/dylib"))?;
 
-    let output = Command::new(std::env::current_exe()?)
+    let mut command = tokio::process::Command::new(std::env::current_exe()?);
+    if self.offline {
+      command.env("LULU_OFFLINE", "1");
+    }
+    if sandboxed {
+      command.env(BUILD_SANDBOX_ENV, cache_path.canonicalize()?);
+    } else {
+      command.env_remove(BUILD_SANDBOX_ENV);
+    }
+    command.env(ALLOW_BUILD_SCRIPTS_ENV, self.allow_build_scripts.join(","));
+
+    let output = command
       .current_dir(cache_path)
-      .args(&["build", "."])
+      .args(["build", "."])
       .output()
+      .await
       .context("Failed to build package")?;
 
+    Ok(Some(output))
+  }
+
+  fn check_build_output(output: &Output) -> Result<()> {
     if !output.status.success() {
       eprintln!("Build output: {}", String::from_utf8_lossy(&output.stdout));
       eprintln!("Build errors: {}", String::from_utf8_lossy(&output.stderr));
@@ -533,14 +792,82 @@ impl PackageManager {
     Ok(())
   }
 
+  /// Builds a project with full access, e.g. the project being published.
+  pub async fn build_package(&self, cache_path: &Path) -> Result<()> {
+    match self.run_build(cache_path, false).await? {
+      Some(output) => Self::check_build_output(&output),
+      None => Ok(()),
+    }
+  }
+
+  /// Builds a dependency inside the build sandbox unless it is trusted with
+  /// `--allow-build-scripts`. When the build needs more than the sandbox
+  /// allows, the user is asked on a terminal, otherwise the install fails.
+  pub async fn build_dependency(&self, cache_path: &Path, name: &str) -> Result<()> {
+    if build_sandbox::is_build_trusted(&self.allow_build_scripts, name) {
+      return self.build_package(cache_path).await;
+    }
+
+    let Some(output) = self.run_build(cache_path, true).await? else {
+      return Ok(());
+    };
+
+    if output.status.code() != Some(BUILD_DENIED_EXIT) {
+      return Self::check_build_output(&output);
+    }
+
+    let reason = String::from_utf8_lossy(&output.stderr)
+      .lines()
+      .find(|line| line.contains("sandboxed dependency build"))
+      .unwrap_or("")
+      .trim()
+      .to_string();
+
+    if Self::confirm_build_script(name, &reason).await {
+      return self.build_package(cache_path).await;
+    }
+
+    Err(anyhow!(
+      "The build script of {} was stopped by the sandbox: {}\nRerun with --allow-build-scripts={} to trust it",
+      name,
+      reason,
+      name
+    ))
+  }
+
+  async fn confirm_build_script(name: &str, reason: &str) -> bool {
+    static PROMPT: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+      return false;
+    }
+
+    let _guard = PROMPT.lock().await;
+    let question = format!(
+      "\n{}\nAllow the build script of {} to run without restrictions? [y/N] ",
+      reason, name
+    );
+
+    tokio::task::spawn_blocking(move || {
+      eprint!("{}", question);
+      std::io::stderr().flush().ok();
+      let mut answer = String::new();
+      std::io::stdin().read_line(&mut answer).ok();
+      matches!(answer.trim(), "y" | "Y" | "yes")
+    })
+    .await
+    .unwrap_or(false)
+  }
+
   async fn copy_package_artifacts(
     &self,
     cache_path: &Path,
     project_path: &Path,
-    _package_info: &PackageInfo,
+    package_info: &PackageInfo,
   ) -> Result<()> {
-
     let (project_lulib_dir, project_dylib_dir) = crate::util::create_lib_folders(project_path)?;
+    let project_lib_dir = project_path.join(".lib");
+    let mut checksums = Checksums::load(&project_lib_dir)?;
 
     let cache_lulib_dir = cache_path.join(".lib");
     if cache_lulib_dir.exists() {
@@ -553,6 +880,7 @@ impl PackageManager {
 
           if !dest_path.exists() {
             fs::copy(&entry.path(), &dest_path)?;
+            checksums.record(&project_lib_dir, &dest_path, &package_info.url)?;
           }
         }
       }
@@ -569,6 +897,7 @@ impl PackageManager {
 
           if !dest_path.exists() {
             fs::copy(&entry.path(), &dest_path)?;
+            checksums.record(&project_lib_dir, &dest_path, &package_info.url)?;
           }
         }
       }
@@ -583,39 +912,174 @@ impl PackageManager {
 
           if !dest_path.exists() {
             fs::copy(&entry.path(), &dest_path)?;
+            checksums.record(&project_lib_dir, &dest_path, &package_info.url)?;
           }
         }
       }
     }
 
+    checksums.save(&project_lib_dir)?;
+
     Ok(())
   }
 
-  fn get_current_platform(&self) -> &'static str {
-    std::env::consts::OS
+  /// The target whose prebuilt dylibs are copied, which is the `--target`
+  /// of the build that resolves the dependencies.
+  fn get_current_platform(&self) -> String {
+    Target::from_env().to_string()
   }
 
   pub async fn install_packages(
     &self,
-    urls: &[String],
+    dependencies: &[Dependency],
     project_path: &Path,
   ) -> Result<Vec<PackageInfo>> {
-    let mut installed_packages = Vec::new();
+    if self.offline {
+      self.check_offline(dependencies, project_path)?;
+    }
 
-    for url in urls {
-      match self.install_package(url, project_path).await {
+    let mut installed_packages = Vec::new();
+    let mut failures = Vec::new();
+
+    let results: Vec<_> = futures_util::stream::iter(dependencies)
+      .map(|dependency| async move {
+        (dependency, self.install_package(dependency, project_path).await)
+      })
+      .buffered(self.downloader.concurrency.max(1))
+      .collect()
+      .await;
+
+    for (dependency, result) in results {
+      match result {
         Ok(package_info) => {
           installed_packages.push(package_info);
         }
-        Err(e) => {
-          eprintln!("Failed to install package '{}': {}", url, e);
-        }
+        Err(e) => failures.push(format!("{}: {:#}", dependency.url, e)),
       }
     }
 
+    let mut lockfile = Lockfile::load(project_path)?;
+    for package_info in &installed_packages {
+      lockfile.record(package_info);
+    }
+    lockfile.save(project_path)?;
+
+    if !failures.is_empty() {
+      return Err(anyhow!(
+        "Failed to install {} of {} packages:\n  {}",
+        failures.len(),
+        dependencies.len(),
+        failures.join("\n  ")
+      ));
+    }
+
     Ok(installed_packages)
   }
 
+  /// Deletes what installing `url` put into the project: its recorded `.lib`
+  /// files, `.lib/lulib/<name>.lulib`, its vendored copy and its lock entry.
+  pub fn remove_package_artifacts(
+    &self,
+    project_path: &Path,
+    url: &str,
+    name: &str,
+  ) -> Result<Vec<String>> {
+    let lib_dir = project_path.join(".lib");
+    let mut checksums = Checksums::load(&lib_dir)?;
+    let mut removed = checksums.remove_source(&lib_dir, url)?;
+    if lib_dir.exists() {
+      checksums.save(&lib_dir)?;
+    }
+
+    let lulib = lib_dir.join("lulib").join(format!("{}.lulib", name));
+    if lulib.exists() {
+      fs::remove_file(&lulib)?;
+      removed.push(format!("lulib/{}.lulib", name));
+    }
+
+    let mut lockfile = Lockfile::load(project_path)?;
+    if let Some(locked) = lockfile.remove(url) {
+      if let Some(vendor) = locked.vendor {
+        let vendor_path = project_path.join(&vendor);
+        if vendor_path.exists() {
+          fs::remove_dir_all(vendor_path)?;
+          removed.push(vendor);
+        }
+      }
+      lockfile.save(project_path)?;
+    }
+
+    removed.dedup();
+    Ok(removed)
+  }
+
+  /// Fails with every dependency that is neither vendored nor cached, so an
+  /// offline resolve stops before installing anything.
+  pub fn check_offline(&self, dependencies: &[Dependency], project_path: &Path) -> Result<()> {
+    let lockfile = Lockfile::load(project_path)?;
+    let missing: Vec<&str> = dependencies
+      .iter()
+      .map(|d| d.url.as_str())
+      .filter(|url| {
+        lockfile.vendored_path(project_path, url).is_none() && !self.is_cached(url)
+      })
+      .collect();
+
+    if !missing.is_empty() {
+      return Err(anyhow!(
+        "Missing from the cache at {} (offline mode): {}",
+        self.cache_dir.display(),
+        missing.join(", ")
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Installs every dependency and copies its built sources into
+  /// `<project>/vendor/<name>`, recording the copy in `lulu.lock`.
+  pub async fn vendor_packages(
+    &self,
+    dependencies: &[Dependency],
+    project_path: &Path,
+  ) -> Result<Vec<PackageInfo>> {
+    if self.offline {
+      self.check_offline(dependencies, project_path)?;
+    }
+
+    let vendor_dir = project_path.join(VENDOR_DIR);
+    let mut lockfile = Lockfile::load(project_path)?;
+    let mut vendored = Vec::new();
+
+    for dependency in dependencies {
+      let package_info = self
+        .install_package(dependency, project_path)
+        .await
+        .with_context(|| format!("Failed to install package '{}'", dependency.url))?;
+      lockfile.record(&package_info);
+
+      if !package_info.cache_path.starts_with(&vendor_dir) {
+        let dest = vendor_dir.join(&package_info.name);
+        if dest.exists() {
+          fs::remove_dir_all(&dest)?;
+        }
+        crate::util::copy_recursively(&package_info.cache_path, &dest)?;
+        fs::remove_dir_all(dest.join(".git")).ok();
+        fs::remove_file(dest.join(cache::CACHE_META_FILE)).ok();
+
+        if let Some(locked) = lockfile.packages.iter_mut().find(|p| p.url == dependency.url) {
+          locked.vendor = Some(format!("{}/{}", VENDOR_DIR, package_info.name));
+        }
+      }
+
+      vendored.push(package_info);
+    }
+
+    lockfile.save(project_path)?;
+
+    Ok(vendored)
+  }
+
   pub fn clear_cache(&self) -> Result<()> {
     if self.cache_dir.exists() {
       fs::remove_dir_all(&self.cache_dir)?;
@@ -632,29 +1096,63 @@ impl PackageManager {
     Ok(())
   }
 
-  pub fn list_cached_packages(&self) -> Result<Vec<String>> {
-    let mut packages = Vec::new();
+  /// Records where a cache entry came from, so `lulu cache list` and
+  /// `lulu cache prune` do not have to guess from the hashed directory name,
+  /// and the `sha256` pin it was verified against.
+  pub fn write_cache_meta(&self, url: &str, cache_path: &Path, sha256: Option<&str>) -> Result<()> {
+    let info = self.get_package_info(cache_path, url).ok();
+    CacheMeta {
+      url: url.to_string(),
+      name: info.as_ref().map(|info| info.name.clone()),
+      version: info.and_then(|info| info.version),
+      fetched_at: cache::now(),
+      size: cache::dir_size(cache_path),
+      sha256: sha256.map(|digest| digest.trim().to_string()),
+    }
+    .save(cache_path)
+  }
+
+  pub fn cache_entries(&self) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
 
     if !self.cache_dir.exists() {
-      return Ok(packages);
+      return Ok(entries);
     }
 
     for entry in fs::read_dir(&self.cache_dir)? {
       let entry = entry?;
       if entry.file_type()?.is_dir() {
-        let conf_path = entry.path().join("lulu.conf.lua");
-        if conf_path.exists() {
-          if let Ok(package_info) = self.get_package_info(&entry.path(), "unknown") {
-            packages.push(format!(
-              "{} ({})",
-              package_info.name,
-              package_info.version.unwrap_or("unknown".to_string())
-            ));
+        let mut cached = cache::read_entry(&entry.path());
+        if cached.meta.name.is_none() {
+          if let Ok(info) = self.get_package_info(&entry.path(), "unknown") {
+            cached.meta.name = Some(info.name);
+            cached.meta.version = info.version;
           }
         }
+        entries.push(cached);
+      }
+    }
+
+    entries.sort_by(|a, b| a.meta.name.cmp(&b.meta.name).then(a.key.cmp(&b.key)));
+    Ok(entries)
+  }
+
+  /// Removes entries fetched longer than `older_than` ago, then the oldest
+  /// entries until the cache is at most `max_size` bytes.
+  pub fn prune_cache(
+    &self,
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+    dry_run: bool,
+  ) -> Result<Vec<CacheEntry>> {
+    let pruned = cache::select_prunable(self.cache_entries()?, older_than, max_size);
+
+    if !dry_run {
+      for entry in &pruned {
+        fs::remove_dir_all(&entry.path)?;
       }
     }
 
-    Ok(packages)
+    Ok(pruned)
   }
 }
@@ -0,0 +1,562 @@
+//! What a script may touch outside of its own VM. `lulu run` starts
+//! unrestricted unless it is given `--allow-*` flags or the entry project's
+//! `lulu.conf.lua` (or the main package of a bundle) has a `permissions`
+//! block; from then on the ops that read or write files, reach the network,
+//! run programs, read the environment or load native code check what was
+//! granted and fail naming the missing flag.
+
+use crate::build_sandbox::resolve;
+use mlua::Lua;
+use std::path::Path;
+
+/// Matches every target of a permission, as in `--allow-read` without a value.
+pub const ALLOW_ALL: &str = "*";
+
+/// The targets granted for each kind of access. An empty list grants
+/// nothing and [`ALLOW_ALL`] grants everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Permissions {
+  /// Paths that may be read, with everything under them.
+  pub read: Vec<String>,
+  /// Paths that may be created, written, renamed or removed.
+  pub write: Vec<String>,
+  /// Hosts, optionally with a port, that may be connected to or listened on.
+  pub net: Vec<String>,
+  /// Programs that may be run.
+  pub run: Vec<String>,
+  /// Environment variables that may be read.
+  pub env: Vec<String>,
+  /// Whether `ffi` and native modules may be loaded, granted by any entry.
+  pub ffi: Vec<String>,
+}
+
+const KINDS: [&str; 6] = ["read", "write", "net", "run", "env", "ffi"];
+
+/// Makes every way of loading code take source only, along with the
+/// searcher `require` uses for Lua files.
+const TEXT_ONLY: &str = r#"
+local load, loadfile, searchpath = load, loadfile, package.searchpath
+
+_G.load = function(chunk, name, _, env)
+  if env ~= nil then
+    return load(chunk, name, "t", env)
+  end
+  return load(chunk, name, "t")
+end
+_G.loadstring = function(chunk, name)
+  return load(chunk, name, "t")
+end
+_G.loadfile = function(path, _, env)
+  if env ~= nil then
+    return loadfile(path, "t", env)
+  end
+  return loadfile(path, "t")
+end
+_G.dofile = function(path)
+  local f = assert(_G.loadfile(path))
+  return f()
+end
+
+package.loaders[2] = function(name)
+  local path, err = searchpath(name, package.path)
+  if not path then
+    return err
+  end
+  local f, load_err = _G.loadfile(path)
+  if not f then
+    error("error loading module '" .. name .. "' from file '" .. path .. "':\n\t" .. load_err, 2)
+  end
+  return f
+end
+"#;
+
+/// Takes away `debug` and `jit`, which read and write the VM's memory, and
+/// loading of bytecode, which escapes LuaJIT when crafted.
+pub(crate) fn source_only(lua: &Lua) -> mlua::Result<()> {
+  let globals = lua.globals();
+  let package: mlua::Table = globals.get("package")?;
+  let loaded: mlua::Table = package.get("loaded")?;
+  let preload: mlua::Table = package.get("preload")?;
+  for name in ["debug", "jit", "jit.util", "jit.profile"] {
+    globals.set(name, mlua::Value::Nil)?;
+    loaded.set(name, mlua::Value::Nil)?;
+    preload.set(name, mlua::Value::Nil)?;
+  }
+  lua.load(TEXT_ONLY).set_name("lulu::permissions").exec()
+}
+
+fn denied(kind: &str, what: String) -> mlua::Error {
+  mlua::Error::RuntimeError(format!("Missing permission --allow-{}: {}", kind, what))
+}
+
+fn allows_all(grants: &[String]) -> bool {
+  grants.iter().any(|grant| grant == ALLOW_ALL)
+}
+
+/// Splits `https://host:port/path`, `host:port` or `host` into the host and
+/// the port, if one is given or implied by the scheme.
+fn net_target(target: &str) -> (String, Option<u16>) {
+  if let Ok(url) = reqwest::Url::parse(target)
+    && let Some(host) = url.host_str()
+  {
+    return (
+      host.trim_matches(['[', ']']).to_lowercase(),
+      url.port_or_known_default(),
+    );
+  }
+
+  match target.rsplit_once(':') {
+    Some((host, port)) if port.parse::<u16>().is_ok() => (
+      host.trim_matches(['[', ']']).to_lowercase(),
+      port.parse().ok(),
+    ),
+    _ => (target.trim_matches(['[', ']']).to_lowercase(), None),
+  }
+}
+
+impl Permissions {
+  /// Grants everything, which is how scripts run without any `--allow-*` flag.
+  pub fn all() -> Self {
+    let all = vec![ALLOW_ALL.to_string()];
+    Permissions {
+      read: all.clone(),
+      write: all.clone(),
+      net: all.clone(),
+      run: all.clone(),
+      env: all.clone(),
+      ffi: all,
+    }
+  }
+
+  pub fn is_all(&self) -> bool {
+    [
+      &self.read,
+      &self.write,
+      &self.net,
+      &self.run,
+      &self.env,
+      &self.ffi,
+    ]
+    .into_iter()
+    .all(|grants| allows_all(grants))
+  }
+
+  fn grants(&self, kind: &str) -> &Vec<String> {
+    match kind {
+      "read" => &self.read,
+      "write" => &self.write,
+      "net" => &self.net,
+      "run" => &self.run,
+      "env" => &self.env,
+      _ => &self.ffi,
+    }
+  }
+
+  fn grants_mut(&mut self, kind: &str) -> &mut Vec<String> {
+    match kind {
+      "read" => &mut self.read,
+      "write" => &mut self.write,
+      "net" => &mut self.net,
+      "run" => &mut self.run,
+      "env" => &mut self.env,
+      _ => &mut self.ffi,
+    }
+  }
+
+  fn check_path(&self, kind: &str, path: &Path) -> mlua::Result<()> {
+    let grants = self.grants(kind);
+    if allows_all(grants) {
+      return Ok(());
+    }
+
+    let cwd = std::env::current_dir()?;
+    let target = resolve(&cwd.join(path));
+    if grants
+      .iter()
+      .any(|grant| target.starts_with(resolve(&cwd.join(grant))))
+    {
+      Ok(())
+    } else {
+      Err(denied(
+        kind,
+        format!("cannot {} \"{}\"", kind, path.display()),
+      ))
+    }
+  }
+
+  pub fn check_read(&self, path: impl AsRef<Path>) -> mlua::Result<()> {
+    self.check_path("read", path.as_ref())
+  }
+
+  pub fn check_write(&self, path: impl AsRef<Path>) -> mlua::Result<()> {
+    self.check_path("write", path.as_ref())
+  }
+
+  /// Checks a URL or a `host:port` address. A granted host without a port
+  /// allows every port of it.
+  pub fn check_net(&self, target: &str) -> mlua::Result<()> {
+    if allows_all(&self.net) {
+      return Ok(());
+    }
+
+    let (host, port) = net_target(target);
+    let granted = self.net.iter().any(|grant| {
+      let (grant_host, grant_port) = net_target(grant);
+      grant_host == host && (grant_port.is_none() || grant_port == port)
+    });
+    if granted {
+      Ok(())
+    } else {
+      Err(denied(
+        "net",
+        format!("cannot connect to or listen on \"{}\"", target),
+      ))
+    }
+  }
+
+  pub fn check_run(&self, program: &str) -> mlua::Result<()> {
+    if allows_all(&self.run) || self.run.iter().any(|grant| grant == program) {
+      Ok(())
+    } else {
+      Err(denied("run", format!("cannot run \"{}\"", program)))
+    }
+  }
+
+  pub fn check_env(&self, key: &str) -> mlua::Result<()> {
+    if allows_all(&self.env) || self.env.iter().any(|grant| grant == key) {
+      Ok(())
+    } else {
+      Err(denied("env", format!("cannot read \"{}\"", key)))
+    }
+  }
+
+  pub fn check_ffi(&self, what: &str) -> mlua::Result<()> {
+    if self.ffi.is_empty() {
+      Err(denied(
+        "ffi",
+        format!("cannot load native code with {}", what),
+      ))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// The `permissions` block of a `lulu.conf.lua`, as written by `conf_to_string`.
+  pub fn to_lua_string(&self) -> String {
+    let mut out = String::from("{ ");
+    for kind in KINDS {
+      let grants = self.grants(kind);
+      if grants.is_empty() {
+        continue;
+      }
+      if allows_all(grants) {
+        out.push_str(&format!("{} = true, ", kind));
+      } else {
+        let list: Vec<String> = grants.iter().map(|grant| format!("{:?}", grant)).collect();
+        out.push_str(&format!("{} = {{ {} }}, ", kind, list.join(", ")));
+      }
+    }
+    out.push('}');
+    out
+  }
+
+  /// Stores the permissions for the ops of `lua` and, unless everything is
+  /// granted, replaces the parts of the Lua standard library that reach
+  /// outside of the VM with checked versions.
+  pub fn apply(&self, lua: &Lua) -> mlua::Result<()> {
+    lua.set_app_data(self.clone());
+    if self.is_all() {
+      return Ok(());
+    }
+
+    let globals = lua.globals();
+    let io: mlua::Table = globals.get("io")?;
+    let os: mlua::Table = globals.get("os")?;
+    let package: mlua::Table = globals.get("package")?;
+
+    let open: mlua::Function = io.get("open")?;
+    io.set(
+      "open",
+      lua.create_function(move |lua, (path, mode): (String, Option<String>)| {
+        if mode
+          .as_deref()
+          .is_some_and(|mode| mode.contains(['w', 'a', '+']))
+        {
+          check_write(lua, &path)?;
+        }
+        if mode.as_deref().is_none_or(|mode| mode.contains(['r', '+'])) {
+          check_read(lua, &path)?;
+        }
+        open.call::<mlua::MultiValue>((path, mode))
+      })?,
+    )?;
+
+    for (table, name) in [
+      (&io, "lines"),
+      (&io, "input"),
+      (&globals, "dofile"),
+      (&globals, "loadfile"),
+    ] {
+      let original: mlua::Function = table.get(name)?;
+      table.set(
+        name,
+        lua.create_function(move |lua, args: mlua::MultiValue| {
+          if let Some(mlua::Value::String(path)) = args.front() {
+            check_read(lua, path.to_str()?.as_ref())?;
+          }
+          original.call::<mlua::MultiValue>(args)
+        })?,
+      )?;
+    }
+
+    let output: mlua::Function = io.get("output")?;
+    io.set(
+      "output",
+      lua.create_function(move |lua, args: mlua::MultiValue| {
+        if let Some(mlua::Value::String(path)) = args.front() {
+          check_write(lua, path.to_str()?.as_ref())?;
+        }
+        output.call::<mlua::MultiValue>(args)
+      })?,
+    )?;
+
+    let remove: mlua::Function = os.get("remove")?;
+    os.set(
+      "remove",
+      lua.create_function(move |lua, path: String| {
+        check_write(lua, &path)?;
+        remove.call::<mlua::MultiValue>(path)
+      })?,
+    )?;
+
+    let rename: mlua::Function = os.get("rename")?;
+    os.set(
+      "rename",
+      lua.create_function(move |lua, (from, to): (String, String)| {
+        check_write(lua, &from)?;
+        check_write(lua, &to)?;
+        rename.call::<mlua::MultiValue>((from, to))
+      })?,
+    )?;
+
+    for (table, name) in [(&os, "execute"), (&io, "popen")] {
+      let original: mlua::Function = table.get(name)?;
+      table.set(
+        name,
+        lua.create_function(move |lua, args: mlua::MultiValue| {
+          if let Some(mlua::Value::String(command)) = args.front() {
+            let command = command.to_str()?;
+            let program = crate::ops::process::split_command(&command)
+              .into_iter()
+              .next()
+              .unwrap_or_default();
+            check_run(lua, &program)?;
+          }
+          original.call::<mlua::MultiValue>(args)
+        })?,
+      )?;
+    }
+
+    let getenv: mlua::Function = os.get("getenv")?;
+    os.set(
+      "getenv",
+      lua.create_function(move |lua, key: String| {
+        check_env(lua, &key)?;
+        getenv.call::<mlua::MultiValue>(key)
+      })?,
+    )?;
+
+    if self.ffi.is_empty() {
+      let loadlib: mlua::Function = package.get("loadlib")?;
+      package.set(
+        "loadlib",
+        lua.create_function(move |lua, args: mlua::MultiValue| {
+          check_ffi(lua, "package.loadlib()")?;
+          loadlib.call::<mlua::MultiValue>(args)
+        })?,
+      )?;
+      package.set("cpath", "")?;
+
+      // Indexing `ffi` fails with the missing permission instead of `nil`.
+      let ffi = lua.create_table()?;
+      let meta = lua.create_table()?;
+      meta.set(
+        "__index",
+        lua.create_function(|lua, (_, key): (mlua::Value, String)| {
+          check_ffi(lua, &format!("ffi.{}", key))?;
+          Ok(mlua::Value::Nil)
+        })?,
+      )?;
+      ffi.set_metatable(Some(meta))?;
+      globals.set("ffi", &ffi)?;
+      let loaded: mlua::Table = package.get("loaded")?;
+      let preload: mlua::Table = package.get("preload")?;
+      loaded.set("ffi", &ffi)?;
+      preload.set("ffi", mlua::Value::Nil)?;
+
+      source_only(lua)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl mlua::FromLua for Permissions {
+  fn from_lua(value: mlua::Value, _: &Lua) -> mlua::Result<Self> {
+    let table = match value {
+      mlua::Value::Table(table) => table,
+      other => {
+        return Err(mlua::Error::FromLuaConversionError {
+          from: other.type_name(),
+          to: "Permissions".to_string(),
+          message: Some("permissions must be a table".to_string()),
+        });
+      }
+    };
+
+    let mut permissions = Permissions::default();
+    for pair in table.pairs::<String, mlua::Value>() {
+      let (kind, value) = pair?;
+      if !KINDS.contains(&kind.as_str()) {
+        return Err(mlua::Error::RuntimeError(format!(
+          "Unknown permission '{}', expected one of {}",
+          kind,
+          KINDS.join(", ")
+        )));
+      }
+      *permissions.grants_mut(&kind) = match value {
+        mlua::Value::Boolean(true) => vec![ALLOW_ALL.to_string()],
+        mlua::Value::Boolean(false) | mlua::Value::Nil => Vec::new(),
+        mlua::Value::String(grant) => vec![grant.to_str()?.to_string()],
+        mlua::Value::Table(grants) => grants
+          .sequence_values::<String>()
+          .collect::<mlua::Result<Vec<_>>>()?,
+        other => {
+          return Err(mlua::Error::RuntimeError(format!(
+            "Permission '{}' must be a boolean, a string or a list, got {}",
+            kind,
+            other.type_name()
+          )));
+        }
+      };
+    }
+    Ok(permissions)
+  }
+}
+
+fn with_permissions(
+  lua: &Lua,
+  check: impl FnOnce(&Permissions) -> mlua::Result<()>,
+) -> mlua::Result<()> {
+  match lua.app_data_ref::<Permissions>() {
+    Some(permissions) => check(&permissions),
+    None => Ok(()),
+  }
+}
+
+pub fn check_read(lua: &Lua, path: impl AsRef<Path>) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_read(path))
+}
+
+pub fn check_write(lua: &Lua, path: impl AsRef<Path>) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_write(path))
+}
+
+pub fn check_net(lua: &Lua, target: &str) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_net(target))
+}
+
+pub fn check_run(lua: &Lua, program: &str) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_run(program))
+}
+
+pub fn check_env(lua: &Lua, key: &str) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_env(key))
+}
+
+pub fn check_ffi(lua: &Lua, what: &str) -> mlua::Result<()> {
+  with_permissions(lua, |permissions| permissions.check_ffi(what))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_checks() {
+    let permissions = Permissions {
+      read: vec!["./data".to_string()],
+      net: vec!["api.internal".to_string(), "127.0.0.1:8080".to_string()],
+      run: vec!["git".to_string()],
+      ..Default::default()
+    };
+
+    assert!(permissions.check_read("data/users.json").is_ok());
+    assert!(permissions.check_read("./data/../secret").is_err());
+    let err = permissions
+      .check_write("data/out.json")
+      .unwrap_err()
+      .to_string();
+    assert!(err.contains("--allow-write"), "{}", err);
+
+    assert!(
+      permissions
+        .check_net("https://api.internal/v1/users")
+        .is_ok()
+    );
+    assert!(permissions.check_net("api.internal:9000").is_ok());
+    assert!(permissions.check_net("127.0.0.1:8080").is_ok());
+    assert!(permissions.check_net("127.0.0.1:8081").is_err());
+    assert!(permissions.check_net("wss://example.com").is_err());
+
+    assert!(permissions.check_run("git").is_ok());
+    assert!(permissions.check_run("rm").is_err());
+    assert!(permissions.check_env("HOME").is_err());
+    assert!(Permissions::all().check_env("HOME").is_ok());
+
+    let lua = Lua::new();
+    let parsed: Permissions = lua.load(permissions.to_lua_string()).eval().unwrap();
+    assert_eq!(parsed, permissions);
+    assert!(lua.load("{ disk = true }").eval::<Permissions>().is_err());
+
+    #[cfg(unix)]
+    {
+      let root = std::env::temp_dir().join(format!("lulu-permissions-{}", std::process::id()));
+      let data = root.join("data");
+      std::fs::create_dir_all(&data).unwrap();
+      std::fs::create_dir_all(root.join("outside")).unwrap();
+      std::os::unix::fs::symlink(root.join("outside"), data.join("x")).unwrap();
+      std::os::unix::fs::symlink(root.join("outside/new"), data.join("dangling")).unwrap();
+      let permissions = Permissions {
+        read: vec![data.display().to_string()],
+        write: vec![data.display().to_string()],
+        ..Default::default()
+      };
+
+      assert!(permissions.check_read(data.join("file")).is_ok());
+      assert!(permissions.check_read(data.join("x/secret")).is_err());
+      assert!(
+        permissions
+          .check_read(data.join("x/../outside/secret"))
+          .is_err()
+      );
+      assert!(permissions.check_write(data.join("dangling")).is_err());
+      std::fs::remove_dir_all(root).ok();
+    }
+
+    let lua = unsafe { Lua::unsafe_new() };
+    permissions.apply(&lua).unwrap();
+    let escapes: bool = lua
+      .load(
+        "return debug ~= nil or jit ~= nil or pcall(require, 'jit.util') \
+         or load(string.dump(function() end)) ~= nil",
+      )
+      .eval()
+      .unwrap();
+    assert!(!escapes);
+    assert_eq!(
+      lua.load("return load('return 1')()").eval::<i64>().unwrap(),
+      1
+    );
+  }
+}
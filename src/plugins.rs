@@ -0,0 +1,200 @@
+//! Native plugins: cdylibs built against the `lulu` crate that add std
+//! modules without rebuilding lulu itself.
+//!
+//! A plugin exports its modules with [`export_plugin!`](crate::export_plugin)
+//! and is listed in the `plugins` of a `lulu.conf.lua`. Rust has no stable
+//! ABI, so a plugin has to be built against the same `lulu` version, with
+//! the same toolchain and dependency versions, as the lulu that loads it.
+//!
+//! A plugin carries its own copy of lulu and mlua. The host holds its mlua
+//! lock while Lua runs, which the plugin's copy can't re-enter, so the host
+//! only takes the names, macros and dependencies of the modules and the
+//! plugin registers their functions through a `Lua` handle of its own.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, c_char};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use mlua::Lua;
+
+use crate::ops::std::STDModule;
+
+pub const LULU_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The symbol [`export_plugin!`](crate::export_plugin) defines.
+const PLUGIN_SYMBOL: &[u8] = b"LULU_PLUGIN\0";
+
+/// Registers the module named by the second argument into the VM, returning
+/// null or an error message.
+pub type PluginOpen =
+  unsafe extern "C-unwind" fn(*mut mlua::lua_State, *const c_char) -> *mut c_char;
+
+pub struct PluginDeclaration {
+  /// The version of `lulu` the plugin was built against.
+  pub lulu_version: &'static str,
+  pub register: fn() -> Vec<STDModule>,
+  pub open: PluginOpen,
+}
+
+/// Exports the function that creates the modules of a plugin.
+///
+/// ```ignore
+/// use lulu::ops::std::{STDModule, create_std_module};
+///
+/// fn register() -> Vec<STDModule> {
+///   vec![create_std_module("hello").add_function("greet", |_, name: String| {
+///     Ok(format!("hello {}", name))
+///   })]
+/// }
+///
+/// lulu::export_plugin!(register);
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+  ($register:path) => {
+    #[unsafe(no_mangle)]
+    pub static LULU_PLUGIN: $crate::plugins::PluginDeclaration =
+      $crate::plugins::PluginDeclaration {
+        lulu_version: $crate::plugins::LULU_VERSION,
+        register: $register,
+        open: {
+          unsafe extern "C-unwind" fn open(
+            state: *mut $crate::mlua::lua_State,
+            name: *const ::std::ffi::c_char,
+          ) -> *mut ::std::ffi::c_char {
+            unsafe { $crate::plugins::open_module(state, name, $register) }
+          }
+          open
+        },
+      };
+  };
+}
+
+/// The `open` of [`export_plugin!`](crate::export_plugin), which runs in the
+/// plugin's copy of lulu.
+///
+/// # Safety
+/// `state` has to be a live Lua state and `name` a nul terminated string.
+#[doc(hidden)]
+#[allow(unused)]
+pub unsafe fn open_module(
+  state: *mut mlua::lua_State,
+  name: *const c_char,
+  register: fn() -> Vec<STDModule>,
+) -> *mut c_char {
+  // The handle lives in the registry of the VM and goes with it.
+  let lua = unsafe { Lua::get_or_init_from_ptr(state) };
+  let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+
+  let result = match register().into_iter().find(|module| module.name == name) {
+    Some(module) => module.register(lua),
+    None => Err(mlua::Error::RuntimeError(format!(
+      "Plugin has no module '{}'",
+      name
+    ))),
+  };
+  match result {
+    Ok(()) => std::ptr::null_mut(),
+    Err(e) => CString::new(e.to_string().replace('\0', ""))
+      .unwrap()
+      .into_raw(),
+  }
+}
+
+/// A module of the plugin as the host registers it, with `open` filling in
+/// the functions.
+fn host_module(module: STDModule, open: PluginOpen) -> STDModule {
+  let name = module.name.clone();
+  let mut host = STDModule::new(&name);
+  host.deps = module.deps;
+  host.macros = module.macros;
+
+  host.on_register(move |lua, _| {
+    let cname = CString::new(name.as_str()).map_err(mlua::Error::external)?;
+    let mut error = std::ptr::null_mut();
+    unsafe {
+      lua.exec_raw::<()>((), |state| {
+        error = open(state, cname.as_ptr());
+      })?;
+    }
+    if !error.is_null() {
+      let error = unsafe { CString::from_raw(error) };
+      return Err(mlua::Error::RuntimeError(format!(
+        "Plugin module '{}': {}",
+        name,
+        error.to_string_lossy()
+      )));
+    }
+    lua.globals().get(name.as_str())
+  })
+}
+
+lazy_static::lazy_static! {
+  /// The module names of every plugin loaded so far, keyed by path.
+  static ref LOADED_PLUGINS: Mutex<HashMap<PathBuf, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Loads the plugin at `path` and registers its modules as std modules,
+/// returning their names. A plugin is only loaded once per process.
+pub fn load_plugin(path: &Path) -> mlua::Result<Vec<String>> {
+  let path = std::fs::canonicalize(path).map_err(|e| {
+    mlua::Error::RuntimeError(format!("Plugin {} not found: {}", path.display(), e))
+  })?;
+
+  let mut loaded = LOADED_PLUGINS.lock().unwrap();
+  if let Some(names) = loaded.get(&path) {
+    return Ok(names.clone());
+  }
+
+  // SAFETY: plugins are native code the project asked to load, running
+  // their initializers is what loading them means.
+  let library = unsafe { libloading::Library::new(&path) }.map_err(mlua::Error::external)?;
+  let declaration = unsafe {
+    let symbol = library
+      .get::<*const PluginDeclaration>(PLUGIN_SYMBOL)
+      .map_err(|_| {
+        mlua::Error::RuntimeError(format!(
+          "{} is not a lulu plugin, it exports no LULU_PLUGIN",
+          path.display()
+        ))
+      })?;
+    &**symbol
+  };
+
+  if declaration.lulu_version != LULU_VERSION {
+    return Err(mlua::Error::RuntimeError(format!(
+      "Plugin {} was built against lulu {}, but this is lulu {}",
+      path.display(),
+      declaration.lulu_version,
+      LULU_VERSION
+    )));
+  }
+
+  let mut names = Vec::new();
+  for module in (declaration.register)() {
+    names.push(module.name.clone());
+    host_module(module, declaration.open).into();
+  }
+
+  // The modules call into the library, so it stays loaded for good.
+  std::mem::forget(library);
+  loaded.insert(path, names.clone());
+  Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_plugin_errors() {
+    let missing = load_plugin(Path::new("/nonexistent/libmissing.so")).unwrap_err();
+    assert!(missing.to_string().contains("not found"));
+
+    let path = std::env::temp_dir().join(format!("lulu-plugin-{}.so", std::process::id()));
+    std::fs::write(&path, b"not a library").unwrap();
+    assert!(load_plugin(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+  }
+}
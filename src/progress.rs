@@ -0,0 +1,87 @@
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct ProgressState {
+  lines: Vec<(String, bool)>,
+  drawn: usize,
+}
+
+/// Keeps one line per running download. On a terminal every line is redrawn
+/// in place; otherwise each download prints a single plain line when it ends.
+#[derive(Debug, Clone)]
+pub struct MultiProgress {
+  state: Arc<Mutex<ProgressState>>,
+  tty: bool,
+}
+
+impl Default for MultiProgress {
+  fn default() -> Self {
+    MultiProgress {
+      state: Arc::new(Mutex::new(ProgressState::default())),
+      tty: std::io::stdout().is_terminal(),
+    }
+  }
+}
+
+impl MultiProgress {
+  pub fn start(&self) -> usize {
+    if !self.tty {
+      return 0;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    state.lines.push((String::new(), false));
+    state.lines.len() - 1
+  }
+
+  pub fn update(&self, id: usize, line: String) {
+    if !self.tty {
+      return;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    if let Some(slot) = state.lines.get_mut(id) {
+      slot.0 = line;
+    }
+    Self::redraw(&mut state);
+  }
+
+  /// Marks a download as done, showing `line` on a terminal or printing
+  /// `plain` otherwise. Once every download is done the next one starts a
+  /// new block below the finished lines.
+  pub fn finish(&self, id: usize, line: String, plain: &str) {
+    if !self.tty {
+      println!("{}", plain);
+      return;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    if let Some(slot) = state.lines.get_mut(id) {
+      *slot = (line, true);
+    }
+    Self::redraw(&mut state);
+
+    if state.lines.iter().all(|(_, done)| *done) {
+      state.lines.clear();
+      state.drawn = 0;
+    }
+  }
+
+  fn redraw(state: &mut ProgressState) {
+    let mut out = String::new();
+    if state.drawn > 0 {
+      out.push_str(&format!("\x1b[{}A", state.drawn));
+    }
+    for (line, _) in &state.lines {
+      out.push_str("\r\x1b[2K");
+      out.push_str(line);
+      out.push('\n');
+    }
+    state.drawn = state.lines.len();
+
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+  }
+}
@@ -0,0 +1,436 @@
+use anyhow::{Context, Result, anyhow};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Bytes;
+use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::integrity::sha256_hex;
+use crate::lulibs::net::{ServeError, serve_http, to_serve_error};
+
+pub const REGISTRY_PREFIX: &str = "registry:";
+pub const REGISTRY_ENV: &str = "LULU_REGISTRY";
+pub const REGISTRY_TOKEN_ENV: &str = "LULU_REGISTRY_TOKEN";
+pub const DEFAULT_MAX_UPLOAD_SIZE: &str = "64M";
+
+/// How `lulu registry serve` guards publishing.
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+  /// When set, publishing requires `Authorization: Bearer <token>`.
+  pub token: Option<String>,
+  /// Accepts uploads without a token; without it, a registry with no token is read-only.
+  pub allow_anonymous_publish: bool,
+  /// The largest artifact accepted, in bytes.
+  pub max_upload_size: usize,
+}
+
+/// The published versions of one package, served as `index/<name>.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PackageIndex {
+  pub name: String,
+  #[serde(default)]
+  pub versions: BTreeMap<String, PackageVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageVersion {
+  pub file: String,
+  pub sha256: String,
+}
+
+impl PackageIndex {
+  pub fn latest(&self) -> Option<(&String, &PackageVersion)> {
+    self
+      .versions
+      .iter()
+      .max_by(|a, b| compare_versions(a.0, b.0))
+  }
+
+  pub fn resolve(&self, version: Option<&str>) -> Option<(&String, &PackageVersion)> {
+    match version {
+      Some(version) => self.versions.get_key_value(version),
+      None => self.latest(),
+    }
+  }
+}
+
+/// Compares dotted versions numerically where possible, so `1.10.0` sorts after `1.9.0`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+  let mut a_parts = a.split('.');
+  let mut b_parts = b.split('.');
+
+  loop {
+    match (a_parts.next(), b_parts.next()) {
+      (None, None) => return Ordering::Equal,
+      (Some(_), None) => return Ordering::Greater,
+      (None, Some(_)) => return Ordering::Less,
+      (Some(x), Some(y)) => {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+          (Ok(x), Ok(y)) => x.cmp(&y),
+          _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+          return ordering;
+        }
+      }
+    }
+  }
+}
+
+/// Splits `registry:name@version` into its name and optional version.
+pub fn parse_registry_dep(s: &str) -> Option<(String, Option<String>)> {
+  let rest = s.strip_prefix(REGISTRY_PREFIX)?;
+  let (name, version) = match rest.split_once('@') {
+    Some((name, version)) => (name, Some(version.to_string())),
+    None => (rest, None),
+  };
+
+  if !is_valid_segment(name) || version.as_deref().is_some_and(|v| !is_valid_segment(v)) {
+    return None;
+  }
+
+  Some((name.to_string(), version))
+}
+
+fn is_valid_segment(segment: &str) -> bool {
+  !segment.is_empty()
+    && !segment.starts_with('.')
+    && segment
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// The registry a project installs from and publishes to: `registry` in its
+/// `lulu.conf.lua`, falling back to the `LULU_REGISTRY` environment variable.
+pub fn registry_for_project(project_path: &Path) -> Option<String> {
+  let conf_path = project_path.join("lulu.conf.lua");
+  let from_conf = if conf_path.exists() {
+    crate::conf::load_lulu_conf(&mlua::Lua::new(), conf_path)
+      .ok()
+      .and_then(|conf| conf.registry)
+  } else {
+    None
+  };
+
+  from_conf
+    .or_else(|| std::env::var(REGISTRY_ENV).ok())
+    .map(|url| url.trim_end_matches('/').to_string())
+}
+
+pub fn index_url(registry: &str, name: &str) -> String {
+  format!("{}/index/{}.json", registry, name)
+}
+
+pub fn blob_url(registry: &str, name: &str, version: &str, file: &str) -> String {
+  format!("{}/blobs/{}/{}/{}", registry, name, version, file)
+}
+
+pub async fn fetch_index(registry: &str, name: &str) -> Result<PackageIndex> {
+  let response = reqwest::get(index_url(registry, name)).await?;
+  if response.status() == reqwest::StatusCode::NOT_FOUND {
+    return Err(anyhow!("Package \"{}\" was not found in {}", name, registry));
+  }
+
+  let text = response
+    .error_for_status()
+    .with_context(|| format!("Failed to fetch the index of \"{}\"", name))?
+    .text()
+    .await?;
+  serde_json::from_str(&text).with_context(|| format!("Malformed index for \"{}\"", name))
+}
+
+pub async fn publish(
+  registry: &str,
+  name: &str,
+  version: &str,
+  file: &Path,
+  token: Option<&str>,
+) -> Result<()> {
+  let file_name = file
+    .file_name()
+    .and_then(|s| s.to_str())
+    .ok_or_else(|| anyhow!("Invalid artifact path {}", file.display()))?;
+  let bytes = fs::read(file)?;
+
+  let mut request = reqwest::Client::new()
+    .put(blob_url(registry, name, version, file_name))
+    .body(bytes);
+  if let Some(token) = token {
+    request = request.bearer_auth(token);
+  }
+
+  let response = request.send().await?;
+  let status = response.status();
+  if !status.is_success() {
+    let message = response.text().await.unwrap_or_default();
+    return Err(anyhow!(
+      "Registry refused {}@{} ({}): {}",
+      name,
+      version,
+      status,
+      message.trim()
+    ));
+  }
+
+  Ok(())
+}
+
+/// A registry kept on disk as `index/<name>.json` plus `blobs/<name>/<version>/<file>`.
+#[derive(Debug, Clone)]
+pub struct RegistryStore {
+  pub root: PathBuf,
+  /// Held while publishing, as requests are served concurrently and the
+  /// index is rewritten as a whole.
+  publish_lock: Arc<Mutex<()>>,
+}
+
+impl RegistryStore {
+  pub fn new(root: PathBuf) -> Result<Self> {
+    fs::create_dir_all(root.join("index"))?;
+    fs::create_dir_all(root.join("blobs"))?;
+    Ok(RegistryStore {
+      root,
+      publish_lock: Arc::new(Mutex::new(())),
+    })
+  }
+
+  fn index_path(&self, name: &str) -> PathBuf {
+    self.root.join("index").join(format!("{}.json", name))
+  }
+
+  pub fn blob_path(&self, name: &str, version: &str, file: &str) -> PathBuf {
+    self.root.join("blobs").join(name).join(version).join(file)
+  }
+
+  pub fn index(&self, name: &str) -> Result<Option<PackageIndex>> {
+    let path = self.index_path(name);
+    if !path.exists() {
+      return Ok(None);
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&content).with_context(
+      || format!("Malformed {}", path.display()),
+    )?))
+  }
+
+  /// Stores a blob and records it in the package index. Published versions are
+  /// immutable, so publishing an existing version fails.
+  pub fn publish(
+    &self,
+    name: &str,
+    version: &str,
+    file: &str,
+    bytes: &[u8],
+  ) -> Result<PackageVersion> {
+    let _guard = self.publish_lock.lock().unwrap_or_else(|e| e.into_inner());
+    let mut index = self.index(name)?.unwrap_or_else(|| PackageIndex {
+      name: name.to_string(),
+      versions: BTreeMap::new(),
+    });
+
+    if index.versions.contains_key(version) {
+      return Err(anyhow!("{}@{} is already published", name, version));
+    }
+
+    let blob_path = self.blob_path(name, version, file);
+    fs::create_dir_all(blob_path.parent().unwrap())?;
+    fs::write(&blob_path, bytes)?;
+
+    let entry = PackageVersion {
+      file: file.to_string(),
+      sha256: sha256_hex(bytes),
+    };
+    index.versions.insert(version.to_string(), entry.clone());
+    fs::write(self.index_path(name), serde_json::to_string_pretty(&index)?)?;
+
+    Ok(entry)
+  }
+}
+
+/// Compares without returning early, so timing doesn't tell how much of a
+/// token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn text_response(status: StatusCode, body: impl Into<String>) -> Response<Full<Bytes>> {
+  Response::builder()
+    .status(status)
+    .header("x-powered-by", "Lulu")
+    .body(Full::new(Bytes::from(body.into())))
+    .unwrap()
+}
+
+async fn handle_registry_request(
+  store: Arc<RegistryStore>,
+  options: Arc<ServeOptions>,
+  req: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, ServeError> {
+  let method = req.method().clone();
+  let path = req.uri().path().to_string();
+  let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+  if segments.iter().any(|s| !is_valid_segment(s)) {
+    return Ok(text_response(StatusCode::BAD_REQUEST, "Invalid path"));
+  }
+
+  match (&method, segments.as_slice()) {
+    (&Method::GET, ["index", file]) => {
+      let Some(name) = file.strip_suffix(".json") else {
+        return Ok(text_response(StatusCode::NOT_FOUND, "Not found"));
+      };
+      match store.index(name).map_err(to_serve_error)? {
+        Some(index) => Ok(
+          Response::builder()
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(
+              serde_json::to_string(&index).map_err(to_serve_error)?,
+            )))
+            .map_err(to_serve_error)?,
+        ),
+        None => Ok(text_response(StatusCode::NOT_FOUND, "Unknown package")),
+      }
+    }
+    (&Method::GET, ["blobs", name, version, file]) => {
+      match fs::read(store.blob_path(name, version, file)) {
+        Ok(bytes) => Ok(
+          Response::builder()
+            .header("content-type", "application/octet-stream")
+            .body(Full::new(Bytes::from(bytes)))
+            .map_err(to_serve_error)?,
+        ),
+        Err(_) => Ok(text_response(StatusCode::NOT_FOUND, "Not found")),
+      }
+    }
+    (&Method::PUT, ["blobs", name, version, file]) => {
+      match options.token.as_ref() {
+        Some(token) => {
+          let authorized = req
+            .headers()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| {
+              constant_time_eq(v.as_bytes(), format!("Bearer {}", token).as_bytes())
+            });
+          if !authorized {
+            return Ok(text_response(StatusCode::UNAUTHORIZED, "Invalid token"));
+          }
+        }
+        None if !options.allow_anonymous_publish => {
+          return Ok(text_response(
+            StatusCode::FORBIDDEN,
+            "Publishing is disabled, start the registry with --token or --allow-anonymous-publish",
+          ));
+        }
+        None => {}
+      }
+
+      let (name, version, file) = (name.to_string(), version.to_string(), file.to_string());
+      let body = match Limited::new(req.into_body(), options.max_upload_size)
+        .collect()
+        .await
+      {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+          return Ok(text_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Uploads are limited to {} bytes", options.max_upload_size),
+          ));
+        }
+        Err(e) => return Err(to_serve_error(e)),
+      };
+
+      match store.publish(&name, &version, &file, &body) {
+        Ok(entry) => {
+          println!("Published {}@{} ({})", name, version, entry.sha256);
+          Ok(text_response(StatusCode::CREATED, entry.sha256))
+        }
+        Err(e) => Ok(text_response(StatusCode::CONFLICT, e.to_string())),
+      }
+    }
+    _ => Ok(text_response(StatusCode::NOT_FOUND, "Not found")),
+  }
+}
+
+/// Serves the registry in `root` over HTTP until the process is stopped.
+/// Publishing is refused unless `options` sets a token or allows anonymous uploads.
+pub async fn serve(root: PathBuf, addr: &str, options: ServeOptions) -> Result<()> {
+  let store = Arc::new(RegistryStore::new(root)?);
+  let options = Arc::new(options);
+
+  let listener = tokio::net::TcpListener::bind(addr)
+    .await
+    .with_context(|| format!("Failed to bind {}", addr))?;
+  println!(
+    "Serving registry {} on http://{}",
+    store.root.display(),
+    listener.local_addr()?
+  );
+
+  serve_http(listener, move |req| {
+    handle_registry_request(store.clone(), options.clone(), req)
+  })
+  .await;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_registry_dep() {
+    assert_eq!(
+      parse_registry_dep("registry:json@1.2.0"),
+      Some(("json".to_string(), Some("1.2.0".to_string())))
+    );
+    assert_eq!(
+      parse_registry_dep("registry:json"),
+      Some(("json".to_string(), None))
+    );
+    assert_eq!(parse_registry_dep("registry:../json"), None);
+    assert_eq!(parse_registry_dep("github:a/b"), None);
+  }
+
+  #[test]
+  fn test_store_publish_and_resolve() {
+    let root = std::env::temp_dir().join(format!("lulu-registry-{}", uuid::Uuid::new_v4()));
+    let store = RegistryStore::new(root.clone()).unwrap();
+
+    store.publish("pkg", "1.9.0", "pkg.lulib", b"old").unwrap();
+    store.publish("pkg", "1.10.0", "pkg.lulib", b"new").unwrap();
+    assert!(store.publish("pkg", "1.10.0", "pkg.lulib", b"again").is_err());
+
+    let index = store.index("pkg").unwrap().unwrap();
+    let (version, entry) = index.resolve(None).unwrap();
+    assert_eq!(version, "1.10.0");
+    assert_eq!(entry.sha256, sha256_hex(b"new"));
+    assert!(index.resolve(Some("1.9.0")).is_some());
+    assert!(index.resolve(Some("2.0.0")).is_none());
+
+    let threads: Vec<_> = (0..8)
+      .map(|i| {
+        let store = store.clone();
+        std::thread::spawn(move || store.publish("many", &format!("1.{}.0", i), "m.lulib", b"m"))
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap().unwrap();
+    }
+    assert_eq!(store.index("many").unwrap().unwrap().versions.len(), 8);
+
+    assert!(constant_time_eq(b"Bearer a", b"Bearer a"));
+    assert!(!constant_time_eq(b"Bearer a", b"Bearer b"));
+
+    fs::remove_dir_all(root).ok();
+  }
+}
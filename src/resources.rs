@@ -0,0 +1,246 @@
+//! Files listed in the `resources` section of `lulu.conf.lua`. They are read
+//! into [`Lulu::assets`](crate::core::Lulu) when the project loads, packed
+//! into `.lulib` files and executables with the modules, and exposed at
+//! runtime as a read-only filesystem through the `res` global.
+
+use crate::lulibs::bytes::LuluByteArray;
+use mlua::Lua;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of resource paths given to `pathing`, `include_bytes!` and `bytes_from`.
+pub const RES_SCHEME: &str = "res://";
+
+/// Turns `res://./assets\logo.png` into `assets/logo.png`.
+pub fn normalize(path: &str) -> String {
+  let path = path.strip_prefix(RES_SCHEME).unwrap_or(path).replace('\\', "/");
+  let mut parts: Vec<&str> = Vec::new();
+  for part in path.split('/') {
+    match part {
+      "" | "." => {}
+      ".." => {
+        parts.pop();
+      }
+      part => parts.push(part),
+    }
+  }
+  parts.join("/")
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+  match (pattern.first(), name.first()) {
+    (None, None) => true,
+    (Some(b'*'), _) => {
+      match_segment(&pattern[1..], name) || (!name.is_empty() && match_segment(pattern, &name[1..]))
+    }
+    (Some(b'?'), Some(_)) => match_segment(&pattern[1..], &name[1..]),
+    (Some(p), Some(n)) if p == n => match_segment(&pattern[1..], &name[1..]),
+    _ => false,
+  }
+}
+
+fn match_parts(pattern: &[&str], path: &[&str]) -> bool {
+  match pattern.first() {
+    None => path.is_empty(),
+    Some(&"**") => (0..=path.len()).any(|skip| match_parts(&pattern[1..], &path[skip..])),
+    Some(segment) => {
+      !path.is_empty()
+        && match_segment(segment.as_bytes(), path[0].as_bytes())
+        && match_parts(&pattern[1..], &path[1..])
+    }
+  }
+}
+
+/// Matches a `/` separated path against a glob where `*` and `?` stay inside
+/// one path segment and `**` spans any number of them.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+  let pattern: Vec<&str> = pattern.split('/').collect();
+  let path: Vec<&str> = path.split('/').collect();
+  match_parts(&pattern, &path)
+}
+
+fn walk(root: &Path, dir: &str, files: &mut Vec<String>) -> std::io::Result<()> {
+  let path = root.join(dir);
+  if path.is_file() {
+    files.push(dir.to_string());
+    return Ok(());
+  }
+  if !path.is_dir() {
+    return Ok(());
+  }
+
+  for entry in std::fs::read_dir(path)? {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().to_string();
+    if dir.is_empty() && (name == ".lib" || name == ".git") {
+      continue;
+    }
+    let child = if dir.is_empty() {
+      name
+    } else {
+      format!("{}/{}", dir, name)
+    };
+    walk(root, &child, files)?;
+  }
+  Ok(())
+}
+
+/// Reads the files under `root` matched by the `resources` patterns. A
+/// pattern naming a directory takes every file inside it.
+pub fn collect(root: &Path, patterns: &[String]) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+  let mut names = BTreeSet::new();
+
+  for pattern in patterns {
+    let pattern = normalize(pattern);
+    // Only walk from the part of the pattern before the first wildcard.
+    let base = pattern
+      .split('/')
+      .take_while(|part| !part.contains(['*', '?']))
+      .collect::<Vec<_>>()
+      .join("/");
+
+    let mut files = Vec::new();
+    walk(root, &base, &mut files)?;
+
+    let matched: Vec<String> = files
+      .into_iter()
+      .filter(|file| {
+        file == &pattern || file.starts_with(&format!("{}/", pattern)) || glob_match(&pattern, file)
+      })
+      .collect();
+    if matched.is_empty() {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("resources pattern \"{}\" matched no files in {}", pattern, root.display()),
+      ));
+    }
+    names.extend(matched);
+  }
+
+  names
+    .into_iter()
+    .map(|name| {
+      let bytes = std::fs::read(root.join(&name))?;
+      Ok((name, bytes))
+    })
+    .collect()
+}
+
+/// The read-only view of the assets the `res` global and `pathing` see.
+#[derive(Clone, Default)]
+pub struct Resources(pub Arc<HashMap<String, Vec<u8>>>);
+
+impl Resources {
+  pub fn of(lua: &Lua) -> Resources {
+    lua
+      .app_data_ref::<Resources>()
+      .map(|res| res.clone())
+      .unwrap_or_default()
+  }
+
+  pub fn get(&self, path: &str) -> Option<&[u8]> {
+    self.0.get(&normalize(path)).map(Vec::as_slice)
+  }
+
+  pub fn read(&self, path: &str) -> mlua::Result<&[u8]> {
+    self
+      .get(path)
+      .ok_or_else(|| mlua::Error::RuntimeError(format!("Resource '{}' not found", normalize(path))))
+  }
+
+  pub fn is_dir(&self, path: &str) -> bool {
+    let dir = normalize(path);
+    dir.is_empty() || self.0.keys().any(|name| name.starts_with(&format!("{}/", dir)))
+  }
+
+  /// The files and directories directly inside `path`, as full resource
+  /// paths, with directories ending in `/`.
+  pub fn list(&self, path: &str) -> Vec<String> {
+    let dir = normalize(path);
+    let prefix = if dir.is_empty() {
+      String::new()
+    } else {
+      format!("{}/", dir)
+    };
+
+    self
+      .0
+      .keys()
+      .filter_map(|name| {
+        let rest = name.strip_prefix(&prefix)?;
+        Some(match rest.split_once('/') {
+          Some((child, _)) => format!("{}{}/", prefix, child),
+          None => name.clone(),
+        })
+      })
+      .collect::<BTreeSet<_>>()
+      .into_iter()
+      .collect()
+  }
+}
+
+/// Sets the `res` global over the assets of `lulu`.
+pub fn register(lua: &Lua, assets: &HashMap<String, Vec<u8>>) -> mlua::Result<()> {
+  lua.set_app_data(Resources(Arc::new(assets.clone())));
+
+  let res = lua.create_table()?;
+  res.set(
+    "read",
+    lua.create_function(|lua, path: String| {
+      lua.create_string(Resources::of(lua).read(&path)?)
+    })?,
+  )?;
+  res.set(
+    "read_bytes",
+    lua.create_function(|lua, path: String| {
+      Ok(LuluByteArray {
+        bytes: Resources::of(lua).read(&path)?.to_vec(),
+      })
+    })?,
+  )?;
+  res.set(
+    "exists",
+    lua.create_function(|lua, path: String| {
+      let res = Resources::of(lua);
+      Ok(res.get(&path).is_some() || res.is_dir(&path))
+    })?,
+  )?;
+  res.set(
+    "is_dir",
+    lua.create_function(|lua, path: String| Ok(Resources::of(lua).is_dir(&path)))?,
+  )?;
+  res.set(
+    "list",
+    lua.create_function(|lua, path: Option<String>| {
+      Ok(Resources::of(lua).list(path.as_deref().unwrap_or("")))
+    })?,
+  )?;
+
+  lua.globals().set("res", res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_glob_and_list() {
+    assert_eq!(normalize("res://./assets\\img/../logo.png"), "assets/logo.png");
+    assert!(glob_match("assets/*.png", "assets/logo.png"));
+    assert!(!glob_match("assets/*.png", "assets/icons/a.png"));
+    assert!(glob_match("assets/**/*.png", "assets/logo.png"));
+    assert!(glob_match("assets/**/*.png", "assets/icons/a.png"));
+    assert!(glob_match("templates/page?.html", "templates/page1.html"));
+
+    let mut assets = HashMap::new();
+    for name in ["templates/a.html", "templates/partials/b.html", "logo.png"] {
+      assets.insert(name.to_string(), name.as_bytes().to_vec());
+    }
+    let res = Resources(Arc::new(assets));
+    assert_eq!(res.list("templates/"), vec!["templates/a.html", "templates/partials/"]);
+    assert_eq!(res.list(""), vec!["logo.png", "templates/"]);
+    assert!(res.is_dir("res://templates"));
+    assert_eq!(res.get("res://logo.png"), Some(&b"logo.png"[..]));
+  }
+}
@@ -0,0 +1,192 @@
+//! Hosting Lulu in another application.
+//!
+//! [`LuluRuntime`] sets up a VM the way `lulu run` does, registering the std
+//! modules and the ops and running `std.lua` in the right order.
+//!
+//! ```
+//! use lulu::runtime::LuluRuntime;
+//!
+//! let mut runtime = LuluRuntime::builder()
+//!   .with_std(["serde"])
+//!   .with_module("greet", "return { hello = function(name) return 'hello ' .. name end }")
+//!   .build()?;
+//!
+//! let greeting: String = runtime.call_export("greet", "hello", "lulu")?;
+//! assert_eq!(greeting, "hello lulu");
+//! assert_eq!(runtime.eval::<i64>("return 1 + 1")?, 2);
+//! # Ok::<(), lulu::mlua::Error>(())
+//! ```
+
+use std::path::PathBuf;
+use std::sync::Once;
+
+use mlua::{FromLuaMulti, IntoLuaMulti, Lua};
+
+use crate::core::Lulu;
+use crate::ops::std::{STDModule, get_std_module, init_std_modules};
+use crate::permissions::Permissions;
+
+static STD_INIT: Once = Once::new();
+
+#[derive(Default)]
+pub struct LuluRuntimeBuilder {
+  std: Option<Vec<String>>,
+  modules: Vec<(String, String)>,
+  native: Vec<STDModule>,
+  args: Vec<String>,
+  root: Option<PathBuf>,
+  permissions: Option<Permissions>,
+}
+
+impl LuluRuntimeBuilder {
+  /// Limits `using { lulib.* }` to these std modules and what they depend
+  /// on. Every std module is available when this is never called.
+  pub fn with_std<I, S>(mut self, names: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self
+      .std
+      .get_or_insert_with(Vec::new)
+      .extend(names.into_iter().map(Into::into));
+    self
+  }
+
+  /// Adds a module that `require(name)` runs from source.
+  pub fn with_module(mut self, name: impl Into<String>, source: impl Into<String>) -> Self {
+    self.modules.push((name.into(), source.into()));
+    self
+  }
+
+  /// Adds a module written in Rust, loaded with `using { lulib.<name> }`.
+  pub fn with_native_module(mut self, module: STDModule) -> Self {
+    self.native.push(module);
+    self
+  }
+
+  /// The `argv` the scripts see.
+  pub fn args<I, S>(mut self, args: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+  {
+    self.args = args.into_iter().map(Into::into).collect();
+    self
+  }
+
+  /// The directory `current_path` and relative lookups start from, the
+  /// working directory by default.
+  pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+    self.root = Some(root.into());
+    self
+  }
+
+  /// Restricts what the scripts can reach, see [`Permissions`]. Nothing is
+  /// restricted by default.
+  pub fn permissions(mut self, permissions: Permissions) -> Self {
+    self.permissions = Some(permissions);
+    self
+  }
+
+  pub fn build(self) -> mlua::Result<LuluRuntime> {
+    STD_INIT.call_once(init_std_modules);
+
+    // Std modules are global, and the compiler picks up their macros when
+    // it is created, so native modules are registered before the VM.
+    let mut std = self.std;
+    for module in self.native {
+      if let Some(std) = &mut std {
+        std.push(module.name.clone());
+      }
+      module.into();
+    }
+
+    let root = match self.root {
+      Some(root) => root,
+      None => std::env::current_dir()?,
+    };
+    let mut lulu = Lulu::new(Some(self.args), Some(root));
+    lulu.std_modules = std.map(with_deps);
+    lulu.permissions = self.permissions;
+
+    for (name, source) in self.modules {
+      lulu.add_mod_from_code(name, source, None);
+    }
+    lulu.preload_mods()?;
+
+    Ok(LuluRuntime { lulu })
+  }
+}
+
+/// `names` with the std modules they depend on.
+fn with_deps(mut names: Vec<String>) -> Vec<String> {
+  let mut i = 0;
+  while i < names.len() {
+    if let Some(module) = get_std_module(&names[i]) {
+      for dep in &module.deps {
+        if !names.contains(dep) {
+          names.push(dep.clone());
+        }
+      }
+    }
+    i += 1;
+  }
+  names
+}
+
+pub struct LuluRuntime {
+  lulu: Lulu,
+}
+
+impl LuluRuntime {
+  pub fn builder() -> LuluRuntimeBuilder {
+    LuluRuntimeBuilder::default()
+  }
+
+  pub fn lua(&self) -> &Lua {
+    &self.lulu.lua
+  }
+
+  pub fn lulu(&self) -> &Lulu {
+    &self.lulu
+  }
+
+  /// Runs a script or the entry of a project like `lulu run`, along with
+  /// every future it starts.
+  pub async fn run_file(&mut self, path: impl Into<PathBuf>) -> mlua::Result<mlua::Value> {
+    let name = self.lulu.entry_mod_path(path.into())?;
+    self.lulu.exec_final(&name).await
+  }
+
+  /// Compiles and runs `code` in the global scope. Macros it defines stay
+  /// defined for later calls. Futures it starts run with [`Self::wait`].
+  pub fn eval<R: FromLuaMulti>(&mut self, code: &str) -> mlua::Result<R> {
+    let code = self.lulu.compiler.compile(code, None, None);
+    self.lulu.lua.load(code).set_name("eval").eval()
+  }
+
+  /// Requires `module` and calls the function it exports as `name`.
+  pub fn call_export<A: IntoLuaMulti, R: FromLuaMulti>(
+    &self,
+    module: &str,
+    name: &str,
+    args: A,
+  ) -> mlua::Result<R> {
+    let require: mlua::Function = self.lulu.lua.globals().get("require")?;
+    let exports: mlua::Table = require.call(module)?;
+    match exports.get::<Option<mlua::Function>>(name)? {
+      Some(function) => function.call(args),
+      None => Err(mlua::Error::RuntimeError(format!(
+        "Module '{}' has no export '{}'",
+        module, name
+      ))),
+    }
+  }
+
+  /// Runs the futures started by [`Self::eval`] or [`Self::call_export`]
+  /// until every one of them is done.
+  pub async fn wait(&self) -> mlua::Result<()> {
+    self.lulu.drive_scheduler().await
+  }
+}
@@ -0,0 +1,225 @@
+use crate::conf::LuluConf;
+use base64::prelude::*;
+use rand::RngCore;
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_MAGIC: &[u8; 4] = b"SIG!";
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+pub const SIGNATURE_TRAILER_LEN: usize = SIGNATURE_LEN + PUBLIC_KEY_LEN + 4;
+
+/// The ed25519 trailer written after a bundle's `LUL!` footer by `lulu bundle --sign`.
+#[derive(Debug, Clone)]
+pub struct BundleSignature {
+  pub signature: [u8; SIGNATURE_LEN],
+  pub public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl BundleSignature {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = Vec::with_capacity(SIGNATURE_TRAILER_LEN);
+    out.extend_from_slice(&self.signature);
+    out.extend_from_slice(&self.public_key);
+    out.extend_from_slice(SIGNATURE_MAGIC);
+    out
+  }
+
+  pub fn from_trailer(trailer: &[u8]) -> Option<Self> {
+    if trailer.len() != SIGNATURE_TRAILER_LEN || &trailer[SIGNATURE_TRAILER_LEN - 4..] != SIGNATURE_MAGIC
+    {
+      return None;
+    }
+
+    Some(BundleSignature {
+      signature: trailer[..SIGNATURE_LEN].try_into().ok()?,
+      public_key: trailer[SIGNATURE_LEN..SIGNATURE_LEN + PUBLIC_KEY_LEN]
+        .try_into()
+        .ok()?,
+    })
+  }
+
+  pub fn verify(&self, body: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, &self.public_key)
+      .verify(body, &self.signature)
+      .is_ok()
+  }
+}
+
+pub fn encode_key(key: &[u8]) -> String {
+  BASE64_STANDARD.encode(key)
+}
+
+pub fn decode_public_key(key: &str) -> Option<[u8; PUBLIC_KEY_LEN]> {
+  BASE64_STANDARD.decode(key.trim()).ok()?.try_into().ok()
+}
+
+fn load_key_pair(path: &Path) -> std::io::Result<Ed25519KeyPair> {
+  let content = std::fs::read_to_string(path)?;
+  let seed = BASE64_STANDARD
+    .decode(content.trim())
+    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+
+  Ed25519KeyPair::from_seed_unchecked(&seed).map_err(|_| {
+    Error::new(
+      ErrorKind::InvalidData,
+      format!("{} is not an ed25519 private key", path.display()),
+    )
+  })
+}
+
+/// Writes a new private key to `path` and its public key to `path.pub`,
+/// returning the encoded public key.
+pub fn generate_key(path: &Path) -> std::io::Result<String> {
+  let mut seed = [0u8; 32];
+  rand::thread_rng().fill_bytes(&mut seed);
+
+  let pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+    .map_err(|_| Error::other("Failed to generate an ed25519 key"))?;
+  let public_key = encode_key(pair.public_key().as_ref());
+
+  std::fs::write(path, encode_key(&seed))?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+  }
+
+  let mut pub_path = path.as_os_str().to_owned();
+  pub_path.push(".pub");
+  std::fs::write(PathBuf::from(pub_path), &public_key)?;
+
+  Ok(public_key)
+}
+
+pub fn sign_bundle(body: &[u8], key_path: &Path) -> std::io::Result<BundleSignature> {
+  let pair = load_key_pair(key_path)?;
+
+  Ok(BundleSignature {
+    signature: pair.sign(body).as_ref().try_into().unwrap(),
+    public_key: pair.public_key().as_ref().try_into().unwrap(),
+  })
+}
+
+/// Which publisher keys are trusted and whether unsigned bundles may be loaded.
+///
+/// Keys come from the user keyring (one base64 key per line in
+/// `<config dir>/lulu/trusted_keys`) and from `trusted_keys` in `lulu.conf.lua`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustPolicy {
+  pub trusted_keys: Vec<[u8; PUBLIC_KEY_LEN]>,
+  pub require_signed: bool,
+}
+
+impl TrustPolicy {
+  pub fn keyring_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("lulu").join("trusted_keys"))
+  }
+
+  pub fn from_keyring() -> Self {
+    let mut policy = TrustPolicy {
+      trusted_keys: Vec::new(),
+      require_signed: std::env::var("LULU_REQUIRE_SIGNED").is_ok_and(|v| v == "1" || v == "true"),
+    };
+
+    if let Some(content) = Self::keyring_path().and_then(|p| std::fs::read_to_string(p).ok()) {
+      for line in content.lines() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+          policy.trust(line);
+        }
+      }
+    }
+
+    policy
+  }
+
+  pub fn from_conf(conf: &LuluConf) -> Self {
+    let mut policy = Self::from_keyring();
+
+    if let Some(keys) = &conf.trusted_keys {
+      for key in keys {
+        policy.trust(key);
+      }
+    }
+
+    if conf.require_signed == Some(true) {
+      policy.require_signed = true;
+    }
+
+    policy
+  }
+
+  pub fn trust(&mut self, key: &str) {
+    match decode_public_key(key) {
+      Some(key) => self.trusted_keys.push(key),
+      None => eprintln!("Warning: ignoring malformed trusted key \"{}\"", key),
+    }
+  }
+
+  /// Rejects bundles whose signature does not match their body, and, when
+  /// `require_signed` is set, bundles that are unsigned or signed by an
+  /// untrusted key.
+  pub fn check(&self, body: &[u8], signature: Option<&BundleSignature>) -> std::io::Result<()> {
+    match signature {
+      Some(signature) => {
+        if !signature.verify(body) {
+          return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Bundle signature does not match its contents, refusing to load a tampered bundle",
+          ));
+        }
+
+        if self.require_signed && !self.trusted_keys.contains(&signature.public_key) {
+          return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+              "Bundle is signed by untrusted key {}",
+              encode_key(&signature.public_key)
+            ),
+          ));
+        }
+      }
+      None => {
+        if self.require_signed {
+          return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Bundle is not signed and require_signed is enabled",
+          ));
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sign_and_check() {
+    let key_path = std::env::temp_dir().join(format!("lulu-test-key-{}", uuid::Uuid::new_v4()));
+    let public_key = generate_key(&key_path).unwrap();
+
+    let body = b"bundle body".to_vec();
+    let signature = sign_bundle(&body, &key_path).unwrap();
+    let trailer = BundleSignature::from_trailer(&signature.to_bytes()).unwrap();
+
+    let mut policy = TrustPolicy {
+      trusted_keys: Vec::new(),
+      require_signed: true,
+    };
+    assert!(policy.check(&body, Some(&trailer)).is_err());
+    assert!(policy.check(&body, None).is_err());
+
+    policy.trust(&public_key);
+    assert!(policy.check(&body, Some(&trailer)).is_ok());
+    assert!(policy.check(b"tampered body", Some(&trailer)).is_err());
+
+    std::fs::remove_file(&key_path).ok();
+    std::fs::remove_file(format!("{}.pub", key_path.display())).ok();
+  }
+}
@@ -1,4 +1,5 @@
 use std::cmp::min;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub struct SourceMapEntry {
@@ -91,6 +92,143 @@ pub fn generate_sourcemap(lulu_source: &str, lua_output: &str) -> Vec<SourceMapE
   filled
 }
 
+/// Pairs of `(output line, source line)` for lines that occur exactly once
+/// in both files, keeping the longest run that is in order in both.
+fn unique_anchors(source: &[&str], output: &[&str]) -> Vec<(usize, usize)> {
+  // Per line: count in the source, count in the output, source index.
+  let mut counts: HashMap<&str, (usize, usize, usize)> = HashMap::new();
+  for (idx, line) in source.iter().enumerate() {
+    if !line.is_empty() {
+      let entry = counts.entry(line).or_default();
+      entry.0 += 1;
+      entry.2 = idx;
+    }
+  }
+  for line in output {
+    if let Some(entry) = counts.get_mut(line) {
+      entry.1 += 1;
+    }
+  }
+
+  let pairs: Vec<(usize, usize)> = output
+    .iter()
+    .enumerate()
+    .filter_map(|(idx, line)| match counts.get(line) {
+      Some((1, 1, source_idx)) => Some((idx, *source_idx)),
+      _ => None,
+    })
+    .collect();
+
+  // Longest increasing subsequence of the source indices.
+  let mut tails: Vec<usize> = Vec::new();
+  let mut prev: Vec<Option<usize>> = vec![None; pairs.len()];
+  for (idx, (_, source_idx)) in pairs.iter().enumerate() {
+    let pos = tails.partition_point(|t| pairs[*t].1 < *source_idx);
+    prev[idx] = pos.checked_sub(1).map(|p| tails[p]);
+    if pos == tails.len() {
+      tails.push(idx);
+    } else {
+      tails[pos] = idx;
+    }
+  }
+
+  let mut anchors = Vec::new();
+  let mut current = tails.last().copied();
+  while let Some(idx) = current {
+    anchors.push(pairs[idx]);
+    current = prev[idx];
+  }
+  anchors.reverse();
+  anchors
+}
+
+/// Maps each line of `lua_output` to a line of `lulu_source` (both 1-based).
+/// Lines found once in both files anchor the map, and the lines between
+/// anchors are matched in order. Lines the compiler rewrote map to the last
+/// matched line. Unlike [`generate_sourcemap`], this is cheap enough to run
+/// on every module of a bundle.
+pub fn generate_line_map(lulu_source: &str, lua_output: &str) -> Vec<u32> {
+  let source: Vec<&str> = lulu_source.lines().map(str::trim).collect();
+  let output: Vec<&str> = lua_output.lines().map(str::trim).collect();
+  let mut anchors = unique_anchors(&source, &output).into_iter().peekable();
+  let mut map = Vec::with_capacity(output.len());
+  let mut next = 0;
+  let mut last = 0;
+
+  for (idx, line) in output.iter().enumerate() {
+    // Lines between two anchors can only come from between them.
+    let limit = anchors.peek().map_or(source.len(), |anchor| anchor.1);
+    let matched = if anchors.peek().is_some_and(|anchor| anchor.0 == idx) {
+      anchors.next().map(|anchor| anchor.1)
+    } else if line.is_empty() {
+      (next < limit && source[next].is_empty()).then_some(next)
+    } else {
+      source[next.min(limit)..limit]
+        .iter()
+        .position(|l| l == line)
+        .map(|offset| next + offset)
+    };
+
+    if let Some(matched) = matched {
+      last = matched;
+      next = matched + 1;
+    }
+    map.push(last as u32 + 1);
+  }
+
+  map
+}
+
+/// Replaces `<chunk>:<line>` positions of chunks that have a line map with
+/// the original line.
+pub fn rewrite_positions(message: &str, maps: &HashMap<String, Vec<u32>>) -> String {
+  let mut out = message.to_string();
+  for (chunk, map) in maps {
+    let pattern = format!("{}:", chunk);
+    let mut rewritten = String::with_capacity(out.len());
+    let mut rest = out.as_str();
+
+    while let Some(pos) = rest.find(&pattern) {
+      let after = pos + pattern.len();
+      let digits = rest[after..]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len() - after);
+      let before = rest[..pos].chars().last().or(rewritten.chars().last());
+      let starts_name = before.is_none_or(|c| !(c.is_alphanumeric() || "_-/.".contains(c)));
+      rewritten.push_str(&rest[..after]);
+
+      let line = rest[after..after + digits]
+        .parse::<usize>()
+        .ok()
+        .filter(|_| starts_name)
+        .and_then(|line| map.get(line.checked_sub(1)?));
+      match line {
+        Some(line) => rewritten.push_str(&line.to_string()),
+        None => rewritten.push_str(&rest[after..after + digits]),
+      }
+      rest = &rest[after + digits..];
+    }
+
+    rewritten.push_str(rest);
+    out = rewritten;
+  }
+  out
+}
+
+/// [`rewrite_positions`] for the message of an error that reached the top.
+pub fn rewrite_error(error: mlua::Error, maps: &HashMap<String, Vec<u32>>) -> mlua::Error {
+  if maps.is_empty() {
+    return error;
+  }
+
+  match error {
+    mlua::Error::RuntimeError(message) => {
+      mlua::Error::RuntimeError(rewrite_positions(&message, maps))
+    }
+    error => mlua::Error::RuntimeError(rewrite_positions(&error.to_string(), maps)),
+  }
+}
+
 #[allow(unused)]
 fn levenshtein(a: &str, b: &str) -> usize {
   let mut costs: Vec<usize> = (0..=b.len()).collect();
@@ -171,4 +309,24 @@ end)
       );
     }
   }
+
+  #[test]
+  fn test_line_map() {
+    let lulu = "local a = 1\n\nprint(a)\n";
+    let lua = "local a = 1\nlocal b = a\n\nprint(a)\n";
+    let map = generate_line_map(lulu, lua);
+    assert_eq!(map, vec![1, 1, 2, 3]);
+
+    // A generated `end` must not jump past the rest of the class.
+    let lulu = "class! A, {\n  f() {\n  }\n}\nfunction g()\n  x()\nend\n";
+    let lua = "A = make_class({})\nfunction A:f()\nend\nfunction g()\n  x()\nend\n";
+    assert_eq!(generate_line_map(lulu, lua), vec![1, 1, 1, 5, 6, 7]);
+
+    let mut maps = HashMap::new();
+    maps.insert("app/main".to_string(), map);
+    assert_eq!(
+      rewrite_positions("app/main:4: boom\n\tmyapp/main:4: in main chunk", &maps),
+      "app/main:3: boom\n\tmyapp/main:4: in main chunk"
+    );
+  }
 }
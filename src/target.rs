@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Target of `lulu bundle --target`, passed to sub-commands of a build.
+pub const TARGET_ENV: &str = "LULU_TARGET";
+/// Directory holding prebuilt stub runtimes named `lulu-<os>-<arch>`.
+pub const STUBS_DIR_ENV: &str = "LULU_STUBS_DIR";
+
+const KNOWN_OS: [&str; 8] = [
+  "linux", "macos", "windows", "freebsd", "netbsd", "openbsd", "android", "ios",
+];
+
+/// An operating system and architecture pair, written as `linux-aarch64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+  pub os: String,
+  pub arch: String,
+}
+
+impl fmt::Display for Target {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}-{}", self.os, self.arch)
+  }
+}
+
+impl Target {
+  pub fn host() -> Self {
+    Target {
+      os: std::env::consts::OS.to_string(),
+      arch: std::env::consts::ARCH.to_string(),
+    }
+  }
+
+  /// `LULU_TARGET` when a parent build set it, otherwise the host.
+  pub fn from_env() -> Self {
+    std::env::var(TARGET_ENV)
+      .ok()
+      .and_then(|target| Target::parse(&target).ok())
+      .unwrap_or_else(Target::host)
+  }
+
+  pub fn parse(target: &str) -> Result<Self, String> {
+    let target = target.trim().to_lowercase();
+    let (os, arch) = target
+      .split_once('-')
+      .ok_or_else(|| format!("Invalid target \"{}\", expected <os>-<arch>", target))?;
+
+    let os = match os {
+      "darwin" | "macosx" => "macos",
+      "win" | "win32" => "windows",
+      os => os,
+    };
+    let arch = match arch {
+      "arm64" => "aarch64",
+      "amd64" | "x64" => "x86_64",
+      "i386" | "i686" => "x86",
+      arch => arch,
+    };
+
+    if !KNOWN_OS.contains(&os) {
+      return Err(format!(
+        "Unknown target OS \"{}\", expected one of {}",
+        os,
+        KNOWN_OS.join(", ")
+      ));
+    }
+
+    Ok(Target {
+      os: os.to_string(),
+      arch: arch.to_string(),
+    })
+  }
+
+  pub fn is_host(&self) -> bool {
+    *self == Target::host()
+  }
+
+  pub fn family(&self) -> &'static str {
+    if self.os == "windows" { "windows" } else { "unix" }
+  }
+
+  /// Overrides the `OS`, `ARCH` and `FAMILY` defs `cfg!` reads.
+  pub fn apply_defs(&self, defs: &mut HashMap<String, String>) {
+    defs.insert("OS".to_string(), self.os.clone());
+    defs.insert("ARCH".to_string(), self.arch.clone());
+    defs.insert("FAMILY".to_string(), self.family().to_string());
+  }
+
+  pub fn exe_path(&self, output: &Path) -> PathBuf {
+    if self.os == "windows" {
+      output.with_extension("exe")
+    } else {
+      output.to_path_buf()
+    }
+  }
+
+  /// Looks `os-arch` up in a platform map such as the ones given to `stubs`
+  /// and `collect_libs`, falling back to a plain `os` key.
+  pub fn pick<'a, V>(&self, map: &'a HashMap<String, V>) -> Option<&'a V> {
+    map.get(&self.to_string()).or_else(|| map.get(&self.os))
+  }
+
+  pub fn stubs_dir() -> Option<PathBuf> {
+    std::env::var(STUBS_DIR_ENV)
+      .ok()
+      .map(PathBuf::from)
+      .or_else(|| dirs::data_dir().map(|dir| dir.join("lulu").join("stubs")))
+  }
+
+  /// A stub runtime for this target from the stubs directory, named
+  /// `lulu-<os>-<arch>` or `<os>-<arch>`, with `.exe` for Windows.
+  pub fn find_stub(&self) -> Option<PathBuf> {
+    let dir = Self::stubs_dir()?;
+    let ext = if self.os == "windows" { ".exe" } else { "" };
+
+    [format!("lulu-{}{}", self, ext), format!("{}{}", self, ext)]
+      .into_iter()
+      .map(|name| dir.join(name))
+      .find(|path| path.is_file())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_and_pick() {
+    let target = Target::parse("Linux-arm64").unwrap();
+    assert_eq!(target.to_string(), "linux-aarch64");
+    assert!(Target::parse("plan9-x86_64").is_err());
+    assert!(Target::parse("linux").is_err());
+
+    let mut map = HashMap::new();
+    map.insert("linux".to_string(), 1);
+    assert_eq!(target.pick(&map), Some(&1));
+    map.insert("linux-aarch64".to_string(), 2);
+    assert_eq!(target.pick(&map), Some(&2));
+    assert_eq!(Target::parse("windows-x86_64").unwrap().pick(&map), None);
+  }
+}
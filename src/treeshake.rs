@@ -0,0 +1,135 @@
+//! Finds the modules a bundle can reach from its entry points so the rest
+//! can be left out. Edges are read from the string constants of each
+//! module's LuaJIT bytecode, which is where the names given to `require`,
+//! `import!`, `include_bytes!` and `lulib.<name>` end up.
+
+use crate::core::LuLib;
+use std::collections::{HashMap, HashSet};
+
+/// LuaJIT writes a string constant as `uleb128(len + 5)` followed by its bytes.
+const KGC_STR: usize = 5;
+
+fn uleb128(mut value: usize) -> Vec<u8> {
+  let mut out = Vec::new();
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      out.push(byte);
+      return out;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn has_string_constant(bytecode: &[u8], constant: &str) -> bool {
+  let mut needle = uleb128(constant.len() + KGC_STR);
+  needle.extend_from_slice(constant.as_bytes());
+  bytecode.windows(needle.len()).any(|window| window == needle)
+}
+
+/// The strings that load `name` from a module of `package`: the full name,
+/// the name relative to the package (`require` inside a module tries
+/// `<package>/<name>` first), and both without `/init`, which is what
+/// `lulib.<name>` falls back to.
+fn reference_keys(name: &str, package: Option<&str>) -> Vec<String> {
+  let mut names = vec![name];
+  if let Some(package) = package
+    && let Some(relative) = name.strip_prefix(&format!("{}/", package))
+  {
+    names.push(relative);
+  }
+
+  let mut keys = Vec::new();
+  for name in names {
+    keys.push(name.to_string());
+    if let Some(dir) = name.strip_suffix("/init") {
+      keys.push(dir.to_string());
+    }
+  }
+  keys
+}
+
+/// The modules reachable from `roots` and from the modules matching a `keep`
+/// glob. `packages` maps module names to the manifest name of their conf.
+pub fn reachable(
+  mods: &HashMap<String, LuLib>,
+  packages: &HashMap<String, String>,
+  roots: &[String],
+  keep: &[String],
+) -> HashSet<String> {
+  let mut reached: HashSet<String> = HashSet::new();
+  let mut queue: Vec<String> = Vec::new();
+
+  for name in mods.keys() {
+    if roots.contains(name)
+      || keep
+        .iter()
+        .any(|pattern| crate::resources::glob_match(pattern, name))
+    {
+      reached.insert(name.clone());
+      queue.push(name.clone());
+    }
+  }
+
+  while let Some(current) = queue.pop() {
+    let bytecode = &mods[&current].bytes;
+    let package = packages.get(&current).map(String::as_str);
+
+    for name in mods.keys() {
+      if reached.contains(name) {
+        continue;
+      }
+
+      if reference_keys(name, package)
+        .iter()
+        .any(|key| has_string_constant(bytecode, key))
+      {
+        reached.insert(name.clone());
+        queue.push(name.clone());
+      }
+    }
+  }
+
+  reached
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn module(lua: &mlua::Lua, code: &str) -> LuLib {
+    LuLib {
+      bytes: crate::util::lua_to_bytecode(lua, code).unwrap(),
+      conf: None,
+    }
+  }
+
+  #[test]
+  fn test_reachable() {
+    let lua = mlua::Lua::new();
+    let mut mods = HashMap::new();
+    mods.insert("app/main".to_string(), module(&lua, "return require('util')"));
+    mods.insert("app/util".to_string(), module(&lua, "return lulib.dep"));
+    mods.insert("dep/init".to_string(), module(&lua, "return bytes_from('bytes://logo')"));
+    mods.insert("bytes://logo".to_string(), module(&lua, "return 1"));
+    mods.insert("dep/unused".to_string(), module(&lua, "return 1"));
+    mods.insert("app/plugins/a".to_string(), module(&lua, "return 1"));
+
+    let mut packages = HashMap::new();
+    for name in ["app/main", "app/util", "app/plugins/a"] {
+      packages.insert(name.to_string(), "app".to_string());
+    }
+
+    let roots = vec!["app/main".to_string()];
+    let reached = reachable(&mods, &packages, &roots, &[]);
+    assert!(reached.contains("dep/init"));
+    assert!(reached.contains("bytes://logo"));
+    assert!(!reached.contains("dep/unused"));
+    assert!(!reached.contains("app/plugins/a"));
+
+    let reached = reachable(&mods, &packages, &roots, &["app/plugins/*".to_string()]);
+    assert!(reached.contains("app/plugins/a"));
+    assert_eq!(uleb128(300), vec![0xac, 0x02]);
+  }
+}
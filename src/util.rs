@@ -40,6 +40,23 @@ pub fn lua_to_bytecode(lua: &Lua, code: &str) -> mlua::Result<Vec<u8>> {
   Ok(dump.as_bytes().to_vec())
 }
 
+/// Compiles a module under its own chunk name, so errors read `name:line:`,
+/// and dumps it without debug info when `strip` is set.
+pub fn module_bytecode(lua: &Lua, name: &str, code: &[u8], strip: bool) -> mlua::Result<Vec<u8>> {
+  let func = lua.load(code).set_name(format!("={}", name)).into_function()?;
+  dump_function(lua, &func, strip)
+}
+
+/// Dumps `func` to bytecode. LuaJIT ignores the strip flag of `lua_dump`,
+/// so stripped dumps go through `string.dump(func, true)` instead.
+pub fn dump_function(lua: &Lua, func: &mlua::Function, strip: bool) -> mlua::Result<Vec<u8>> {
+  if !strip {
+    return Ok(func.dump(false));
+  }
+  let dump: mlua::Function = lua.globals().get::<mlua::Table>("string")?.get("dump")?;
+  Ok(dump.call::<mlua::String>((func, true))?.as_bytes().to_vec())
+}
+
 pub fn normalize_name(cpath: &str) -> String {
   let path = Path::new(cpath);
   let mut parts: Vec<String> = path
@@ -73,6 +90,26 @@ pub fn copy_recursively(source: impl AsRef<Path>, destination: impl AsRef<Path>)
   Ok(())
 }
 
+/// Reads a file, or every file under a directory, as `(name, bytes)` pairs
+/// where nested files are named `<name>/<relative path>` with `/` separators.
+pub fn read_files_recursively(path: &Path, name: &str) -> io::Result<Vec<(String, Vec<u8>)>> {
+  if !path.is_dir() {
+    return Ok(vec![(name.to_string(), fs::read(path)?)]);
+  }
+
+  let mut files = Vec::new();
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    let entry_name = format!(
+      "{}/{}",
+      name.trim_end_matches('/'),
+      entry.file_name().to_string_lossy()
+    );
+    files.extend(read_files_recursively(&entry.path(), &entry_name)?);
+  }
+  Ok(files)
+}
+
 pub fn create_lib_folders(project_path: &Path) -> io::Result<(PathBuf, PathBuf)> {
   let project_lib_dir = project_path.join(".lib");
   let project_lulib_dir = project_lib_dir.join("lulib");
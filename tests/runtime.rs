@@ -0,0 +1,51 @@
+use lulu::ops::std::create_std_module;
+use lulu::runtime::LuluRuntime;
+
+#[tokio::test]
+async fn test_runtime() {
+  let greeter = create_std_module("greeter")
+    .add_function("greet", |_, name: String| Ok(format!("hi {}", name)));
+
+  let mut runtime = LuluRuntime::builder()
+    .with_std(["serde"])
+    .with_native_module(greeter)
+    .with_module(
+      "app",
+      "using { lulib.greeter }\n\
+       return { greet = function(name) return greeter.greet(name) end }",
+    )
+    .with_module("blocked", "using { lulib.kvdb }")
+    .args(["one", "two"])
+    .build()
+    .unwrap();
+
+  let greeting: String = runtime.call_export("app", "greet", "lulu").unwrap();
+  assert_eq!(greeting, "hi lulu");
+  assert_eq!(runtime.eval::<String>("return argv[2]").unwrap(), "two");
+  assert!(runtime.call_export::<_, ()>("app", "missing", ()).is_err());
+
+  let error = runtime.eval::<()>("require('blocked')").unwrap_err();
+  assert!(error.to_string().contains("not available"));
+
+  runtime
+    .eval::<()>("done = false; async(function() done = true end)")
+    .unwrap();
+  assert!(!runtime.eval::<bool>("return done").unwrap());
+  runtime.wait().await.unwrap();
+  assert!(runtime.eval::<bool>("return done").unwrap());
+
+  let dir = std::env::temp_dir().join(format!("lulu-runtime-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let script = dir.join("script.lua");
+  std::fs::write(&script, "return 40 + 2").unwrap();
+  let value = runtime.run_file(&script).await.unwrap();
+  assert_eq!(value.as_i64(), Some(42));
+
+  // The scheduler finished above and starts over for new futures.
+  runtime
+    .eval::<()>("async(function() done = 'again' end)")
+    .unwrap();
+  runtime.wait().await.unwrap();
+  assert_eq!(runtime.eval::<String>("return done").unwrap(), "again");
+  std::fs::remove_dir_all(&dir).unwrap();
+}
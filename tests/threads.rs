@@ -0,0 +1,158 @@
+use lulu::runtime::LuluRuntime;
+use std::time::Duration;
+
+/// Squares what it gets, answering later jobs sooner so replies come back
+/// out of order.
+const SQUARE_WORKER: &str = "while true do
+    local n = worker.recv()
+    if n == nil then break end
+    local busy_until = os.clock() + (5 - n) * 0.02
+    while os.clock() < busy_until do end
+    worker.send(n * n)
+  end
+  return worker.args and worker.args.tag or worker.id";
+
+fn runtime() -> LuluRuntime {
+  let mut runtime = LuluRuntime::builder()
+    .with_std(["threads", "timers"])
+    .with_module(
+      "libs",
+      "using { lulib.threads, lulib.timers }\n\
+       return { threads = threads, timers = timers }",
+    )
+    .with_module("square", SQUARE_WORKER)
+    .build()
+    .unwrap();
+  runtime
+    .eval::<()>("threads, timers = require('libs').threads, require('libs').timers")
+    .unwrap();
+  runtime
+}
+
+async fn wait(runtime: &LuluRuntime) {
+  tokio::time::timeout(Duration::from_secs(5), runtime.wait())
+    .await
+    .expect("futures did not finish")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_channels() {
+  let mut runtime = runtime();
+
+  let sent: Vec<bool> = runtime
+    .eval(
+      "tx, rx = threads.channel(1)
+       return { tx:try_send(1), tx:try_send(2), rx:try_recv() == 1, tx:try_send(3) }",
+    )
+    .unwrap();
+  assert_eq!(sent, vec![true, false, true, true]);
+
+  // A `send` waits for room, and `recv` drains what was sent before the
+  // channel closed.
+  runtime
+    .eval::<()>(
+      "order = {}
+       async(function()
+         tx:send(4)
+         table.insert(order, 'sent')
+         tx:close()
+       end)
+       async(function()
+         table.insert(order, rx:recv())
+         table.insert(order, rx:recv())
+         table.insert(order, tostring(rx:recv()))
+       end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+  let order: Vec<String> = runtime.eval("return order").unwrap();
+  assert_eq!(order, vec!["3", "sent", "4", "nil"]);
+
+  // Closing the receiver ends a `recv` that is waiting on it.
+  runtime
+    .eval::<()>(
+      "tx, rx = threads.channel()
+       got = 'pending'
+       async(function() got = tostring(rx:recv()) end)
+       async(function() rx:close() end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+  assert_eq!(runtime.eval::<String>("return got").unwrap(), "nil");
+  assert!(!runtime.eval::<bool>("return tx:try_send(1)").unwrap());
+
+  let fan_out: Vec<String> = runtime
+    .eval(
+      "local tx, a = threads.broadcast()
+       local b = tx:subscribe()
+       tx:send('x')
+       return { a:try_recv(), b:try_recv() }",
+    )
+    .unwrap();
+  assert_eq!(fan_out, vec!["x", "x"]);
+}
+
+#[tokio::test]
+async fn test_select() {
+  let mut runtime = runtime();
+  runtime
+    .eval::<()>(
+      "picked = {}
+       local tx, rx = threads.channel()
+       local _, idle = threads.channel()
+       async(function()
+         tx:send('value')
+         local idx, value = threads.select({ idle, rx })
+         table.insert(picked, idx .. ':' .. value)
+
+         -- The timer fires before the select starts waiting.
+         local timer = timers.after(1, function() end)
+         threads.sleep(20)
+         local idx, value = threads.select({ idle, timer })
+         table.insert(picked, idx .. ':' .. tostring(value == timer))
+
+         local future = async(function()
+           threads.sleep(5)
+           return 'done'
+         end)
+         local idx, value = threads.select({ idle, future })
+         table.insert(picked, idx .. ':' .. value)
+       end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+  let picked: Vec<String> = runtime.eval("return picked").unwrap();
+  assert_eq!(picked, vec!["2:value", "2:true", "2:done"]);
+}
+
+#[tokio::test]
+async fn test_workers() {
+  let mut runtime = runtime();
+  runtime
+    .eval::<()>(
+      "results = {}
+       async(function()
+         local single = threads.worker('square', { tag = 'single' })
+         single:send(3)
+         results.single = single:recv()
+         results.returned = single:join()
+
+         local pool = threads.pool('square', 4)
+         results.map = pool:map({ 1, 2, 3, 4 })
+         results.ids = pool:join()
+         table.sort(results.ids)
+       end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+
+  let (single, returned): (i64, String) = runtime
+    .eval("return results.single, results.returned")
+    .unwrap();
+  assert_eq!((single, returned.as_str()), (9, "single"));
+  let map: Vec<i64> = runtime.eval("return results.map").unwrap();
+  assert_eq!(map, vec![1, 4, 9, 16]);
+  let ids: Vec<i64> = runtime.eval("return results.ids").unwrap();
+  assert_eq!(ids, vec![1, 2, 3, 4]);
+}
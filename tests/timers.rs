@@ -0,0 +1,87 @@
+use lulu::runtime::LuluRuntime;
+use std::time::Duration;
+
+fn runtime() -> LuluRuntime {
+  let mut runtime = LuluRuntime::builder()
+    .with_std(["timers"])
+    .with_module("libs", "using { lulib.timers }\nreturn { timers = timers }")
+    .build()
+    .unwrap();
+  runtime
+    .eval::<()>("timers = require('libs').timers")
+    .unwrap();
+  runtime
+}
+
+async fn wait(runtime: &LuluRuntime) {
+  tokio::time::timeout(Duration::from_secs(5), runtime.wait())
+    .await
+    .expect("futures did not finish")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_after_and_every() {
+  let mut runtime = runtime();
+  runtime
+    .eval::<()>(
+      "fired, skipped, ticks = false, false, 0
+       timers.after(5, function() fired = true end)
+       timers.after(5, function() skipped = true end):cancel()
+       local ticker
+       ticker = timers.every(5, function()
+         ticks = ticks + 1
+         if ticks == 3 then timers.cancel(ticker) end
+       end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+
+  let (fired, skipped, ticks): (bool, bool, i64) =
+    runtime.eval("return fired, skipped, ticks").unwrap();
+  assert!(fired);
+  assert!(!skipped);
+  assert_eq!(ticks, 3);
+}
+
+#[tokio::test]
+async fn test_timeout() {
+  let mut runtime = runtime();
+  runtime
+    .eval::<()>(
+      "rounds, seen = 0, {}
+       async(function()
+         for _ = 1, 10 do
+           rounds = rounds + 1
+           coroutine.yield()
+         end
+       end)
+       local quick = async(function()
+         for _ = 1, 3 do
+           table.insert(seen, rounds)
+           coroutine.yield()
+         end
+         return 'quick'
+       end)
+       timers.timeout(quick, 1000):after(function(value) result = value end)
+
+       local timer = timers.timer()
+       slow = async(function()
+         timer:wait(1000)
+         return 'slow'
+       end)
+       timers.timeout(slow, 5):catch(function(e) timed_out = e end)",
+    )
+    .unwrap();
+  wait(&runtime).await;
+
+  assert_eq!(runtime.eval::<String>("return result").unwrap(), "quick");
+  // Resumed once per round of the scheduler.
+  let seen: Vec<i64> = runtime.eval("return seen").unwrap();
+  assert_eq!(seen, vec![1, 2, 3]);
+
+  let (timed_out, cancelled): (String, bool) =
+    runtime.eval("return timed_out, slow.cancelled").unwrap();
+  assert_eq!(timed_out, "Future timed out");
+  assert!(cancelled);
+}